@@ -0,0 +1,304 @@
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+use azure_core::prelude::{Delimiter, MaxResults, NextMarker, Range};
+use azure_storage::core::prelude::StorageAccountClient;
+use azure_storage_blobs::blob::CopyStatus;
+use azure_storage_blobs::prelude::{AsBlobClient, AsContainerClient, BlobBlockType, BlobClient, BlockList, ContainerClient};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use tokio1::runtime::Runtime;
+use url::Url;
+
+use crate::vfs::{FileStatus, FileType};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// size of each ranged GET of a download and each staged block of an upload
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Blob storage behind an `az://account/container/prefix` url.
+///
+/// Paths given to the vfs are the url path, so they start with the
+/// container name and the rest is the blob name.  '/' separated blob name
+/// prefixes are treated as directories.  The azure crates are async on a
+/// newer tokio than rusoto, so this vfs owns a runtime of that version.
+pub struct AzureBlobVfs {
+    account: String,
+    container: String,
+    sas_token: Option<String>,
+    base_dir: PathBuf,
+    client: Arc<ContainerClient>,
+    rt: Arc<Runtime>,
+}
+
+impl AzureBlobVfs {
+    /// authenticates with the sas token if there is one, otherwise the
+    /// shared key in AZURE_STORAGE_ACCOUNT_KEY
+    pub fn new(url: &Url, sas_token: Option<&str>) -> Result<AzureBlobVfs> {
+        let account = url.host_str().ok_or_else(|| anyhow!("azure url needs a storage account: {}", url))?.to_string();
+        let container = url.path_segments().and_then(|mut s| s.next()).filter(|c| !c.is_empty())
+            .ok_or_else(|| anyhow!("azure url needs a container: {}", url))?.to_string();
+
+        let rt = tokio1::runtime::Builder::new_current_thread().enable_all().build()
+            .context("Unable to start runtime for azure")?;
+        let http_client = azure_core::new_http_client();
+        let account_client = match sas_token {
+            Some(sas) => StorageAccountClient::new_sas_token(http_client, &account, sas.trim_start_matches('?'))
+                .with_context(|| format!("bad azure sas token for {}", url))?,
+            None => {
+                let key = std::env::var("AZURE_STORAGE_ACCOUNT_KEY")
+                    .map_err(|_| anyhow!("azure url {} needs --azure-sas-token or AZURE_STORAGE_ACCOUNT_KEY set", url))?;
+                StorageAccountClient::new_access_key(http_client, &account, key)
+            }
+        };
+        let vfs = AzureBlobVfs {
+            account,
+            client: account_client.as_container_client(container.as_str()),
+            container,
+            sas_token: sas_token.map(|s| s.trim_start_matches('?').to_string()),
+            base_dir: PathBuf::from(url.path()),
+            rt: Arc::new(rt),
+        };
+        // same as the sftp stat check of the base path - a bad container or credentials fail here
+        vfs.list(&vfs.base_dir.clone(), Some(1)).with_context(|| format!("Cannot list azure url \"{}\"", url))?;
+        info!("creating azure blob vfs for {}", url);
+        Ok(vfs)
+    }
+
+    pub fn base_dir(&self) -> &PathBuf {
+        &self.base_dir
+    }
+
+    /// lists the blobs and prefixes directly under dir, following next
+    /// markers.  Prefixes come back as directories.
+    pub fn list(&self, dir: &Path, max_results: Option<u32>) -> Result<Vec<(PathBuf, Option<FileStatus>)>> {
+        let mut prefix = self.blob_name(dir);
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let mut list = vec![];
+        let mut marker: Option<NextMarker> = None;
+        loop {
+            let mut req = self.client.list_blobs().prefix(prefix.clone()).delimiter(Delimiter::new("/"));
+            if let Some(m) = marker.take() {
+                req = req.next_marker(m);
+            }
+            if let Some(n) = max_results.and_then(NonZeroU32::new) {
+                req = req.max_results(MaxResults::new(n));
+            }
+            let out = self.rt.block_on(req.execute()).map_err(|e| anyhow!(e))?;
+            let before = list.len();
+            for bp in out.blobs.blob_prefix.unwrap_or_default() {
+                let name = bp.name[prefix.len()..].trim_end_matches('/').to_string();
+                list.push((PathBuf::from(name), Some(FileStatus { file_type: FileType::Directory, size: 0, mtime: SystemTime::UNIX_EPOCH, hard_link: None })));
+            }
+            for b in out.blobs.blobs {
+                if b.name.len() <= prefix.len() {
+                    continue; // the "directory" marker blob itself
+                }
+                let status = FileStatus {
+                    file_type: FileType::Regular,
+                    size: b.properties.content_length,
+                    mtime: to_system_time(b.properties.last_modified),
+                    hard_link: None,
+                };
+                list.push((PathBuf::from(&b.name[prefix.len()..]), Some(status)));
+            }
+            crate::STATS.dirs_check.fetch_add(list.len() - before, Ordering::Relaxed);
+            match out.next_marker {
+                Some(m) if max_results.is_none() => marker = Some(m),
+                _ => return Ok(list),
+            }
+        }
+    }
+
+    /// the blob is read with ranged GETs of BLOCK_SIZE as the reader needs them
+    pub fn open(&self, path: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let size = self.stat(path)?.size;
+        Ok(Box::new(AzureReader {
+            rt: self.rt.clone(),
+            blob: self.blob_client(path),
+            size,
+            next: offset,
+            buf: vec![],
+            pos: 0,
+        }))
+    }
+
+    /// blocks are staged as they fill and committed with a block list on flush
+    pub fn create(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        Ok(Box::new(AzureWriter {
+            rt: self.rt.clone(),
+            blob: self.blob_client(path),
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            blocks: BlockList::default(),
+            done: false,
+        }))
+    }
+
+    pub fn stat(&self, path: &Path) -> Result<FileStatus> {
+        let res = self.rt.block_on(self.blob_client(path).get_properties().execute());
+        match res {
+            Ok(out) => Ok(FileStatus {
+                file_type: FileType::Regular,
+                size: out.blob.properties.content_length,
+                mtime: to_system_time(out.blob.properties.last_modified),
+                hard_link: None,
+            }),
+            // no blob by that name may still be a prefix other blobs live under
+            Err(e) => {
+                if self.list(path, Some(1))?.is_empty() {
+                    // kind NotFound becomes VfsError::NotFound, so it is not retried
+                    let msg = format!("no azure blob or prefix \"{}\": {}", path.display(), e);
+                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, msg).into());
+                }
+                Ok(FileStatus { file_type: FileType::Directory, size: 0, mtime: SystemTime::UNIX_EPOCH, hard_link: None })
+            }
+        }
+    }
+
+    /// server side copy then delete - blob storage has no rename
+    pub fn rename(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut source = Url::parse(&format!("https://{}.blob.core.windows.net", self.account))?;
+        source.path_segments_mut().map_err(|_| anyhow!("bad azure account \"{}\"", self.account))?
+            .push(&self.container)
+            .extend(self.blob_name(src).split('/'));
+        source.set_query(self.sas_token.as_deref());
+
+        let dst_blob = self.blob_client(dst);
+        let out = self.rt.block_on(dst_blob.copy(&source).execute()).map_err(|e| anyhow!(e))
+            .with_context(|| format!("azure copy of \"{}\" to \"{}\"", src.display(), dst.display()))?;
+        let mut status = out.copy_status;
+        while status == CopyStatus::Pending {
+            std::thread::sleep(Duration::from_millis(100));
+            let props = self.rt.block_on(dst_blob.get_properties().execute()).map_err(|e| anyhow!(e))?;
+            status = props.blob.properties.copy_status.unwrap_or(CopyStatus::Success);
+        }
+        if status != CopyStatus::Success {
+            return Err(anyhow!("azure copy of \"{}\" to \"{}\" ended as {:?}", src.display(), dst.display(), status));
+        }
+        self.remove(src)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        self.rt.block_on(self.blob_client(path).delete().execute()).map_err(|e| anyhow!(e))
+            .with_context(|| format!("azure delete of \"{}\"", path.display()))?;
+        Ok(())
+    }
+
+    fn blob_client(&self, path: &Path) -> Arc<BlobClient> {
+        self.client.as_blob_client(self.blob_name(path))
+    }
+
+    /// the path minus its leading container name
+    fn blob_name(&self, path: &Path) -> String {
+        let p = path.to_string_lossy();
+        let p = p.trim_start_matches('/');
+        match p.strip_prefix(self.container.as_str()) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_start_matches('/').to_string(),
+            _ => p.to_string(),
+        }
+    }
+}
+
+fn to_system_time(t: chrono::DateTime<chrono::Utc>) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(t.timestamp().max(0) as u64)
+}
+
+struct AzureReader {
+    rt: Arc<Runtime>,
+    blob: Arc<BlobClient>,
+    size: u64,
+    next: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for AzureReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            if self.next >= self.size {
+                return Ok(0);
+            }
+            let end = std::cmp::min(self.size, self.next + BLOCK_SIZE as u64);
+            let res = self.rt.block_on(self.blob.get().range(Range::new(self.next, end)).execute())
+                .map_err(|e| std::io::Error::other(format!("azure download of \"{}\": {}", self.blob.blob_name(), e)))?;
+            self.buf = res.data.to_vec();
+            self.pos = 0;
+            self.next = end;
+            if self.buf.is_empty() {
+                return Ok(0);
+            }
+        }
+        let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct AzureWriter {
+    rt: Arc<Runtime>,
+    blob: Arc<BlobClient>,
+    buf: Vec<u8>,
+    blocks: BlockList,
+    done: bool,
+}
+
+impl AzureWriter {
+    fn put_block(&mut self) -> Result<()> {
+        // ids must all be the same length within a blob
+        let id = format!("{:010}", self.blocks.blocks.len());
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        self.rt.block_on(self.blob.put_block(id.clone(), data).execute()).map_err(|e| anyhow!(e))
+            .with_context(|| format!("azure put block {} of \"{}\"", id, self.blob.blob_name()))?;
+        self.blocks.blocks.push(BlobBlockType::new_latest(id));
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        if !self.buf.is_empty() || self.blocks.blocks.is_empty() {
+            self.put_block()?;
+        }
+        self.rt.block_on(self.blob.put_block_list(&self.blocks).execute()).map_err(|e| anyhow!(e))
+            .with_context(|| format!("azure put block list of \"{}\"", self.blob.blob_name()))?;
+        trace!("uploaded {} blocks to azure blob \"{}\"", self.blocks.blocks.len(), self.blob.blob_name());
+        Ok(())
+    }
+}
+
+impl Write for AzureWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.done {
+            return Err(std::io::Error::other("azure blob already committed"));
+        }
+        let n = std::cmp::min(buf.len(), BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() >= BLOCK_SIZE {
+            self.put_block().map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.commit().map_err(|e| std::io::Error::other(format!("{:#}", e)))
+    }
+}
+
+impl Drop for AzureWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("azure upload on close failed: {:#}", e);
+        }
+    }
+}
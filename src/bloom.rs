@@ -0,0 +1,85 @@
+/// Bloom filter over byte keys, sized from an expected item count and a
+/// false positive rate.  A miss means the key was never added; a hit only
+/// means it may have been.  Going past the expected count raises the false
+/// positive rate but never gives a false negative.
+pub struct Bloom {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl Bloom {
+    pub fn new_for_fp_rate(items: usize, fp_rate: f64) -> Self {
+        let n = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = ((-n * fp_rate.ln()) / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+        Bloom {
+            bits: vec![0u64; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8]) {
+        let (h1, h2) = hashes(key);
+        for i in 0..self.num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn check(&self, key: &[u8]) -> bool {
+        let (h1, h2) = hashes(key);
+        (0..self.num_hashes as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+}
+
+/// double hashing: the k probes are h1 + i*h2, with h2 a splitmix64 remix of
+/// h1 forced odd so the probes do not collapse onto each other
+fn hashes(key: &[u8]) -> (u64, u64) {
+    let h1 = fxhash::hash64(key);
+    let mut z = h1.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (h1, (z ^ (z >> 31)) | 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut bloom = Bloom::new_for_fp_rate(1000, 0.01);
+        // well past the expected count too, where only false positives may rise
+        let keys = (0..5000).map(|i| format!("/src/dir{}/file_{}.csv", i % 13, i)).collect::<Vec<_>>();
+        for k in &keys {
+            bloom.set(k.as_bytes());
+        }
+        for k in &keys {
+            assert!(bloom.check(k.as_bytes()), "{}", k);
+        }
+    }
+
+    #[test]
+    fn false_positives_near_the_rate() {
+        let mut bloom = Bloom::new_for_fp_rate(10_000, 0.01);
+        for i in 0..10_000 {
+            bloom.set(format!("/src/in_{}", i).as_bytes());
+        }
+        let fp = (0..10_000).filter(|i| bloom.check(format!("/src/out_{}", i).as_bytes())).count();
+        assert!(fp < 300, "{} false positives", fp);
+    }
+}
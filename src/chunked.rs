@@ -0,0 +1,70 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::Builder;
+
+use anyhow::{anyhow, Context};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::cli::Cli;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// Copies a large file as N chunks in parallel, each writer seeking to its own
+/// offset in the same dst file.  The dst file must already exist (created empty
+/// by the caller).  All chunk threads are joined before returning, so the
+/// caller can rename the file right after.
+pub fn chunked_copy(cli: &Arc<Cli>, src_path: &Path, dst_path: &Path, size: u64) -> Result<usize> {
+    let chunks = cli.parallel_write_chunks as u64;
+    let chunk_size = size.div_ceil(chunks);
+
+    let mut handles = vec![];
+    let mut offset = 0u64;
+    let mut i = 0;
+    while offset < size {
+        let len = std::cmp::min(chunk_size, size - offset);
+        let (cli_c, src_c, dst_c) = (cli.clone(), src_path.to_path_buf(), dst_path.to_path_buf());
+        let h = Builder::new().name(format!("chunk:{}", i))
+            .spawn(move || write_chunk(&cli_c, &src_c, &dst_c, offset, len))
+            .context("chunk thread start failed")?;
+        handles.push(h);
+        offset += len;
+        i += 1;
+    }
+    debug!("split \"{}\" of size {} into {} chunks", src_path.display(), size, handles.len());
+
+    // joining every chunk is the barrier before rename - a failed chunk
+    // cannot leave the others waiting forever
+    let mut total = 0usize;
+    let mut errors = vec![];
+    for h in handles {
+        match h.join() {
+            Err(e) => errors.push(anyhow!("chunk thread panicked: {:?}", e)),
+            Ok(Err(e)) => errors.push(e),
+            Ok(Ok(n)) => total += n,
+        }
+    }
+    if let Some(e) = errors.pop() {
+        return Err(e.context(format!("{} chunk(s) failed for \"{}\"", errors.len() + 1, src_path.display())));
+    }
+    Ok(total)
+}
+
+fn write_chunk(cli: &Arc<Cli>, src_path: &Path, dst_path: &Path, offset: u64, len: u64) -> Result<usize> {
+    let (src, dst) = crate::connect(cli)?;
+
+    let f_in = src.open_at(src_path, offset).with_context(|| format!("opening src chunk at {}: {}", offset, src_path.display()))?;
+    let f_in = crate::rate_limited(cli, Box::new(f_in.take(len)), cli.parallel_write_chunks);
+    let mut f_in = BufReader::with_capacity(cli.copy_buffer_size, f_in);
+    let mut f_out = BufWriter::with_capacity(cli.copy_buffer_size,
+                                             dst.write_at(dst_path, offset).with_context(|| format!("opening dst chunk at {}: {}", offset, dst_path.display()))?);
+
+    let written = std::io::copy(&mut f_in, &mut f_out)?;
+    f_out.flush()?;
+    if written != len {
+        return Err(anyhow!("chunk at {} of \"{}\" short: wrote {} of {} bytes", offset, src_path.display(), written, len));
+    }
+    trace!("chunk at {} of \"{}\" wrote {} bytes", offset, src_path.display(), written);
+    Ok(written as usize)
+}
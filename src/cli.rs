@@ -3,9 +3,14 @@ use structopt::StructOpt;
 use url::Url;
 use std::path::PathBuf;
 use std::time::Duration;
+use std::convert::TryFrom;
 use pcre2::bytes::Regex;
 use lazy_static::lazy_static;
 use crate::util::to_log_level;
+use crate::track::{TrackerBackend, TrackFormat};
+use crate::vfs::CreateMode;
+use crate::compress::Compression;
+use crate::verify::ChecksumAlgorithm;
 use log::LevelFilter;
 
 
@@ -25,14 +30,53 @@ structopt::clap::AppSettings::UnifiedHelpMessage
 ]),
 )]
 pub struct Cli {
+    #[structopt(long, env = "PULLPUSH_CONFIG")]
+    /// TOML file of defaults for any option here, keyed by long name e.g. src-url = "sftp://..."
+    ///
+    /// Options given on the command line win over the file.  Flags take
+    /// true, repeatable options take an array and everything else a string
+    /// or number.
+    pub config: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// print the resolved options as a config file and exit - passwords and tokens are left out
+    pub print_config: bool,
+
     #[structopt(long)]
     /// source url
+    ///
+    /// The path may be a glob, like file:///data/2024/01/*/, to list every
+    /// matching directory in one run.  Files still land in the one dst dir by
+    /// name, so the same name in two matched dirs overwrites.
     pub src_url: Url,
 
+    #[structopt(long, number_of_values = 1)]
+    /// alternate source url mirroring the same directory - may be repeated
+    ///
+    /// Only used with --src-url-rotate-on-error.  Must have the same path as
+    /// --src-url so tracked paths stay the same whichever server is used.
+    pub src_url_alt: Vec<Url>,
+
+    #[structopt(long)]
+    /// when a source connection fails, try the next of --src-url and --src-url-alt in order
+    pub src_url_rotate_on_error: bool,
+
     #[structopt(long)]
     /// destiation url
     pub dst_url: Url,
 
+    #[structopt(long)]
+    /// TOML file of [[route]] tables, each with a re and a dst_url
+    ///
+    /// A file goes to the dst_url of the first route whose re matches its
+    /// name, or to --dst-url when none do.  Every dst gets its share of
+    /// --threads, at least one, and uses the same dst key or password.
+    pub routing_file: Option<PathBuf>,
+
+    #[structopt(skip)]
+    /// filled from --routing-file by load_routing_file
+    pub routes: Vec<Route>,
+
     #[structopt(long)]
     /// source private key files
     pub src_pk: Option<PathBuf>,
@@ -41,24 +85,133 @@ pub struct Cli {
     /// destination private key files
     pub dst_pk: Option<PathBuf>,
 
+    #[structopt(long)]
+    /// source sftp password - "env:" reads it from PULLPUSH_SRC_PASS instead
+    ///
+    /// With --src-pk too, the key is tried first and the password only if the
+    /// key is refused.
+    pub src_password: Option<Password>,
+
+    #[structopt(long)]
+    /// destination sftp password - "env:" reads it from PULLPUSH_DST_PASS instead
+    pub dst_password: Option<Password>,
+
+    #[structopt(long, parse(try_from_str = to_jump_host))]
+    /// ssh jump host as user@host:port the source sftp server is reached through
+    ///
+    /// The jump host authenticates with --jump-pk, or --src-pk without it, and
+    /// the source session runs over a direct-tcpip channel from it.
+    pub src_jump_host: Option<Url>,
+
+    #[structopt(long, parse(try_from_str = to_jump_host))]
+    /// ssh jump host as user@host:port the destination sftp server is reached through
+    pub dst_jump_host: Option<Url>,
+
+    #[structopt(long)]
+    /// private key for the jump hosts - defaults to --src-pk or --dst-pk
+    pub jump_pk: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// shared access signature for az:// urls - without it the account key is
+    /// read from AZURE_STORAGE_ACCOUNT_KEY
+    pub azure_sas_token: Option<String>,
+
+    #[structopt(long)]
+    /// known hosts file sftp servers must be listed in - defaults to ~/.ssh/known_hosts
+    pub known_hosts: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// do not check sftp host keys against known hosts - for lab use only
+    pub insecure_skip_host_check: bool,
+
+    #[structopt(long, parse(try_from_str = to_fingerprint))]
+    /// SHA-256 host key fingerprint in hex the source server must have
+    ///
+    /// Checked right after the ssh handshake, so even a legitimate key rotation
+    /// is refused.  Colons between bytes are allowed.
+    pub src_fingerprint_pin: Option<String>,
+
+    #[structopt(long, parse(try_from_str = to_fingerprint))]
+    /// SHA-256 host key fingerprint in hex the destination server must have
+    pub dst_fingerprint_pin: Option<String>,
+
     #[structopt(long, parse(try_from_str = to_perm))]
     /// destination permissions in octal like 777
     pub dst_perm: Option<u32>,
 
-    #[structopt(long, parse(try_from_str = to_regex))]
-    /// regular expression on filename of files to keep
+    #[structopt(long)]
+    /// give each dst file the mtime of its source after the rename - file and sftp dst only
+    pub mtime_preserve: bool,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_u64))]
+    /// skip files smaller than this size, like zero byte sentinel files
+    pub min_size: u64,
+
+    #[structopt(long, default_value("18446744073709551615"), parse(try_from_str = to_size_u64))]
+    /// skip files larger than this size
+    pub max_size: u64,
+
+    #[structopt(long, number_of_values = 1, parse(try_from_str = to_regex))]
+    /// regular expression on filename of files to keep - may be repeated
     ///
-    /// ".*" means all filenames will pass
-    pub re: Regex,
+    /// A file is kept when any of them match.  With none given every filename
+    /// passes, the same as ".*".
+    pub re: Vec<Regex>,
+
+    #[structopt(long)]
+    /// file of --re patterns, one per line, added to any given with --re
+    ///
+    /// Blank lines and lines starting with # are skipped.
+    pub re_file: Option<PathBuf>,
+
+    #[structopt(long, number_of_values = 1, parse(try_from_str = to_regex))]
+    /// regular expression on filename of files to skip even when --re matches - may be repeated
+    pub exclude_re: Vec<Regex>,
 
     #[structopt(long)]
     /// tracking list name
+    ///
+    /// A name ending in .bin makes the file tracker write it in a binary
+    /// format that loads much faster; its WAL stays text.
     pub track: PathBuf,
 
+    #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+    /// where tracking entries are kept: file or sqlite
+    ///
+    /// file keeps entries in memory with a WAL next to the tracking file and
+    /// rewrites the file on commit.  sqlite keeps them in "<track>.sqlite"
+    /// where every update is its own transaction, and which several processes
+    /// can share.
+    pub tracker_backend: TrackerBackend,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_u64))]
+    /// rough memory cap for the file tracker - 0 means no limit
+    ///
+    /// When the loaded entries are estimated to exceed this, the most recent
+    /// ones are kept in memory and the rest are written to a sorted
+    /// "<track>.spill" file that lookups binary search when they miss in
+    /// memory.  Commit folds the spill file back into the tracking file.
+    pub tracker_memory_limit: u64,
+
+    #[structopt(long, default_value("0.001"), parse(try_from_str = to_fpr))]
+    /// false positive rate of the bloom filter the file tracker checks before
+    /// its set - 0 turns the filter off
+    pub bloom_fpr: f64,
+
     #[structopt(long, parse(try_from_str = to_duration))]
-    /// timeout in seconds
+    /// timeout of each sftp operation, and of connecting unless the two below are given
     pub timeout: Duration,
 
+    #[structopt(long, parse(try_from_str = to_duration))]
+    /// how long the tcp connect to an sftp or ftp server may take - defaults to --timeout
+    pub tcp_connect_timeout: Option<Duration>,
+
+    #[structopt(long, parse(try_from_str = to_duration))]
+    /// how long the ssh handshake, host key check and authentication may take - defaults to --timeout
+    ///
+    /// An overloaded server can accept the tcp connection and then stall here.
+    pub ssh_handshake_timeout: Option<Duration>,
+
     #[structopt(long, parse(try_from_str = to_duration))]
     /// max age to consider for transfer
     pub max_age: Duration,
@@ -78,6 +231,36 @@ pub struct Cli {
     /// default is nice mid-way, but 64M might help.
     pub copy_buffer_size: usize,
 
+    #[structopt(long)]
+    /// let each xfer thread tune its copy buffer instead of --copy-buffer-size
+    ///
+    /// Starts at 64k.  The first 4 buffers of every xfer are timed and the
+    /// size is doubled or halved depending on whether that beat the rate seen
+    /// at the previous size.  Only the plain copy adapts, not --threaded-copy.
+    pub adaptive_buffer: bool,
+
+    #[structopt(long, default_value("4k"), parse(try_from_str = to_size_usize))]
+    /// smallest buffer --adaptive-buffer goes down to
+    pub min_buffer: usize,
+
+    #[structopt(long, default_value("64M"), parse(try_from_str = to_size_usize))]
+    /// largest buffer --adaptive-buffer goes up to
+    pub max_buffer: usize,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_usize))]
+    /// cap each xfer thread at this many bytes per second e.g. 50M - 0 is no limit
+    ///
+    /// Bursts of up to --copy-buffer-size are let through at once.
+    pub bandwidth_limit: usize,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_usize))]
+    /// give up on an xfer reading under this many bytes per second e.g. 1M - 0 is off
+    ///
+    /// Measured in 5 second windows.  Two slow windows in a row fail the xfer
+    /// as a timeout, so it is retried on fresh connections like any other
+    /// with --max-retries.
+    pub min_rate: usize,
+
     #[structopt(long, default_value("4"))]
     /// number of buffers between read and write thread for copy
     pub buffer_ring_size: usize,
@@ -98,6 +281,47 @@ pub struct Cli {
     /// default is nice mid-way, but 64M might help.
     pub threaded_copy_fill_buffer: bool,
 
+    #[structopt(long, default_value("normal"), parse(try_from_str = to_create_mode))]
+    /// how the dst tmp file is created: normal or exclusive
+    ///
+    /// normal overwrites any tmp file already there.  exclusive refuses to
+    /// touch an existing tmp file, which protects against two instances
+    /// writing the same file, and retries while it waits for the other
+    /// instance to finish.  A tmp file left by a crashed run will block
+    /// that file until removed.
+    pub dst_create_mode: CreateMode,
+
+    #[structopt(long)]
+    /// write straight to the final dst path with no tmp file and rename
+    ///
+    /// For sftp servers that do not support rename.  A failed or killed xfer
+    /// leaves a partial file under the final name.  Cannot be used with
+    /// --dst-create-mode exclusive.
+    pub no_tmp_rename: bool,
+
+    #[structopt(long, default_value("1s"), parse(try_from_str = to_duration))]
+    /// wait between attempts when an exclusive create finds the tmp file
+    pub exclusive_lock_retry_delay: Duration,
+
+    #[structopt(long, default_value("3"))]
+    /// retries when an exclusive create finds the tmp file already there
+    pub exclusive_lock_max_retries: usize,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_u64))]
+    /// files larger than this are split into chunks for xfer - 0 means never split
+    ///
+    /// Only has an effect when --parallel-write-chunks is more than 1.
+    pub split_large_files: u64,
+
+    #[structopt(long, default_value("1"))]
+    /// number of chunks written in parallel into the dst file for large files
+    ///
+    /// Each chunk gets its own source and destination connection and writes
+    /// directly at its offset in the destination tmp file, so there is no
+    /// concatenation step afterward.  The rename happens only after every
+    /// chunk has finished.
+    pub parallel_write_chunks: usize,
+
     #[structopt(long)]
     /// Runs without actual xfer, read long help for more
     ///
@@ -107,6 +331,70 @@ pub struct Cli {
     /// dry_run and the run it normally.
     pub dry_run: bool,
 
+    #[structopt(long)]
+    /// only check that src and dst can be reached, listed and written to, then exit
+    ///
+    /// Connects with the same settings a run would, stats and lists the first
+    /// 5 entries of each base dir, times a few stat round trips and writes then
+    /// removes a .pullpush_test_<timestamp> file on the dst.  A report goes to
+    /// stdout and the exit code is 0 only when every check passed.  The
+    /// tracker is not touched.
+    pub connection_test: bool,
+
+    #[structopt(long, requires("dry-run"))]
+    /// write the source paths --dry-run would have xferred here, one per line - "-" for stdout
+    ///
+    /// Written once listing is done, through a .tmp file renamed into place.
+    pub dry_run_output: Option<PathBuf>,
+
+    #[structopt(long, conflicts_with("delete-source"), parse(try_from_str = to_strftime))]
+    /// move each source file into this dir under the src url once it is at the destination and tracked
+    ///
+    /// strftime codes are filled in from the local time, so sent/%Y-%m-%d
+    /// gives a dir per day.  The dir is created when missing.  A failed move
+    /// is only warned about.  With --depth other than 1 keep the archive out
+    /// of the listing with --exclude-re.  A globbed src url archives under
+    /// its dirs before the first wildcard.
+    pub archive_dir: Option<String>,
+
+    #[structopt(long)]
+    /// create the dst directory and any missing parents before writing into it
+    pub create_dst_dirs: bool,
+
+    #[structopt(long)]
+    /// remove each source file once it is at the destination and tracked
+    ///
+    /// Gives "move" semantics.  A failed removal is only warned about - the
+    /// destination file and tracker entry stay.
+    pub delete_source: bool,
+
+    #[structopt(long, requires("delete-source"))]
+    /// also remove the source file when its xfer failed after all retries - dangerous
+    pub delete_source_on_error: bool,
+
+    #[structopt(long, default_value("continue"), parse(try_from_str = to_on_error))]
+    /// what a file that failed all its retries does to the run: continue, abort or quarantine
+    ///
+    /// continue logs it and goes on.  abort stops all xfer threads and exits
+    /// non-zero, even in watch mode.  quarantine also records the path in
+    /// --quarantine-tracker.
+    pub on_error: OnError,
+
+    #[structopt(long)]
+    /// abort the whole run on the first failed file, without any retries
+    ///
+    /// The lister stops queueing and each xfer thread quits once its current
+    /// file is done, leaving the rest of the queue for the next run.  Exits
+    /// non-zero with the failed file's error as the last line logged.
+    pub fail_fast: bool,
+
+    #[structopt(long, required_if("on-error", "quarantine"))]
+    /// tracker file the failed paths are recorded in with --on-error quarantine
+    ///
+    /// Same format as --track but with a lastmod and size of 0, which never
+    /// age out.
+    pub quarantine_tracker: Option<PathBuf>,
+
     #[structopt(long, default_value="4")]
     /// Number of transfer threads and also connections used + 1 to source
     pub threads: usize,
@@ -120,6 +408,40 @@ pub struct Cli {
     /// added to figure out which works best.
     pub disable_queue_as_found: bool,
 
+    #[structopt(long, parse(try_from_str = to_regex))]
+    /// regular expression on filename of files to queue ahead of the rest, e.g. "\.manifest$"
+    ///
+    /// --sort-order still applies within the priority files and within the
+    /// rest, and --max-files / --max-bytes count the priority files first.
+    pub priority_re: Option<Regex>,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_u64))]
+    /// refuse to start xferring when the dst filesystem has less than this free e.g. 5G - 0 is no check
+    ///
+    /// Checked when the run starts, and with --disable-queue-as-found again
+    /// with the queued bytes added on.  Needs statvfs@openssh.com on sftp
+    /// servers and is skipped with a warning on object stores.
+    pub min_dst_free: u64,
+
+    #[structopt(long, default_value("0"))]
+    /// stop queueing after this many files in one run - 0 is no limit
+    ///
+    /// Files left out are not tracked so the next run picks them up.
+    pub max_files: usize,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_u64))]
+    /// stop queueing once this much data is queued in one run e.g. 10G - 0 is no limit
+    ///
+    /// The file that crosses the limit is still sent.  Files left out are not
+    /// tracked so the next run picks them up.
+    pub max_bytes: u64,
+
+    #[structopt(long, parse(try_from_str = to_sort_order))]
+    /// queue files in this order: size-asc, size-desc, mtime-asc, mtime-desc, name-asc or name-desc
+    ///
+    /// Without it files go in whatever order the listing returned them.
+    pub sort_order: Option<SortOrder>,
+
     #[structopt(long)]
     /// everyfile listed will be added to lister to make future listing faster
     ///
@@ -132,6 +454,13 @@ pub struct Cli {
     /// You might want this - you might not - think about it before using.
     pub add_all_to_tracker: bool,
 
+    #[structopt(long)]
+    /// drop entries that aged past --max-track-age during the run before committing the tracker
+    ///
+    /// Matters for long lived runs like --watch-interval where entries can
+    /// expire between loading the tracker and writing it out again.
+    pub compact_on_commit: bool,
+
     #[structopt(long)]
     /// files are overwritten on dst if size or time changes on faile - this disables that
     ///
@@ -145,16 +474,312 @@ pub struct Cli {
     /// By default hidden files are excluded
     pub include_dot_files: bool,
 
+    #[structopt(long, parse(try_from_str = to_duration))]
+    /// do not xfer a path again within this time of xferring it in this process
+    ///
+    /// Kept in memory only and lost on restart, unlike the tracker.  Useful when
+    /// --max-track-age is shorter than files live at the source.
+    pub transfer_dedup_window: Option<Duration>,
+
+    #[structopt(long, parse(try_from_str = to_duration))]
+    /// keep running, starting a new pass this long after the last one finished
+    ///
+    /// Each pass reconnects to the source and reloads the tracker.  SIGTERM
+    /// exits cleanly once the current pass is done.
+    pub watch_interval: Option<Duration>,
+
+    #[structopt(long, default_value("5"))]
+    /// with --watch-interval, exit after this many failed passes in a row
+    ///
+    /// A failed pass, such as the source not reconnecting, is retried after
+    /// --retry-delay instead of the full interval.
+    pub max_connect_failures: usize,
+
+    #[structopt(long)]
+    /// skip listing the source dir when its mtime has not changed since the last listing
+    ///
+    /// Only matters with --watch-interval.  A listing that left too-young files
+    /// behind always forces the next one.
+    pub watch_dir_mtime: bool,
+
+    #[structopt(long, default_value("1"))]
+    /// how many directory levels of the source to list - 1 is flat, 0 is no limit
+    ///
+    /// Files found in sub directories still land directly in the destination
+    /// directory under their own file name.
+    pub depth: usize,
+
+    #[structopt(long)]
+    /// skip re-listing sub directories whose mtime has not changed since they were tracked
+    ///
+    /// With a --depth other than 1 each listed sub directory whose files were
+    /// all dealt with is tracked with its mtime.  Later listings step through
+    /// an unchanged one to its tracked sub directories without reading it.
+    /// Needs --disable-overwrite, since a file changed in place does not change
+    /// its directory's mtime.  Directories last changed before
+    /// --max-track-age drop out of the tracker and are listed again.
+    pub cache_dirs: bool,
+
+    #[structopt(long)]
+    /// run the path checks on each directory entry as it is read
+    ///
+    /// Only the entries that pass are kept, instead of the whole directory
+    /// first, so huge directories take less memory and the filtering overlaps
+    /// a slow remote readdir.  Only used for a flat listing (--depth 1)
+    /// without --readdir-rpc-timeout or --sftp-stat-before-filter.
+    pub streaming_listing: bool,
+
+    #[structopt(long, parse(try_from_str = to_duration))]
+    /// max time to wait on any single readdir call while listing the source
+    ///
+    /// When it fires, the hung listing is abandoned and redone on a fresh
+    /// source connection, up to --listing-max-retries times.
+    pub readdir_rpc_timeout: Option<Duration>,
+
+    #[structopt(long, default_value("0"))]
+    /// how many times a listing that hit --readdir-rpc-timeout is retried
+    pub listing_max_retries: usize,
+
+    #[structopt(long)]
+    /// stat every sftp entry before path filtering when readdir returns no attributes
+    ///
+    /// By default the path filters run first and only the survivors are
+    /// stat'ed.  When most files pass the filters anyway, stating everything
+    /// up front can be faster.  Both strategies log their timings at info.
+    pub sftp_stat_before_filter: bool,
+
     #[structopt(long, default_value("8"))]
     /// thread pool size for stating local threads
     ///
     pub local_file_stat_thread_pool_size: usize,
 
+    #[structopt(long, default_value("256"))]
+    /// statx calls per io_uring submission when local stats go through io_uring
+    ///
+    /// Only used by builds with the io-uring feature, where it replaces the
+    /// --local-file-stat-thread-pool-size threads.
+    pub local_file_stat_batch_size: usize,
+
+    #[structopt(long, default_value("16"))]
+    /// sftp stat requests kept in flight at once when stat'ing listed files
+    ///
+    /// Each one beyond the first opens another sftp channel on the source
+    /// connection for the listing.  1 stats one file at a time.
+    pub sftp_stat_pipeline: usize,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_usize))]
+    /// SO_RCVBUF for sftp tcp connections - 0 leaves the OS default
+    ///
+    /// On high bandwidth, high latency paths the tcp receive window rather
+    /// than sftp flow control is often what limits a transfer.  Linux doubles
+    /// the value asked for and caps it at net.core.rmem_max.
+    pub sftp_tcp_rcvbuf: usize,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_usize))]
+    /// SO_SNDBUF for sftp tcp connections - 0 leaves the OS default
+    pub sftp_tcp_sndbuf: usize,
+
     #[structopt(long, default_value("4"))]
     /// number of ssh session creations allowed to happen at a time
     ///
     pub number_of_ssh_startups: usize,
 
+    #[structopt(long)]
+    /// path known to exist on sftp servers, lstat'ed before each xfer to verify the connection
+    ///
+    /// If the lstat fails, the src and dst connections are recreated before
+    /// the file transfer is attempted.  Only sftp connections are probed.
+    pub connection_probe_file: Option<PathBuf>,
+
+    #[structopt(long, default_value("0s"), parse(try_from_str = to_duration))]
+    /// skip the connection probe when the last successful xfer was within this time
+    pub probe_skip_if_recent: Duration,
+
+    #[structopt(long)]
+    /// same as --include-symlinks skip, kept for older command lines
+    pub sftp_src_readdir_skip_links: bool,
+
+    #[structopt(long, default_value("follow"), parse(try_from_str = to_symlink_mode))]
+    /// follow, copy or skip source symlinks
+    ///
+    /// follow transfers the file a symlink points to and drops dangling ones,
+    /// copy transfers the symlink itself as a small file holding the path it
+    /// points to, and skip leaves symlinks out.  Symlinked directories are
+    /// never listed into.
+    pub include_symlinks: SymlinkMode,
+
+    #[structopt(long)]
+    /// stat the src file on its open handle and record that status in the tracker
+    ///
+    /// Catches files rewritten between listing and transfer.  The status from
+    /// the listing is still used for filtering.
+    pub sftp_src_stat_on_open: bool,
+
+    #[structopt(long)]
+    /// open every thread's sftp channel on one ssh session per url
+    ///
+    /// Cuts the tcp connections to the src and dst to one each, for firewalls
+    /// that rate limit them.  All sftp requests on a session take turns, so
+    /// expect less throughput than with a connection per thread.
+    /// --number-of-ssh-startups still caps how many channels open at once.
+    pub sftp_channel_per_thread: bool,
+
+    #[structopt(long)]
+    /// check the sftp subsystem answers a realpath of "." before using a connection
+    ///
+    /// Some servers accept the sftp channel but run a broken or restricted
+    /// implementation that closes it on the first real request.  This fails
+    /// those at connect time instead of part way through a run.
+    pub validate_sftp_subsystem: bool,
+
+    #[structopt(long, parse(try_from_str = to_duration), default_value("30s"))]
+    /// send an ssh keepalive on idle sftp sessions this often - 0s turns it off
+    pub sftp_keepalive_interval: Duration,
+
+    #[structopt(long)]
+    /// validate every sftp create, rename and setstat with an extra lstat - debug builds only
+    ///
+    /// This roughly doubles the sftp round trips and is only meant for
+    /// debugging odd servers.  Discrepancies are logged as errors.
+    pub sftp_strict_mode: bool,
+
+    #[structopt(long)]
+    /// send log output to syslog with the LOG_DAEMON facility instead of stderr
+    ///
+    /// The ident includes the session id of the run so concurrent runs can be
+    /// told apart.
+    pub syslog_output: bool,
+
+    #[structopt(long, alias = "checksum-verify-dst")]
+    /// compare the --checksum-algorithm digest (SHA-256 by default) of the src bytes sent with a read back of the renamed dst file
+    ///
+    /// The src digest is taken during the copy; the dst file is read once more,
+    /// catching what the dst storage got wrong as well as what the copy did.
+    /// A mismatched dst file is removed, or moved to --quarantine-dir, and the
+    /// xfer counts as failed and goes through --on-error.  The read back time
+    /// is logged as verify_time.
+    pub verify_checksum: bool,
+
+    #[structopt(long)]
+    /// directory on the destination where files failing --verify-checksum are moved
+    pub quarantine_dir: Option<PathBuf>,
+
+    #[structopt(long, parse(try_from_str = to_checksum_algorithm))]
+    /// digest xferred files with md5, sha256 or xxhash and keep it in the tracker
+    ///
+    /// The digest is taken during the copy.  A file xferred again with the same
+    /// size and mtime but a different digest is logged.  --verify-checksum uses
+    /// this algorithm too, or sha256 without it.
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+
+    #[structopt(long, default_value("none"), parse(try_from_str = to_compression))]
+    /// compress dst files while writing them: none, gzip or zstd
+    ///
+    /// The dst file gets a .gz or .zst extension unless --compress-keep-ext.
+    /// Compressed files are never split into parallel chunks.
+    pub compress: Compression,
+
+    #[structopt(long)]
+    /// keep the src file name for compressed dst files
+    pub compress_keep_ext: bool,
+
+    #[structopt(long, default_value("6"))]
+    /// compression level - 0-9 for gzip, 1-22 for zstd
+    pub compress_level: u32,
+
+    #[structopt(long, default_value("3"))]
+    /// retries of a failed file xfer, each on freshly made connections
+    ///
+    /// After the last retry the file is logged as failed and the thread moves
+    /// on to the next file.
+    pub max_retries: u32,
+
+    #[structopt(long, parse(try_from_str = to_duration), default_value("2s"))]
+    /// delay before the first retry - doubled for each retry after that
+    pub retry_delay: Duration,
+
+    #[structopt(long, parse(try_from_str = to_duration))]
+    /// end the pass once every xfer thread has waited this long without a file
+    ///
+    /// Keeps a stalled upstream from leaving pullpush hanging forever, in
+    /// watch mode above all.  Files still to come are left for the next run.
+    pub idle_timeout: Option<Duration>,
+
+    #[structopt(long)]
+    /// shell command run after each file lands at dst - {src} {dst} {size} {name} are filled in
+    ///
+    /// Run with sh -c after the rename and before the file is tracked.  The
+    /// placeholders become "$1" to "$4" with the values passed as positional
+    /// args, so names are never parsed by the shell - write "{src}" in double
+    /// quotes if paths can hold spaces.  Output is logged at debug level.
+    pub post_cmd: Option<String>,
+
+    #[structopt(long, requires("post-cmd"))]
+    /// fail the xfer when --post-cmd exits non-zero instead of only logging it
+    pub post_cmd_strict: bool,
+
+    #[structopt(long)]
+    /// append one JSON line per completed xfer to this file - "-" is stdout
+    ///
+    /// Each line has ts, src, dst, size, duration_ms and rate_mbps.
+    pub transfer_log: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// create an empty file with this extension in place of the dst file's own after each xfer
+    ///
+    /// e.g. done makes data.csv.done for data.csv.gz and data.done for data.csv.
+    /// It is created right after the rename, for consumers that poll for a
+    /// marker.  Failing to create it is only warned about.
+    pub sentinel_ext: Option<String>,
+
+    #[structopt(long)]
+    /// write the files that failed to xfer as a JSON array to this file
+    ///
+    /// Each entry has path, error and attempts.  Without it they are printed
+    /// as a table on stderr.  Either way a run with failures exits non-zero.
+    pub error_log: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// print the final statistics as one JSON object on stdout
+    pub output_json_stats: bool,
+
+    #[structopt(long)]
+    /// send tracing spans of the run, listing, each xfer and tracker commits to this OTLP http collector
+    ///
+    /// e.g. http://collector:4318/v1/traces.  Log lines are attached to the
+    /// span they were written in.
+    pub otlp_endpoint: Option<Url>,
+
+    #[structopt(long)]
+    /// serve the statistics in Prometheus text format at http://0.0.0.0:<port>/metrics
+    pub metrics_port: Option<u16>,
+
+    #[structopt(long)]
+    /// append a CSV row of the ticker counters to this file every --metrics-interval
+    ///
+    /// Columns are timestamp_iso8601, xfer_count, dirs_check, path_check,
+    /// stat_check, never2xfer and too_young.
+    pub metrics_csv: Option<PathBuf>,
+
+    #[structopt(long, parse(try_from_str = to_duration), default_value("60s"))]
+    /// how often a --metrics-csv row is written - checked every --ticker-interval
+    pub metrics_interval: Duration,
+
+    #[structopt(long, default_value("10M"), parse(try_from_str = to_size_u64))]
+    /// roll --metrics-csv over to <path>.1 once it reaches this size - 0 never rolls it
+    pub metrics_csv_max_size: u64,
+
+    #[structopt(long)]
+    /// write the listing results and final statistics as a JSON object to this file - "-" is stdout
+    ///
+    /// Written after the tracker is committed, with times in seconds.  The
+    /// file is rewritten each --watch-interval pass.
+    pub output_json: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// turn off all logging - with --output-json-stats only the JSON object is printed
+    pub quiet: bool,
+
     #[structopt(short="L", long, parse(try_from_str = to_log_level), default_value("info"))]
     /// log level
     pub log_level: LevelFilter,
@@ -162,6 +787,413 @@ pub struct Cli {
     #[structopt(short="L", long, parse(try_from_str = to_duration), default_value("1s"))]
     /// ticker interval
     pub ticker_interval: Duration,
+
+    #[structopt(long)]
+    /// also append every log line to this file
+    pub log_file: Option<PathBuf>,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = to_size_u64))]
+    /// roll --log-file over to <path>.1 once it would pass this size - 0 never rolls it
+    pub log_max_size: u64,
+
+    #[structopt(long, default_value("5"))]
+    /// how many rolled --log-file files to keep, <path>.1 being the newest
+    pub log_rotate_count: usize,
+
+    #[structopt(long, default_value("text"), parse(try_from_str = to_ticker_format))]
+    /// text or json - json writes every tick's counters to stderr as one json line
+    pub ticker_format: TickerFormat,
+}
+
+/// `pullpush maintenance <op>` - housekeeping that runs without any transfer
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(
+version = BUILD_INFO.as_str(), rename_all = "kebab-case",
+global_settings(& [
+structopt::clap::AppSettings::ColoredHelp,
+structopt::clap::AppSettings::UnifiedHelpMessage
+]),
+)]
+pub struct MaintenanceCli {
+    #[structopt(short="L", long, parse(try_from_str = to_log_level), default_value("info"))]
+    /// log level
+    pub log_level: LevelFilter,
+
+    #[structopt(subcommand)]
+    pub op: MaintenanceOp,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub enum MaintenanceOp {
+    /// replay the WAL into the tracking file, drop expired entries and remove the WAL
+    ///
+    /// Safe to run from cron between transfers but not during one.
+    CompactWal {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+    },
+    /// write the tracked entries as csv: path,lastmod_epoch,size_bytes
+    Export {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+
+        #[structopt(long)]
+        /// csv file to write - stdout when not given
+        output: Option<PathBuf>,
+    },
+    /// add entries from csv in the export format to the tracker
+    ///
+    /// Rows past --max-track-age are skipped.  Rows for paths already tracked
+    /// replace them.
+    Import {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+
+        #[structopt(long)]
+        /// csv file to read - stdin when not given
+        input: Option<PathBuf>,
+    },
+    /// merge another tracker into this one, e.g. from an instance in another region
+    ///
+    /// For paths in both the entry with the later mtime is kept.  The --from
+    /// tracker is only read.
+    MergeTracker {
+        #[structopt(long)]
+        /// tracking list name merged into
+        into: PathBuf,
+
+        #[structopt(long)]
+        /// tracking list name merged from
+        from: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage of both: file or sqlite
+        tracker_backend: TrackerBackend,
+    },
+    /// drop tracked entries whose source path matches, so the next run xfers them again
+    Forget {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+
+        #[structopt(long, number_of_values = 1, required = true, parse(try_from_str = to_regex))]
+        /// regular expression on the whole source path - may be repeated
+        re: Vec<Regex>,
+
+        #[structopt(long)]
+        /// only print the paths that would be forgotten
+        dry_run: bool,
+    },
+    /// print the tracked entries under a source path as csv, or json with --json
+    QueryTracker {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+
+        #[structopt(long)]
+        /// source path the entries must be under, e.g. /data/incoming/2024
+        prefix: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// only entries last modified at most this long ago
+        from: Option<Duration>,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// only entries last modified at least this long ago
+        to: Option<Duration>,
+
+        #[structopt(long)]
+        /// print a json array instead of csv
+        json: bool,
+    },
+    /// print a tracker's entry count, size on disk and lastmod range
+    ///
+    /// As key value lines, or one json object with --json.  The on disk
+    /// size includes a file tracker's WAL.
+    Inspect {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+
+        #[structopt(long)]
+        /// print a json object instead of key value lines
+        json: bool,
+    },
+    /// stat every tracked path on the source and print the ones it no longer has
+    ///
+    /// One stat per entry, so large trackers take a while - progress is logged
+    /// every 1000.  With --purge-stale the missing paths are also dropped from
+    /// the tracker.
+    Verify {
+        #[structopt(long)]
+        /// tracking list name
+        track: PathBuf,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// max age to keep in tracking file
+        max_track_age: Duration,
+
+        #[structopt(long, default_value("file"), parse(try_from_str = to_tracker_backend))]
+        /// tracker storage: file or sqlite
+        tracker_backend: TrackerBackend,
+
+        #[structopt(long)]
+        /// source url the tracked paths are on
+        src_url: Url,
+
+        #[structopt(long)]
+        /// source private key file
+        src_pk: Option<PathBuf>,
+
+        #[structopt(long)]
+        /// source sftp password - "env:" reads it from PULLPUSH_SRC_PASS instead
+        src_password: Option<Password>,
+
+        #[structopt(long)]
+        /// known hosts file sftp servers must be listed in - defaults to ~/.ssh/known_hosts
+        known_hosts: Option<PathBuf>,
+
+        #[structopt(long, default_value("30s"), parse(try_from_str = to_duration))]
+        /// timeout of connecting and of each stat
+        timeout: Duration,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// how long the tcp connect to an sftp or ftp server may take - defaults to --timeout
+        tcp_connect_timeout: Option<Duration>,
+
+        #[structopt(long, parse(try_from_str = to_duration))]
+        /// how long the ssh handshake, host key check and authentication may take - defaults to --timeout
+        ssh_handshake_timeout: Option<Duration>,
+
+        #[structopt(long)]
+        /// remove the stale paths from the tracker
+        purge_stale: bool,
+    },
+    /// rewrite a file tracker's tracking file as binary or text
+    ///
+    /// The input may be either format.  Name a binary output *.bin since
+    /// --track picks the format to write from that suffix.  Expired entries
+    /// are kept and the input's WAL is not read, so compact-wal first.
+    ConvertTracker {
+        #[structopt(long, parse(try_from_str = to_track_format))]
+        /// binary or text
+        format: TrackFormat,
+
+        #[structopt(long)]
+        /// tracking file to read
+        input: PathBuf,
+
+        #[structopt(long)]
+        /// tracking file to write
+        output: PathBuf,
+    },
+}
+
+impl Cli {
+    /// parses the command line on top of the --config file, exiting on
+    /// errors and after --print-config the way from_args does
+    pub fn from_args_and_config() -> Result<Cli> {
+        let args = std::env::args_os().collect::<Vec<_>>();
+        let config = match config_path(&args) {
+            None => std::env::var_os("PULLPUSH_CONFIG").map(PathBuf::from),
+            x => x,
+        };
+        let args = match config {
+            None => args,
+            Some(path) => {
+                let mut from_file = config_args(&path, &args)?;
+                from_file.insert(0, args[0].clone());
+                from_file.extend(args.into_iter().skip(1));
+                from_file
+            }
+        };
+        let matches = Cli::clap().get_matches_from(args);
+        let cli = Cli::from_clap(&matches);
+        if cli.print_config {
+            print!("{}", resolved_config(&matches)?);
+            std::process::exit(0);
+        }
+        Ok(cli)
+    }
+
+    /// appends the --re-file patterns to re - an unparsable one fails with its line
+    pub fn load_re_file(&mut self) -> Result<()> {
+        let path = match &self.re_file {
+            None => return Ok(()),
+            Some(p) => p,
+        };
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading --re-file \"{}\"", path.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let re = to_regex(line).with_context(|| format!("pattern \"{}\" on line {} of --re-file \"{}\"", line, i + 1, path.display()))?;
+            self.re.push(re);
+        }
+        Ok(())
+    }
+
+    /// reads --routing-file into routes
+    pub fn load_routing_file(&mut self) -> Result<()> {
+        let path = match &self.routing_file {
+            None => return Ok(()),
+            Some(p) => p,
+        };
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading --routing-file \"{}\"", path.display()))?;
+        let file: RoutingFile = toml::from_str(&text).with_context(|| format!("parsing --routing-file \"{}\"", path.display()))?;
+        for (i, r) in file.route.into_iter().enumerate() {
+            let re = to_regex(&r.re).with_context(|| format!("re of route {} in --routing-file \"{}\"", i + 1, path.display()))?;
+            let dst_url = Url::parse(&r.dst_url).with_context(|| format!("dst_url of route {} in --routing-file \"{}\"", i + 1, path.display()))?;
+            self.routes.push(Route { re, dst_url });
+        }
+        Ok(())
+    }
+
+    /// replaces "env:" passwords with their environment variable - done after
+    /// parsing since the parse function cannot tell src from dst
+    pub fn resolve_passwords(&mut self) -> Result<()> {
+        for (pw, var) in [(&mut self.src_password, "PULLPUSH_SRC_PASS"), (&mut self.dst_password, "PULLPUSH_DST_PASS")] {
+            if let Some(p) = pw {
+                if p.0 == "env:" {
+                    p.0 = std::env::var(var).with_context(|| format!("password set to env: but {} is not set", var))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// order files are queued for xfer with --sort-order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    SizeAsc,
+    SizeDesc,
+    MtimeAsc,
+    MtimeDesc,
+    NameAsc,
+    NameDesc,
+}
+
+/// how the ticker reports the counters, --ticker-format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TickerFormat {
+    Text,
+    Json,
+}
+
+/// what the lister does with source symlinks, --include-symlinks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkMode {
+    Follow,
+    Copy,
+    Skip,
+}
+
+/// one --routing-file entry
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub re: Regex,
+    pub dst_url: Url,
+}
+
+#[derive(serde::Deserialize)]
+struct RoutingFile {
+    #[serde(default)]
+    route: Vec<RoutingEntry>,
+}
+
+#[derive(serde::Deserialize)]
+struct RoutingEntry {
+    re: String,
+    dst_url: String,
+}
+
+/// what happens to the run when a file fails all its retries, --on-error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnError {
+    Continue,
+    Abort,
+    Quarantine,
+}
+
+/// keeps passwords out of Debug output of the Cli
+#[derive(Clone)]
+pub struct Password(String);
+
+impl Password {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for Password {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Password(s.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "******")
+    }
 }
 
 fn to_regex(s: &str) -> Result<Regex> {
@@ -171,40 +1203,240 @@ fn to_regex(s: &str) -> Result<Regex> {
     }
 }
 
+fn to_fingerprint(s: &str) -> Result<String> {
+    let hex = s.replace(':', "").to_lowercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("fingerprint must be 32 bytes of hex for SHA-256 but got {}", s));
+    }
+    Ok(hex)
+}
+
+fn to_jump_host(s: &str) -> Result<Url> {
+    let url = Url::parse(&format!("ssh://{}", s)).map_err(|e| anyhow!("jump host must be user@host:port but got {}: {}", s, e))?;
+    if url.username().is_empty() || url.host_str().is_none_or(|h| h.is_empty()) {
+        return Err(anyhow!("jump host must be user@host:port but got {}", s));
+    }
+    Ok(url)
+}
+
+fn to_tracker_backend(s: &str) -> Result<TrackerBackend> {
+    match s {
+        "file" => Ok(TrackerBackend::File),
+        "sqlite" => Ok(TrackerBackend::Sqlite),
+        _ => Err(anyhow!("tracker backend must be one of file or sqlite but got {}", s)),
+    }
+}
+
+fn to_track_format(s: &str) -> Result<TrackFormat> {
+    match s {
+        "binary" => Ok(TrackFormat::Binary),
+        "text" => Ok(TrackFormat::Text),
+        _ => Err(anyhow!("tracker format must be one of binary or text but got {}", s)),
+    }
+}
+
+fn to_ticker_format(s: &str) -> Result<TickerFormat> {
+    match s {
+        "text" => Ok(TickerFormat::Text),
+        "json" => Ok(TickerFormat::Json),
+        _ => Err(anyhow!("ticker format must be one of text or json but got {}", s)),
+    }
+}
+
+fn to_symlink_mode(s: &str) -> Result<SymlinkMode> {
+    match s {
+        "follow" => Ok(SymlinkMode::Follow),
+        "copy" => Ok(SymlinkMode::Copy),
+        "skip" => Ok(SymlinkMode::Skip),
+        _ => Err(anyhow!("include symlinks must be one of follow, copy or skip but got {}", s)),
+    }
+}
+
+fn to_compression(s: &str) -> Result<Compression> {
+    match s {
+        "none" => Ok(Compression::None),
+        "gzip" => Ok(Compression::Gzip),
+        "zstd" => Ok(Compression::Zstd),
+        _ => Err(anyhow!("compression must be one of none, gzip or zstd but got {}", s)),
+    }
+}
+
+fn to_fpr(s: &str) -> Result<f64> {
+    let fpr: f64 = s.parse().with_context(|| format!("cannot parse false positive rate {}", s))?;
+    if !(0.0..1.0).contains(&fpr) {
+        return Err(anyhow!("false positive rate must be at least 0 and under 1 but got {}", s));
+    }
+    Ok(fpr)
+}
+
+fn to_checksum_algorithm(s: &str) -> Result<ChecksumAlgorithm> {
+    match s {
+        "md5" => Ok(ChecksumAlgorithm::Md5),
+        "sha256" => Ok(ChecksumAlgorithm::Sha256),
+        "xxhash" => Ok(ChecksumAlgorithm::Xxhash),
+        _ => Err(anyhow!("checksum algorithm must be one of md5, sha256 or xxhash but got {}", s)),
+    }
+}
+
+/// --config from the command line, before clap has seen it
+fn config_path(args: &[std::ffi::OsString]) -> Option<PathBuf> {
+    let mut it = args.iter().skip(1);
+    while let Some(a) = it.next() {
+        let a = a.to_string_lossy();
+        if a == "--config" {
+            return it.next().map(PathBuf::from);
+        } else if let Some(p) = a.strip_prefix("--config=") {
+            return Some(PathBuf::from(p));
+        }
+    }
+    None
+}
+
+/// turns the config file into command line args, leaving out any option
+/// the command line already has so it wins without clap seeing it twice
+fn config_args(path: &PathBuf, args: &[std::ffi::OsString]) -> Result<Vec<std::ffi::OsString>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading config file \"{}\"", path.display()))?;
+    let table: toml::value::Table = toml::from_str(&text).with_context(|| format!("parsing config file \"{}\"", path.display()))?;
+    let given = |key: &str| args.iter().skip(1).any(|a| {
+        let a = a.to_string_lossy();
+        a == format!("--{}", key) || a.starts_with(&format!("--{}=", key))
+    });
+    let mut out = vec![];
+    for (key, value) in table {
+        if key == "config" || given(&key) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Boolean(true) => {
+                out.push(format!("--{}", key).into());
+                continue;
+            }
+            toml::Value::Boolean(false) => continue,
+            toml::Value::Array(a) => a,
+            v => vec![v],
+        };
+        for v in values {
+            let v = match v {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                v => return Err(anyhow!("config file \"{}\" key {} has unusable value {}", path.display(), key, v)),
+            };
+            out.push(format!("--{}", key).into());
+            out.push(v.into());
+        }
+    }
+    Ok(out)
+}
+
+/// every option with a value after parsing, defaults included, in the
+/// --config format
+fn resolved_config(matches: &structopt::clap::ArgMatches) -> Result<String> {
+    let mut table = toml::value::Table::new();
+    for name in matches.args.keys().filter(|n| !is_secret_option(n) && **n != "config" && **n != "print-config") {
+        let value = match matches.values_of_lossy(name) {
+            None => toml::Value::Boolean(true),
+            Some(v) if v.is_empty() => toml::Value::Boolean(true),
+            Some(mut v) if v.len() == 1 => toml::Value::String(v.remove(0)),
+            Some(v) => toml::Value::Array(v.into_iter().map(toml::Value::String).collect()),
+        };
+        table.insert(name.to_string(), value);
+    }
+    Ok(toml::to_string(&table)?)
+}
+
+/// options holding credentials, kept out of --print-config - matched by
+/// name so one added later is covered too
+fn is_secret_option(name: &str) -> bool {
+    ["password", "passphrase", "token", "secret"].iter().any(|s| name.contains(s))
+}
+
+fn to_on_error(s: &str) -> Result<OnError> {
+    match s {
+        "continue" => Ok(OnError::Continue),
+        "abort" => Ok(OnError::Abort),
+        "quarantine" => Ok(OnError::Quarantine),
+        _ => Err(anyhow!("on error must be one of continue, abort or quarantine but got {}", s)),
+    }
+}
+
+fn to_sort_order(s: &str) -> Result<SortOrder> {
+    match s {
+        "size-asc" => Ok(SortOrder::SizeAsc),
+        "size-desc" => Ok(SortOrder::SizeDesc),
+        "mtime-asc" => Ok(SortOrder::MtimeAsc),
+        "mtime-desc" => Ok(SortOrder::MtimeDesc),
+        "name-asc" => Ok(SortOrder::NameAsc),
+        "name-desc" => Ok(SortOrder::NameDesc),
+        _ => Err(anyhow!("sort order must be one of size-asc, size-desc, mtime-asc, mtime-desc, name-asc or name-desc but got {}", s)),
+    }
+}
+
+fn to_strftime(s: &str) -> Result<String> {
+    if chrono::format::StrftimeItems::new(s).any(|i| i == chrono::format::Item::Error) {
+        return Err(anyhow!("bad strftime code in {}", s));
+    }
+    Ok(s.to_string())
+}
+
+fn to_create_mode(s: &str) -> Result<CreateMode> {
+    match s {
+        "normal" => Ok(CreateMode::Normal),
+        "exclusive" => Ok(CreateMode::Exclusive),
+        _ => Err(anyhow!("dst create mode must be one of normal or exclusive but got {}", s)),
+    }
+}
+
 fn to_perm(s: &str) -> Result<u32> {
-    Ok(u32::from_str_radix(&s, 8)?)
+    Ok(u32::from_str_radix(s, 8)?)
 }
 
+/// sums number and unit pairs like 1h30m, 1s500ms or 1.5s - a bare number
+/// is seconds.  Units are w d h m s ms and us.
 fn to_duration(s: &str) -> Result<Duration> {
     let mut num = String::new();
-    let mut sum_secs = 0u64;
-    for c in s.chars() {
-        if c >= '0' && c <='9' {
+    let mut sum_nanos = 0u128;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
             num.push(c);
-        } else {
-            let s = num.parse::<u64>().with_context(|| format!("cannot parse number {} inside duration {}", &num, &s))?;
-            num.clear();
-            match c {
-                's' => sum_secs += s,
-                'm' => sum_secs += s*60,
-                'h' => sum_secs += s * 3600,
-                'd' => sum_secs += s*3600*24,
-                'w' => sum_secs += s*3600*24*7,
-                _ => Err(anyhow!("Cannot interpret {} as a time unit inside duration {}", c, &s))?,
-            }
+            continue;
         }
+        let unit_nanos: u128 = match c {
+            'm' if chars.peek() == Some(&'s') => { chars.next(); 1_000_000 }
+            'u' if chars.peek() == Some(&'s') => { chars.next(); 1_000 }
+            's' => 1_000_000_000,
+            'm' => 60 * 1_000_000_000,
+            'h' => 3600 * 1_000_000_000,
+            'd' => 3600 * 24 * 1_000_000_000,
+            'w' => 3600 * 24 * 7 * 1_000_000_000,
+            _ => Err(anyhow!("Cannot interpret {} as a time unit inside duration {}", c, &s))?,
+        };
+        sum_nanos = sum_nanos.checked_add(duration_part(&num, unit_nanos, s)?).ok_or_else(|| anyhow!("duration {} is too long", s))?;
+        num.clear();
+    }
+    if !num.is_empty() {
+        sum_nanos = sum_nanos.checked_add(duration_part(&num, 1_000_000_000, s)?).ok_or_else(|| anyhow!("duration {} is too long", s))?;
     }
-    if num.len() > 0 {
-        sum_secs += num.parse::<u64>().with_context(|| format!("cannot parse number {} inside duration {}", &num, &s))?;
+    let secs = u64::try_from(sum_nanos / 1_000_000_000).map_err(|_| anyhow!("duration {} is too long", &s))?;
+    Ok(Duration::from_secs(secs) + Duration::from_nanos((sum_nanos % 1_000_000_000) as u64))
+}
+
+fn duration_part(num: &str, unit_nanos: u128, s: &str) -> Result<u128> {
+    if num.contains('.') {
+        let f = num.parse::<f64>().with_context(|| format!("cannot parse number {} inside duration {}", num, s))?;
+        Ok((f * unit_nanos as f64).round() as u128)
+    } else {
+        num.parse::<u128>().with_context(|| format!("cannot parse number {} inside duration {}", num, s))?
+            .checked_mul(unit_nanos).ok_or_else(|| anyhow!("duration {} is too long", s))
     }
-    Ok(Duration::from_secs(sum_secs))
 }
 
 fn to_size_u64(s: &str) -> Result<u64> {
     let mut num = String::new();
     let mut bytes = 0u64;
     for c in s.chars() {
-        if c >= '0' && c <='9' {
+        if c.is_ascii_digit() {
             num.push(c);
         } else {
             let s = num.parse::<u64>().with_context(|| format!("cannot parse number {} inside duration {}", &num, &s))?;
@@ -219,7 +1451,7 @@ fn to_size_u64(s: &str) -> Result<u64> {
             }
         }
     }
-    if num.len() > 0 {
+    if !num.is_empty() {
         bytes += num.parse::<u64>().with_context(|| format!("cannot parse number {} inside size {}", &num, &s))?;
     }
     Ok(bytes)
@@ -227,5 +1459,52 @@ fn to_size_u64(s: &str) -> Result<u64> {
 
 fn to_size_usize(s: &str) -> Result<usize> {
     let sz = to_size_u64(s)?;
-    return Ok(sz as usize);
+    Ok(sz as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(to_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(to_duration("1s500ms").unwrap(), Duration::from_millis(1500));
+        assert_eq!(to_duration("2m30s").unwrap(), Duration::from_secs(150));
+        assert_eq!(to_duration("250us").unwrap(), Duration::from_micros(250));
+        assert_eq!(to_duration("90").unwrap(), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn duration_fractions() {
+        assert_eq!(to_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(to_duration("0.5h").unwrap(), Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn fingerprint_forms() {
+        let hex = "ab".repeat(32);
+        assert_eq!(to_fingerprint(&hex).unwrap(), hex);
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(to_fingerprint(&colons).unwrap(), hex);
+        assert!(to_fingerprint(&"ab".repeat(20)).is_err());
+        assert!(to_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn secrets_left_out_of_config() {
+        for name in &["src-password", "dst-password", "azure-sas-token"] {
+            assert!(is_secret_option(name), "{}", name);
+        }
+        for name in &["src-pk", "known-hosts", "src-url"] {
+            assert!(!is_secret_option(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn duration_errors() {
+        assert!(to_duration("5x").is_err());
+        assert!(to_duration("1..5s").is_err());
+        assert!(to_duration("99999999999999999999999999999999999999w").is_err());
+    }
 }
@@ -0,0 +1,173 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use flate2::write::GzEncoder;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// how dst files are compressed while being written
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// extension the dst file gains unless --compress-keep-ext
+    pub fn ext(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+}
+
+/// counts what actually reaches the dst writer, i.e. compressed bytes
+pub struct CountingWriter {
+    inner: Box<dyn Write + Send>,
+    count: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Enc {
+    Plain(CountingWriter),
+    Gzip(GzEncoder<CountingWriter>),
+    Zstd(zstd::stream::write::Encoder<'static, CountingWriter>),
+}
+
+/// Compresses into the dst writer.  The stream must be ended with finish so
+/// the trailer is written before the inner writer's final flush - dropping
+/// it unfinished finishes it too but can only log errors.
+pub struct CompressWriter {
+    enc: Option<Enc>,
+}
+
+impl CompressWriter {
+    pub fn new(inner: Box<dyn Write + Send>, compression: Compression, level: u32) -> std::io::Result<Self> {
+        let cw = CountingWriter { inner, count: 0 };
+        let enc = match compression {
+            Compression::None => Enc::Plain(cw),
+            Compression::Gzip => Enc::Gzip(GzEncoder::new(cw, flate2::Compression::new(level))),
+            Compression::Zstd => Enc::Zstd(zstd::stream::write::Encoder::new(cw, level as i32)?),
+        };
+        Ok(CompressWriter { enc: Some(enc) })
+    }
+
+    /// writes any trailer, flushes the dst writer and returns the bytes it got
+    pub fn finish(&mut self) -> std::io::Result<u64> {
+        let mut cw = match self.enc.take() {
+            None => return Err(std::io::Error::other("compressed stream already finished")),
+            Some(Enc::Plain(cw)) => cw,
+            Some(Enc::Gzip(e)) => e.finish()?,
+            Some(Enc::Zstd(e)) => e.finish()?,
+        };
+        cw.flush()?;
+        Ok(cw.count)
+    }
+}
+
+impl Write for CompressWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.enc {
+            None => Err(std::io::Error::other("compressed stream already finished")),
+            Some(Enc::Plain(w)) => w.write(buf),
+            Some(Enc::Gzip(w)) => w.write(buf),
+            Some(Enc::Zstd(w)) => w.write(buf),
+        }
+    }
+
+    /// only pushes buffered data along - it does not end the stream
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.enc {
+            None => Ok(()),
+            Some(Enc::Plain(_)) => Ok(()),
+            Some(Enc::Gzip(w)) => w.flush(),
+            Some(Enc::Zstd(w)) => w.flush(),
+        }
+    }
+}
+
+impl Drop for CompressWriter {
+    fn drop(&mut self) {
+        if self.enc.is_some() {
+            if let Err(e) = self.finish() {
+                error!("finishing compressed stream on close failed: {}", e);
+            }
+        }
+    }
+}
+
+/// lets the threaded copier own a writer the caller can still finish
+pub struct SharedWriter(pub Arc<Mutex<CompressWriter>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// undoes the compression of a dst file when reading it back
+pub fn decoder(r: Box<dyn Read + Send>, compression: Compression) -> std::io::Result<Box<dyn Read + Send>> {
+    match compression {
+        Compression::None => Ok(r),
+        Compression::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(r))),
+        Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(r)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the dst writer, with the bytes it got kept for the test to read back
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decoder_reads_back_what_was_written() {
+        let data = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let sink = Sink::default();
+            let mut w = CompressWriter::new(Box::new(sink.clone()), *compression, 3).unwrap();
+            for chunk in data.chunks(7001) {
+                w.write_all(chunk).unwrap();
+            }
+            let count = w.finish().unwrap();
+            let written = sink.0.lock().unwrap().clone();
+            assert_eq!(count, written.len() as u64, "{:?}", compression);
+            assert!(w.write(b"x").is_err());
+
+            let mut read = vec![];
+            decoder(Box::new(std::io::Cursor::new(written)), *compression).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == data, "{:?}", compression);
+        }
+    }
+}
@@ -10,12 +10,16 @@ use anyhow::{anyhow, Context};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn, Record};
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use crossbeam_channel::{Receiver, Sender};
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
-struct Copier {
+/// A reader and a writer thread passing a fixed ring of buffers between them.
+/// Files are handed over one pair at a time with copy; the threads end when
+/// the Copier is dropped.  Each xfer thread keeps one for all its files with
+/// --threaded-copy, so no threads are started per file.
+pub struct Copier {
     read_h_s: Sender<Arc<Mutex<Box<dyn Read + Send>>>>,
     write_h_s: Sender<Arc<Mutex<Box<dyn Write + Send>>>>,
     read_h_r: Receiver<Arc<Mutex<Box<dyn Read + Send>>>>,
@@ -28,13 +32,20 @@ struct Copier {
     read_r: Receiver<Option<Vec<u8>>>,
     write_s: Sender<Option<Vec<u8>>>,
 
-    res_s: Sender<usize>,
-    res_w: Receiver<usize>,
+    res_s: Sender<(Side, Result<usize>)>,
+    res_w: Receiver<(Side, Result<usize>)>,
 
     read_t: JoinHandle<()>,
     write_t: JoinHandle<()>,
 }
 
+/// which thread a result came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Read,
+    Write,
+}
+
 impl Copier {
     pub fn new(buff_size: usize, buff_ring_size: usize) -> Self {
         //hmmmm... is this worth is?
@@ -44,18 +55,24 @@ impl Copier {
         let (write_s, read_r) = crossbeam_channel::unbounded::<Option<Vec<u8>>>();
         let (res_s, res_w) = crossbeam_channel::unbounded();
 
+        // named after the xfer thread they serve
+        let owner = std::thread::current().name().unwrap_or("copier").to_string();
         let read_t = {
             let file_recv = read_h_r.clone();
             let buff_send = read_s.clone();
             let buff_recycle = read_r.clone();
-            spawn(move || reader_thread(file_recv, buff_send, buff_recycle))
+            let res = res_s.clone();
+            std::thread::Builder::new().name(format!("{}:read", owner))
+                .spawn(move || reader_thread(file_recv, buff_send, buff_recycle, res)).expect("copier reader thread start failed")
         };
 
         let write_t = {
             let file_recv = write_h_r.clone();
             let buff_send = write_r.clone();
             let buff_recycle = write_s.clone();
-            spawn(move || writer_thread(file_recv, buff_send, buff_recycle))
+            let res = res_s.clone();
+            std::thread::Builder::new().name(format!("{}:write", owner))
+                .spawn(move || writer_thread(file_recv, buff_send, buff_recycle, res)).expect("copier writer thread start failed")
         };
 
         for _ in 0..buff_ring_size {
@@ -79,30 +96,212 @@ impl Copier {
             write_t
         }
     }
+
+    /// copies all of reader into writer and returns the bytes copied
+    pub fn copy(&self, reader: Arc<Mutex<Box<dyn Read + Send>>>, writer: Arc<Mutex<Box<dyn Write + Send>>>) -> Result<usize> {
+        self.read_h_s.send(reader).context("handing reader to copier thread")?;
+        self.write_h_s.send(writer).context("handing writer to copier thread")?;
+        let mut read = None;
+        let mut write = None;
+        for _ in 0..2 {
+            match self.res_w.recv().context("copier thread went away")? {
+                (Side::Read, r) => read = Some(r),
+                (Side::Write, r) => write = Some(r),
+            }
+        }
+        match (read.unwrap(), write.unwrap()) {
+            (Err(er), Err(ew)) => Err(anyhow!("error during transfer on read and writer thread: r: {:?} w: {:?}", &er, &ew))?,
+            (Err(er), Ok(_)) => Err(anyhow!("error during transfer on read thread: {:?}", &er))?,
+            (Ok(bytes_read), Err(ew)) => Err(anyhow!("error during transfer on writer thread after reading: {} bytes: error: {:?}", bytes_read, &ew))?,
+            (Ok(read), Ok(_)) => Ok(read),
+        }
+    }
 }
 
+/// Fills buffers from the recycle channel and sends them on to the writer.
+/// A None after the last buffer ends each file; the buffer in hand at EOF is
+/// kept as a spare for the next file so the ring never shrinks.
 fn reader_thread(file_recv: Receiver<Arc<Mutex<Box<dyn Read + Send>>>>,
                  buff_send:  Sender<Option<(usize, Vec<u8>)>>,
-                 buff_recycle: Receiver<Option<Vec<u8>>>) -> () {
-
-
-    ()
+                 buff_recycle: Receiver<Option<Vec<u8>>>,
+                 res: Sender<(Side, Result<usize>)>) {
+    let mut spare: Option<Vec<u8>> = None;
+    for handle in file_recv.iter() {
+        let mut reader = match handle.lock() {
+            Ok(r) => r,
+            Err(_) => {
+                let _ = buff_send.send(None);
+                let _ = res.send((Side::Read, Err(anyhow!("reader mutex poisoned"))));
+                continue;
+            }
+        };
+        let mut read = 0usize;
+        let r = loop {
+            let now = Instant::now();
+            let mut buf = match spare.take() {
+                Some(b) => b,
+                None => match buff_recycle.recv() {
+                    Ok(Some(b)) => b,
+                    Ok(None) | Err(_) => break Err(anyhow!("buffer ring closed in reader thread")),
+                },
+            };
+            let afterrecv = now.elapsed().as_micros();
+            match fill_buff(&mut **reader, &mut buf[..]).context("fail on regular read") {
+                Err(e) => {
+                    spare = Some(buf);
+                    break Err(e);
+                }
+                Ok(0) => {
+                    spare = Some(buf);
+                    break Ok(read);
+                }
+                Ok(len) => {
+                    let afterread = now.elapsed().as_micros();
+                    read += len;
+                    if buff_send.send(Some((len, buf))).is_err() {
+                        break Err(anyhow!("writer thread went away"));
+                    }
+                    let aftersend = now.elapsed().as_micros();
+                    trace!("read: {}  waittime: {}  readtime: {}  sendtime: {}", len, afterrecv, (afterread-afterrecv), (aftersend-afterread));
+                }
+            }
+        };
+        let _ = buff_send.send(None);
+        let _ = res.send((Side::Read, r));
+    }
 }
 
+/// Writes buffers from the reader until its None and returns each one to the
+/// recycle channel.  After a write error it keeps draining so the reader and
+/// the ring are not left stuck.
 fn writer_thread(file_recv: Receiver<Arc<Mutex<Box<dyn Write + Send>>>>,
                  buff_recv:  Receiver<Option<(usize, Vec<u8>)>>,
-                 buff_recycle: Sender<Option<Vec<u8>>>) -> () {
+                 buff_recycle: Sender<Option<Vec<u8>>>,
+                 res: Sender<(Side, Result<usize>)>) {
+    for handle in file_recv.iter() {
+        let mut writer = handle.lock().ok();
+        let mut r = if writer.is_some() { Ok(0usize) } else { Err(anyhow!("writer mutex poisoned")) };
+        loop {
+            let now = Instant::now();
+            match buff_recv.recv() {
+                Ok(Some((len, buf))) => {
+                    let waittime = now.elapsed().as_micros();
+                    if let (Ok(written), Some(w)) = (&mut r, &mut writer) {
+                        match w.write_all(&buf[..len]) {
+                            Ok(()) => *written += len,
+                            Err(e) => r = Err(anyhow!("writer in writer thread failed: {}", e)),
+                        }
+                    }
+                    let afterwrite = now.elapsed().as_micros();
+                    let _ = buff_recycle.send(Some(buf));
+                    let aftersend = now.elapsed().as_micros();
+                    trace!("wrote: {}  waittime: {}  writetime: {}  sendtime: {}", len, waittime, (afterwrite-waittime), (aftersend-afterwrite));
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    r = Err(anyhow!("reader thread went away"));
+                    break;
+                }
+            }
+        }
+        let _ = res.send((Side::Write, r));
+    }
+}
 
+/// Token bucket holding at most `burst` bytes, refilled at `rate` bytes per
+/// second.  Each xfer opens its own so the limit applies per xfer thread.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
 }
 
-fn fill_buff(handle: &mut MutexGuard<dyn Read>, buff: &mut [u8]) -> Result<usize> {
+impl RateLimiter {
+    pub fn new(rate: usize, burst: usize) -> Self {
+        RateLimiter { rate: rate as f64, burst: burst as f64, tokens: burst as f64, last: Instant::now() }
+    }
+
+    /// takes len bytes worth of tokens, sleeping when the bucket runs dry
+    pub fn consume(&mut self, len: usize) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+        self.tokens -= len as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+}
+
+/// applies a RateLimiter after every read
+pub struct ThrottledReader {
+    inner: Box<dyn Read + Send>,
+    limiter: RateLimiter,
+}
+
+impl ThrottledReader {
+    pub fn new(inner: Box<dyn Read + Send>, limiter: RateLimiter) -> Self {
+        ThrottledReader { inner, limiter }
+    }
+}
+
+impl Read for ThrottledReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.limiter.consume(len);
+        Ok(len)
+    }
+}
+
+/// how long each --min-rate measuring window is
+pub const MIN_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// fails a read with TimedOut once two windows in a row came in under
+/// min_rate bytes per second, so a dying connection is given up on
+pub struct MinRateReader {
+    inner: Box<dyn Read + Send>,
+    min_rate: usize,
+    window_start: Instant,
+    window_bytes: usize,
+    slow_windows: u32,
+}
+
+impl MinRateReader {
+    pub fn new(inner: Box<dyn Read + Send>, min_rate: usize) -> Self {
+        MinRateReader { inner, min_rate, window_start: Instant::now(), window_bytes: 0, slow_windows: 0 }
+    }
+}
+
+impl Read for MinRateReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.window_bytes += len;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= MIN_RATE_WINDOW {
+            let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            if rate < self.min_rate as f64 {
+                self.slow_windows += 1;
+                if self.slow_windows >= 2 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::TimedOut,
+                        format!("xfer rate {:.0} B/s is under --min-rate {} B/s for 2 windows in a row", rate, self.min_rate)));
+                }
+            } else {
+                self.slow_windows = 0;
+            }
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        Ok(len)
+    }
+}
+
+fn fill_buff(handle: &mut dyn Read, buff: &mut [u8]) -> Result<usize> {
     // eprintln!("call fill");
     let mut sz = handle.read(&mut buff[..])?;
     // eprintln!("mid read: {}", sz);
     loop {
-        if sz == 0 {
-            return Ok(sz);
-        } else if sz == buff.len() {
+        if sz == 0 || sz == buff.len() {
             return Ok(sz);
         }
 
@@ -117,76 +316,159 @@ fn fill_buff(handle: &mut MutexGuard<dyn Read>, buff: &mut [u8]) -> Result<usize
     }
 }
 
-pub fn copier(p_reader: &mut Arc<Mutex<Box<dyn Read + Send>>>, p_writer: &mut Arc<Mutex<Box<dyn Write + Send>>>, buff_size: usize, buff_ring_size: usize) -> Result<usize> {
-    let (_r_send, _w_recv) = crossbeam_channel::unbounded::<Option<(usize, Vec<u8>)>>();
-    let (_w_send, _r_recv) = crossbeam_channel::unbounded::<Option<Vec<u8>>>();
-
-    let mut t_reader = p_reader.clone();
-    let mut t_writer = p_writer.clone();
+/// copies a whole local file into another, in the kernel with
+/// copy_file_range when built with zero-copy-local, else through a buffer
+pub fn copy_local(src: &std::fs::File, dst: &std::fs::File, buff_size: usize) -> Result<u64> {
+    #[cfg(feature = "zero-copy-local")]
+    {
+        if let Some(size) = copy_file_range_all(src, dst)? {
+            return Ok(size);
+        }
+    }
+    let mut r = std::io::BufReader::with_capacity(buff_size, src);
+    let mut w = std::io::BufWriter::with_capacity(buff_size, dst);
+    let size = std::io::copy(&mut r, &mut w)?;
+    w.flush()?;
+    Ok(size)
+}
 
-    // prime the circle of buffers
-    for _ in 0..buff_ring_size {
-        _w_send.send(Some(vec![0u8; buff_size])).context("send in priming of copier routine failed")?;
+/// None when the kernel or the filesystem pair cannot copy_file_range and
+/// nothing was copied yet, so the caller can fall back to a buffered copy
+#[cfg(feature = "zero-copy-local")]
+fn copy_file_range_all(src: &std::fs::File, dst: &std::fs::File) -> Result<Option<u64>> {
+    use nix::errno::Errno;
+    let mut size = 0u64;
+    loop {
+        match nix::fcntl::copy_file_range(src, None, dst, None, 1 << 30) {
+            Ok(0) => return Ok(Some(size)),
+            Ok(n) => size += n as u64,
+            Err(Errno::ENOSYS) | Err(Errno::EXDEV) | Err(Errno::EOPNOTSUPP) | Err(Errno::EINVAL) if size == 0 => {
+                debug!("copy_file_range not possible here so copying through a buffer");
+                return Ok(None);
+            }
+            Err(e) => return Err(anyhow!("copy_file_range failed after {} bytes: {}", size, e)),
+        }
     }
+}
 
-    let r_send = _r_send.clone();
-    let w_recv = _w_recv.clone();
+/// One xfer thread's copy buffer size under --adaptive-buffer, carried from
+/// file to file.  Each copy times its first ADAPT_CYCLES buffers, compares
+/// that rate to the one seen at the previous size and keeps stepping the same
+/// way while it helps, turning around once it hurts.
+pub struct AdaptiveBuffer {
+    size: usize,
+    min: usize,
+    max: usize,
+    growing: bool,
+    last_rate: Option<f64>,
+}
 
-    let w_send = _w_send.clone();
-    let r_recv = _r_recv.clone();
+const ADAPT_CYCLES: usize = 4;
+
+impl AdaptiveBuffer {
+    pub fn new(min: usize, max: usize) -> Self {
+        AdaptiveBuffer {
+            size: (64 * 1024).max(min).min(max),
+            min,
+            max,
+            growing: true,
+            last_rate: None,
+        }
+    }
 
+    pub fn size(&self) -> usize {
+        self.size
+    }
 
-    let l_reader = spawn(move || -> usize {
-        let mut written = 0;
-        let mut reader = t_reader.lock().expect("cannot lock reader in reader thread");
-        loop {
-            let now = Instant::now();
-            match r_recv.recv().expect("recv of reader thread failed") {
-                Some(mut buf) => {
-                    let afterrecv = now.elapsed().as_micros();
-                    let len = reader.read(&mut buf[..]).context("fail on regular read").expect("failure in the middle of read in copier thread");
-                    let afterread = now.elapsed().as_micros();
-                    written += len;
-                    if len == 0 {
-                        r_send.send(None).expect("sending none to writer thread");
-                        return written;
-                    } else {
-                        r_send.send(Some((len, buf))).expect("send of reader thread failed");
-                    }
-                    let aftersend = now.elapsed().as_micros();
-                    trace!("read: {}  waittime: {}  readtime: {}  sendtime: {}", len, afterrecv, (afterread-afterrecv), (aftersend-afterread));
-                }
-                None => return written,
+    fn adapt(&mut self, rate: f64) {
+        if let Some(last) = self.last_rate {
+            if rate < last {
+                self.growing = !self.growing;
             }
         }
-    });
+        self.last_rate = Some(rate);
+        let next = if self.growing { self.size.saturating_mul(2) } else { self.size / 2 };
+        let next = next.max(self.min).min(self.max);
+        // pinned against a bound so head back the other way next time
+        if next == self.size {
+            self.growing = !self.growing;
+        }
+        trace!("adaptive buffer {} -> {} at {:.0} B/s", self.size, next, rate);
+        self.size = next;
+    }
 
-    let l_writer = spawn(move || {
-        let mut writer = t_writer.lock().expect("cannot locker writer in writer thread");
+    /// copies like std::io::copy through a buffer of the current size, which
+    /// may change once the first ADAPT_CYCLES buffers are timed
+    pub fn copy(&mut self, r: &mut dyn Read, w: &mut dyn Write) -> Result<u64> {
+        let mut buf = vec![0u8; self.size];
+        let (mut total, mut cycles, mut window) = (0u64, 0usize, 0u64);
+        let start = Instant::now();
         loop {
-            let now = Instant::now();
-            match w_recv.recv().expect("recv in writer thread failed") {
-                Some((len, buf)) => {
-                    if len == 0 {
-                        return ();
-                    } else {
-                        let waittime = now.elapsed().as_micros();
-                        writer.write_all(&buf[..len]).expect("writer in writer thread failed");
-                        let afterwrite = now.elapsed().as_micros();
-                        w_send.send(Some(buf)).expect("send in send thread failed");
-                        let aftersend = now.elapsed().as_micros();
-                        trace!("wrote: {}  waittime: {}  writetime: {}  sendtime: {}", len, waittime, (afterwrite-waittime), (aftersend-afterwrite));
-                    }
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            w.write_all(&buf[..n])?;
+            total += n as u64;
+            if cycles < ADAPT_CYCLES {
+                cycles += 1;
+                window += n as u64;
+                if cycles == ADAPT_CYCLES {
+                    self.adapt(window as f64 / start.elapsed().as_secs_f64().max(1e-9));
+                    buf.resize(self.size, 0);
                 }
-                None => return (),
             }
         }
-    });
+        w.flush()?;
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_lets_the_burst_through_then_holds_to_rate() {
+        let mut limiter = RateLimiter::new(1_000_000, 100_000);
+        let start = Instant::now();
+        limiter.consume(100_000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.consume(200_000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
 
-    match (l_reader.join(), l_writer.join()) {
-        (Err(er), Err(ew)) => Err(anyhow!("error during transfer on read and writer thread: r: {:?} w: {:?}",&er, &ew))?,
-        (Err(er), Ok(_)) => Err(anyhow!("error during transfer on read thread: {:?}",&er))?,
-        (Ok(bytes_read), Err(ew)) => Err(anyhow!("error during transfer on writer thread after reading: {} bytes: error: {:?}",bytes_read, &ew))?,
-        (Ok(written), Ok(_)) => Ok(written),
+    /// a window's worth of time passes without waiting for it
+    fn end_window(r: &mut MinRateReader) {
+        r.window_start = Instant::now().checked_sub(MIN_RATE_WINDOW).unwrap();
+    }
+
+    #[test]
+    fn min_rate_fails_after_two_slow_windows_in_a_row() {
+        let mut buf = [0u8; 16];
+        let mut r = MinRateReader::new(Box::new(std::io::repeat(1)), 1_000);
+        end_window(&mut r);
+        assert_eq!(r.read(&mut buf).unwrap(), 16);
+        // a fast window in between starts the count again
+        r.min_rate = 1;
+        end_window(&mut r);
+        assert_eq!(r.read(&mut buf).unwrap(), 16);
+        r.min_rate = 1_000;
+        end_window(&mut r);
+        assert_eq!(r.read(&mut buf).unwrap(), 16);
+        end_window(&mut r);
+        assert_eq!(r.read(&mut buf).unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn min_rate_leaves_reads_within_a_window_alone() {
+        let mut buf = [0u8; 16];
+        let mut r = MinRateReader::new(Box::new(std::io::repeat(1)), usize::MAX);
+        for _ in 0..100 {
+            assert_eq!(r.read(&mut buf).unwrap(), 16);
+        }
     }
 }
@@ -7,28 +7,31 @@ use std::sync::{Arc, Mutex};
 use crate::vfs::FileStatus;
 use std::thread::spawn;
 use std::convert::TryFrom;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
-fn get_stats(recv: &Receiver<Option<PathBuf>>, list: &mut Arc<Mutex<Option<Vec<(PathBuf, FileStatus)>>>>) -> () {
-    match __get_stats(recv, list) {
-        Err(e) => {
-            error!("error in fast stats thread: {}", e);
-            ()
-        },
-        Ok(())=>()
+type StatList = Arc<Mutex<Option<Vec<(PathBuf, FileStatus)>>>>;
+
+fn get_stats(recv: &Receiver<Option<PathBuf>>, list: &mut StatList, cancel: &Receiver<()>, cancelled: &AtomicBool) {
+    if let Err(e) = __get_stats(recv, list, cancel, cancelled) {
+        error!("error in fast stats thread: {}", e);
     }
 }
 
-fn __get_stats(recv: &Receiver<Option<PathBuf>>, list: &mut Arc<Mutex<Option<Vec<(PathBuf, FileStatus)>>>>) -> Result<()> {
+fn __get_stats(recv: &Receiver<Option<PathBuf>>, list: &mut StatList, cancel: &Receiver<()>, cancelled: &AtomicBool) -> Result<()> {
     loop {
+        // one cancel message stops every thread through the shared flag
+        if cancelled.load(Ordering::Relaxed) || cancel.try_recv().is_ok() {
+            cancelled.store(true, Ordering::Relaxed);
+            break;
+        }
         match recv.recv() {
             Err(e) => return Err(anyhow!("cannot recv in stats thread {}", e)),
             Ok(None) => break,
             Ok(Some(path)) => {
                 crate::STATS.stat_check.fetch_add(1, Ordering::Relaxed);
-                let md = std::fs::metadata(&path)?;
+                let md = std::fs::symlink_metadata(&path)?;
                 let fs = FileStatus::try_from(&md)?;
                 match list.lock() {
                     Err(e) => return Err(anyhow!("cannot lock list in file stats thread {}", e)),
@@ -41,16 +44,21 @@ fn __get_stats(recv: &Receiver<Option<PathBuf>>, list: &mut Arc<Mutex<Option<Vec
 
 }
 
-pub fn get_stats_fast(no_threads: usize, list: &mut Vec<PathBuf>) -> Result<Vec<(PathBuf,FileStatus)>> {
+/// stats list on no_threads threads.  A message on cancel ends them early
+/// and only what was stat'ed by then is returned - crossbeam_channel::never()
+/// for no cancelling.
+pub fn get_stats_fast(no_threads: usize, list: &mut Vec<PathBuf>, cancel: Receiver<()>) -> Result<Vec<(PathBuf,FileStatus)>> {
 
     let results = Arc::new(Mutex::new(Some(vec![])));
+    let cancelled = Arc::new(AtomicBool::new(false));
     {
         let (s, r) = crossbeam_channel::unbounded();
         let mut vec_h = vec![];
         for _t in 0..no_threads {
             let r_c = r.clone();
             let mut res_c = results.clone();
-            let h = spawn(move || get_stats(&r_c, &mut res_c));
+            let (cancel_c, cancelled_c) = (cancel.clone(), cancelled.clone());
+            let h = spawn(move || get_stats(&r_c, &mut res_c, &cancel_c, &cancelled_c));
             vec_h.push(h);
         }
 
@@ -73,5 +81,68 @@ pub fn get_stats_fast(no_threads: usize, list: &mut Vec<PathBuf>) -> Result<Vec<
         }
     }
     let results = results.lock().unwrap().take().unwrap();
+    if cancelled.load(Ordering::Relaxed) {
+        debug!("fast stats cancelled after {} paths", results.len());
+    }
     Ok(results)
-}
\ No newline at end of file
+}
+/// stats with io_uring statx, batch_size requests per submission, all from
+/// this one thread.  Paths that cannot be stat'ed are skipped with a warning.
+/// Errors only if the ring itself cannot be set up, e.g. on kernels before
+/// 5.6 or where seccomp blocks io_uring.
+#[cfg(feature = "io-uring")]
+pub fn get_stats_uring(batch_size: usize, list: &mut Vec<PathBuf>) -> Result<Vec<(PathBuf, FileStatus)>> {
+    use io_uring::{opcode, types, IoUring};
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::time::{Duration, SystemTime};
+    use crate::vfs::FileType;
+
+    let batch_size = batch_size.max(1);
+    let mut ring = IoUring::new(batch_size as u32).map_err(|e| anyhow!("cannot set up io_uring for stats: {}", e))?;
+    let mut results = Vec::with_capacity(list.len());
+    let paths = std::mem::take(list);
+    for batch in paths.chunks(batch_size) {
+        let names = batch.iter()
+            .map(|p| CString::new(p.as_os_str().as_bytes()).map_err(|_| anyhow!("path has a nul byte: {}", p.display())))
+            .collect::<Result<Vec<_>>>()?;
+        let mut bufs: Vec<libc::statx> = vec![unsafe { std::mem::zeroed() }; batch.len()];
+        for (i, (name, buf)) in names.iter().zip(bufs.iter_mut()).enumerate() {
+            let sqe = opcode::Statx::new(types::Fd(libc::AT_FDCWD), name.as_ptr(), buf as *mut libc::statx as *mut types::statx)
+                .flags(libc::AT_SYMLINK_NOFOLLOW)
+                .mask(libc::STATX_TYPE | libc::STATX_SIZE | libc::STATX_MTIME | libc::STATX_INO | libc::STATX_NLINK)
+                .build()
+                .user_data(i as u64);
+            // the ring has batch_size entries so there is always room
+            unsafe { ring.submission().push(&sqe).map_err(|e| anyhow!("io_uring submission queue full: {}", e))?; }
+        }
+        ring.submit_and_wait(batch.len())?;
+        for cqe in ring.completion() {
+            crate::STATS.stat_check.fetch_add(1, Ordering::Relaxed);
+            let i = cqe.user_data() as usize;
+            if cqe.result() < 0 {
+                warn!("cannot stat \"{}\" so skipping it: {}", batch[i].display(), std::io::Error::from_raw_os_error(-cqe.result()));
+                continue;
+            }
+            let st = &bufs[i];
+            // same as FileStatus::try_from(&Metadata) of a symlink_metadata
+            let fs = FileStatus {
+                file_type: match st.stx_mode as u32 & libc::S_IFMT {
+                    libc::S_IFREG => FileType::Regular,
+                    libc::S_IFDIR => FileType::Directory,
+                    libc::S_IFLNK => FileType::Symlink,
+                    _ => FileType::Unknown,
+                },
+                size: st.stx_size,
+                mtime: SystemTime::UNIX_EPOCH + Duration::new(st.stx_mtime.tv_sec.max(0) as u64, st.stx_mtime.tv_nsec),
+                hard_link: if st.stx_nlink > 1 {
+                    Some((libc::makedev(st.stx_dev_major, st.stx_dev_minor) as u64, st.stx_ino))
+                } else {
+                    None
+                },
+            };
+            results.push((batch[i].clone(), fs));
+        }
+    }
+    Ok(results)
+}
@@ -0,0 +1,223 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use suppaftp::native_tls::TlsConnector;
+use suppaftp::{FtpError, NativeTlsConnector, NativeTlsFtpStream, Status};
+use url::Url;
+
+use crate::vfs::{FileStatus, FileType};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+type Ftp = Arc<Mutex<NativeTlsFtpStream>>;
+
+/// FTP server behind an `ftp://user@host/dir` url, or `ftps://` for explicit
+/// TLS on the control and data connections.
+///
+/// There is one control connection per vfs, so an open reader or writer holds
+/// it until it is finished - only one file at a time can be moving.
+pub struct FtpVfs {
+    base_dir: PathBuf,
+    ftp: Ftp,
+}
+
+impl FtpVfs {
+    pub fn new(url: &Url, password: Option<&str>, timeout: Option<Duration>) -> Result<FtpVfs> {
+        let soc = url.socket_addrs(|| Some(21))?[0];
+        let mut ftp = match timeout {
+            Some(timeout) => NativeTlsFtpStream::connect_timeout(soc, timeout),
+            None => NativeTlsFtpStream::connect(soc),
+        }.with_context(|| format!("Ftp connection to url: {} failed", &url))?;
+        if url.scheme() == "ftps" {
+            let host = url.host_str().ok_or_else(|| anyhow!("ftps url needs a host: {}", url))?;
+            let tls = NativeTlsConnector::from(TlsConnector::new().context("Unable to create tls connector")?);
+            ftp = ftp.into_secure(tls, host).with_context(|| format!("Unable to negotiate tls with {}", url))?;
+        }
+        let user = if url.username().is_empty() { "anonymous" } else { url.username() };
+        ftp.login(user, password.unwrap_or(""))
+            .with_context(|| format!("Ftp login as {} failed for {}", user, url))?;
+        ftp.transfer_type(suppaftp::types::FileType::Binary)?;
+        let base_dir = PathBuf::from(url.path());
+        // same as the sftp stat check of the base path
+        ftp.cwd(url.path()).with_context(|| format!("Cannot change to remote path of \"{}\"", url))?;
+        info!("creating ftp vfs for {}", url);
+        Ok(FtpVfs { base_dir, ftp: Arc::new(Mutex::new(ftp)) })
+    }
+
+    pub fn base_dir(&self) -> &PathBuf {
+        &self.base_dir
+    }
+
+    /// Lists with LIST so entries come with their status.  If the server's
+    /// LIST format cannot be parsed, falls back to NLST names that are
+    /// stat'ed later.
+    pub fn list(&self, dir: &Path) -> Result<Vec<(PathBuf, Option<FileStatus>)>> {
+        let mut ftp = self.ftp.lock().unwrap();
+        let lines = ftp.list(Some(&path_str(dir))).with_context(|| format!("ftp LIST of \"{}\"", dir.display()))?;
+        let parsed = lines.iter()
+            .filter(|l| !l.starts_with("total "))
+            .map(|l| suppaftp::list::File::from_str(l))
+            .collect::<std::result::Result<Vec<_>, _>>();
+        let list = match parsed {
+            Ok(files) => files.into_iter()
+                .filter(|f| f.name() != "." && f.name() != "..")
+                .map(|f| {
+                    let file_type = if f.is_directory() {
+                        FileType::Directory
+                    } else if f.is_symlink() {
+                        FileType::Symlink
+                    } else {
+                        FileType::Regular
+                    };
+                    (PathBuf::from(f.name()), Some(FileStatus { file_type, size: f.size() as u64, mtime: f.modified(), hard_link: None }))
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                debug!("cannot parse LIST of \"{}\" so using NLST: {}", dir.display(), e);
+                ftp.nlst(Some(&path_str(dir))).with_context(|| format!("ftp NLST of \"{}\"", dir.display()))?
+                    .into_iter()
+                    // some servers answer with the full path, others the bare name
+                    .filter_map(|n| Path::new(&n).file_name().map(|n| (PathBuf::from(n), None)))
+                    .collect::<Vec<_>>()
+            }
+        };
+        crate::STATS.dirs_check.fetch_add(list.len(), Ordering::Relaxed);
+        Ok(list)
+    }
+
+    pub fn open(&self, path: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let mut ftp = self.ftp.lock().unwrap();
+        if offset > 0 {
+            ftp.resume_transfer(offset as usize).with_context(|| format!("ftp REST {} of \"{}\"", offset, path.display()))?;
+        }
+        let data = ftp.retr_as_stream(path_str(path)).with_context(|| format!("ftp RETR of \"{}\"", path.display()))?;
+        Ok(Box::new(FtpReader { ftp: self.ftp.clone(), data: Some(Box::new(data)) }))
+    }
+
+    pub fn create(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        let data = self.ftp.lock().unwrap().put_with_stream(path_str(path))
+            .with_context(|| format!("ftp STOR of \"{}\"", path.display()))?;
+        Ok(Box::new(FtpWriter { ftp: self.ftp.clone(), data: Some(Box::new(data)), path: path.to_path_buf() }))
+    }
+
+    /// SIZE and MDTM - a path that has neither but can be changed into is a directory
+    pub fn stat(&self, path: &Path) -> Result<FileStatus> {
+        let mut ftp = self.ftp.lock().unwrap();
+        let p = path_str(path);
+        match (ftp.size(&p), ftp.mdtm(&p)) {
+            (Ok(size), Ok(mtime)) => Ok(FileStatus {
+                file_type: FileType::Regular,
+                size: size as u64,
+                mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(mtime.and_utc().timestamp().max(0) as u64),
+                hard_link: None,
+            }),
+            (Err(e), _) | (_, Err(e)) => {
+                match ftp.cwd(&p) {
+                    Ok(()) => (),
+                    // 550 to both is the server saying there is nothing by that name
+                    Err(FtpError::UnexpectedResponse(r)) if r.status == Status::FileUnavailable => {
+                        let msg = format!("no ftp file or dir \"{}\": {}", path.display(), e);
+                        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, msg).into());
+                    }
+                    Err(_) => return Err(anyhow!("cannot stat ftp path \"{}\": {}", path.display(), e)),
+                }
+                Ok(FileStatus { file_type: FileType::Directory, size: 0, mtime: SystemTime::UNIX_EPOCH, hard_link: None })
+            }
+        }
+    }
+
+    pub fn rename(&self, src: &Path, dst: &Path) -> Result<()> {
+        self.ftp.lock().unwrap().rename(path_str(src), path_str(dst))
+            .with_context(|| format!("ftp rename of \"{}\" to \"{}\"", src.display(), dst.display()))
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        self.ftp.lock().unwrap().rm(path_str(path)).with_context(|| format!("ftp delete of \"{}\"", path.display()))
+    }
+
+    pub fn mkdir(&self, path: &Path) -> Result<()> {
+        self.ftp.lock().unwrap().mkdir(path_str(path)).with_context(|| format!("ftp mkdir of \"{}\"", path.display()))
+    }
+
+    /// NOOP on the control connection
+    pub fn probe(&self) -> Result<()> {
+        self.ftp.lock().unwrap().noop().context("ftp probe failed")
+    }
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+/// the control connection waits for the end of transfer reply once the data
+/// connection is done with, so that happens on drop
+struct FtpReader {
+    ftp: Ftp,
+    data: Option<Box<dyn Read + Send>>,
+}
+
+impl Read for FtpReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.data {
+            Some(d) => d.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for FtpReader {
+    fn drop(&mut self) {
+        if let Some(d) = self.data.take() {
+            if let Err(e) = self.ftp.lock().unwrap().finalize_retr_stream(d) {
+                warn!("ftp end of download failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Like the s3 writer the upload is completed on flush, so the server's reply
+/// can fail the xfer before the rename.  Dropping it unflushed completes it
+/// too but can only log errors.
+struct FtpWriter {
+    ftp: Ftp,
+    data: Option<Box<dyn Write + Send>>,
+    path: PathBuf,
+}
+
+impl FtpWriter {
+    fn finish(&mut self) -> Result<()> {
+        match self.data.take() {
+            None => Ok(()),
+            Some(d) => self.ftp.lock().unwrap().finalize_put_stream(d)
+                .with_context(|| format!("ftp end of upload of \"{}\"", self.path.display())),
+        }
+    }
+}
+
+impl Write for FtpWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.data {
+            Some(d) => d.write(buf),
+            None => Err(std::io::Error::other("ftp upload already finished")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.finish().map_err(|e| std::io::Error::other(format!("{:#}", e)))
+    }
+}
+
+impl Drop for FtpWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("ftp upload on close failed: {:#}", e);
+        }
+    }
+}
@@ -28,7 +28,7 @@ use tokio::runtime::Runtime;
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
-const ITR_COUNT: AtomicU64 = AtomicU64::new(0);
+static ITR_COUNT: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
     pub static ref BUILD_INFO: String  = format!("ver: {}  rev: {}  date: {}", env!("CARGO_PKG_VERSION"), env!("VERGEN_SHA_SHORT"), env!("VERGEN_BUILD_DATE"));
@@ -86,7 +86,7 @@ struct Job {
 
 fn main() -> Result<()> {
     let cli: Arc<Cli> = Arc::new(Cli::from_args());
-    crate::util::init_log(cli.log_level);
+    crate::util::init_log(cli.log_level, None);
 
     println!("args: {:?}", &cli);
 
@@ -127,7 +127,7 @@ fn main() -> Result<()> {
     let mut rt = rt_bld.build().unwrap();
 
 
-    let x = rt.block_on(actual(cc))?;
+    rt.block_on(actual(cc))?;
 
     let et = start_f.elapsed().as_secs_f64();
     let iters = ITR_COUNT.fetch_add(0, Ordering::Relaxed);
@@ -174,11 +174,9 @@ async fn actual(cli: Arc<Cli>) -> Result<()> {
 }
 
 async fn eval_dir_entry(cli: Arc<Cli>, d: DirEntry) -> Option<Job> {
-    let mut size = 0u64;
     match d.metadata().await {
         Err(e) => error!("error getting metadata: for {} {}", d.path().display(), e),
         Ok(md) => {
-            size = md.len();
             match canonicalize(cli.path.join(d.path())).await {
                 Err(e) => error!("cannot canonicalize path: {} {}", d.path().display(), e),
                 Ok(fullpath) => {
@@ -192,5 +190,5 @@ async fn eval_dir_entry(cli: Arc<Cli>, d: DirEntry) -> Option<Job> {
             }
         }
     }
-    return None;
+    None
 }
\ No newline at end of file
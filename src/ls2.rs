@@ -58,15 +58,14 @@ pub struct Cli {
 }
 
 fn main() {
-    match run() {
-        Err(e) => error!("got high error: {:?}", e),
-        Ok(()) => (),
+    if let Err(e) = run() {
+        error!("got high error: {:?}", e);
     }
 }
 
 fn run() -> Result<()> {
     let cli:Cli = Cli::from_args();
-    util::init_log(cli.log_level);
+    util::init_log(cli.log_level, None);
 
     let mut data = Vec::with_capacity(cli.vec_pre_alloc_size);
 
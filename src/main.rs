@@ -4,26 +4,31 @@
 // #![allow(unused_mut)]
 // #![allow(unreachable_code)]
 
-use std::io::{BufReader, BufWriter};
-use std::path::{PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::thread::{Builder, sleep, spawn};
 use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Context};
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use lazy_static::lazy_static;
-use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use log::{debug, error, info, trace, warn, LevelFilter};
 use structopt::StructOpt;
 use url::Url;
 
 use sema::Semaphore;
 use track::Tracker;
-use vfs::{FileStatus, Vfs};
+use vfs::{CreateMode, FileStatus, Vfs};
 
-use crate::cli::Cli;
-use crate::track::TrackDelta;
+use crate::compress::{Compression, CompressWriter};
+use crate::cli::{Cli, MaintenanceCli, MaintenanceOp, OnError, SortOrder, SymlinkMode, TickerFormat};
+use crate::track::{TrackDelta, TrackerBackend};
+use crate::verify::{Checksum, ChecksumAlgorithm};
 
 mod cli;
 mod track;
@@ -32,6 +37,16 @@ mod vfs;
 mod fast_stat;
 mod sema;
 mod util;
+mod chunked;
+mod bloom;
+mod sqlite_track;
+mod verify;
+mod s3;
+mod azure;
+mod compress;
+mod ftp;
+mod metrics;
+mod otel;
 
 #[derive(Debug)]
 pub struct Stats {
@@ -42,6 +57,75 @@ pub struct Stats {
     pub stat_check: AtomicUsize,
     pub never2xfer: AtomicUsize,
     pub too_young: AtomicUsize,
+    pub too_small: AtomicUsize,
+    pub too_large: AtomicUsize,
+    pub probe_count: AtomicUsize,
+    pub probe_fail: AtomicUsize,
+    pub readdir_timeouts: AtomicUsize,
+    pub listing_skips: AtomicUsize,
+    pub dedup_skips: AtomicUsize,
+    pub hard_link_skips: AtomicUsize,
+    pub xfer_failures: AtomicUsize,
+    pub checksum_mismatches: AtomicUsize,
+    pub raw_bytes: AtomicUsize,
+    pub wire_bytes: AtomicUsize,
+    /// src bytes of every completed xfer, for the ticker's rate
+    pub bytes_transferred: AtomicU64,
+    /// files the lister has queued over all passes, for the ticker's eta
+    pub paths_queued: AtomicUsize,
+}
+
+impl Stats {
+    /// the counters as they are right now, for the json ticker and run summary
+    pub fn snapshot(&self, start: Instant) -> StatsSnapshot {
+        StatsSnapshot {
+            elapsed_secs: start.elapsed().as_secs_f64(),
+            xfer_count: self.xfer_count.load(Ordering::Relaxed),
+            dirs_check: self.dirs_check.load(Ordering::Relaxed),
+            path_check: self.path_check.load(Ordering::Relaxed),
+            stat_check: self.stat_check.load(Ordering::Relaxed),
+            never2xfer: self.never2xfer.load(Ordering::Relaxed),
+            too_young: self.too_young.load(Ordering::Relaxed),
+            too_small: self.too_small.load(Ordering::Relaxed),
+            too_large: self.too_large.load(Ordering::Relaxed),
+            probe_count: self.probe_count.load(Ordering::Relaxed),
+            probe_fail: self.probe_fail.load(Ordering::Relaxed),
+            readdir_timeouts: self.readdir_timeouts.load(Ordering::Relaxed),
+            listing_skips: self.listing_skips.load(Ordering::Relaxed),
+            dedup_skips: self.dedup_skips.load(Ordering::Relaxed),
+            hard_link_skips: self.hard_link_skips.load(Ordering::Relaxed),
+            xfer_failures: self.xfer_failures.load(Ordering::Relaxed),
+            checksum_mismatches: self.checksum_mismatches.load(Ordering::Relaxed),
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            wire_bytes: self.wire_bytes.load(Ordering::Relaxed),
+            bytes_transferred: self.bytes_transferred.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// plain copy of STATS that serializes
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub elapsed_secs: f64,
+    pub xfer_count: usize,
+    pub dirs_check: usize,
+    pub path_check: usize,
+    pub stat_check: usize,
+    pub never2xfer: usize,
+    pub too_young: usize,
+    pub too_small: usize,
+    pub too_large: usize,
+    pub probe_count: usize,
+    pub probe_fail: usize,
+    pub readdir_timeouts: usize,
+    pub listing_skips: usize,
+    pub dedup_skips: usize,
+    pub hard_link_skips: usize,
+    pub xfer_failures: usize,
+    pub checksum_mismatches: usize,
+    pub raw_bytes: usize,
+    pub wire_bytes: usize,
+    pub bytes_transferred: u64,
 }
 
 lazy_static! {
@@ -53,138 +137,844 @@ lazy_static! {
         stat_check: AtomicUsize::new(0),
         never2xfer: AtomicUsize::new(0),
         too_young: AtomicUsize::new(0),
+        too_small: AtomicUsize::new(0),
+        too_large: AtomicUsize::new(0),
+        probe_count: AtomicUsize::new(0),
+        probe_fail: AtomicUsize::new(0),
+        readdir_timeouts: AtomicUsize::new(0),
+        listing_skips: AtomicUsize::new(0),
+        dedup_skips: AtomicUsize::new(0),
+        hard_link_skips: AtomicUsize::new(0),
+        xfer_failures: AtomicUsize::new(0),
+        checksum_mismatches: AtomicUsize::new(0),
+        raw_bytes: AtomicUsize::new(0),
+        wire_bytes: AtomicUsize::new(0),
+        bytes_transferred: AtomicU64::new(0),
+        paths_queued: AtomicUsize::new(0),
     };
 
     pub static ref SSH_SEMA: Semaphore = Semaphore::new(0);
+    /// --sftp-channel-per-thread sessions by url
+    static ref SHARED_SESSIONS: Mutex<HashMap<String, Arc<Mutex<ssh2::Session>>>> = Mutex::new(HashMap::new());
+
+    /// index into [src_url] + src_url_alt of the source currently in use
+    pub static ref SRC_URL_IDX: AtomicUsize = AtomicUsize::new(0);
+
+    /// src dir mtime at the last listing that left nothing behind, for --watch-dir-mtime
+    pub static ref LAST_SRC_DIR_MTIME: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+    /// hard links found while listing, by the queued path they share content
+    /// with - tracked only once that path's xfer is tracked, reset each pass
+    pub static ref PENDING_LINKS: Mutex<HashMap<PathBuf, Vec<(PathBuf, FileStatus)>>> = Mutex::new(HashMap::new());
+
+    /// when each path was last xferred in this process, for --transfer-dedup-window
+    pub static ref RECENT_XFERS: Mutex<HashMap<PathBuf, Instant>> = Mutex::new(HashMap::new());
+
+    /// set by SIGTERM or SIGINT - in flight files finish, the rest is left for the next run
+    pub static ref SHUTDOWN_REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+
+    /// set by the first file to fail with --on-error abort so the other xfer threads stop
+    pub static ref XFER_ABORTED: AtomicBool = AtomicBool::new(false);
+
+    /// xfer threads that have waited --idle-timeout without a file, reset each pass
+    pub static ref IDLE_XFER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+    /// xfer threads spawned this pass, routes included - all must be idle for --idle-timeout to end it
+    pub static ref XFER_THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    /// set once every xfer thread went --idle-timeout without a file, so the
+    /// rest of the pass, lister included, winds down
+    pub static ref IDLE_ENDED: AtomicBool = AtomicBool::new(false);
+
+    /// --transfer-log destination, shared by the xfer threads
+    pub static ref TRANSFER_LOG: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+    /// identifies this run in syslog and machine readable output
+    pub static ref SESSION_ID: String = format!("{}-{}", chrono::Utc::now().format("%Y%m%d%H%M%S"), std::process::id());
 }
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
+/// one --transfer-log line per completed xfer
+#[derive(Debug, Serialize)]
+struct TransferRecord<'a> {
+    ts: String,
+    src: &'a str,
+    dst: &'a str,
+    size: usize,
+    duration_ms: u128,
+    rate_mbps: f64,
+}
+
+/// a file an xfer thread gave up on, for the end of run report and --error-log
+#[derive(Debug, Serialize)]
+struct FailedFile {
+    path: PathBuf,
+    error: String,
+    attempts: u32,
+}
+
+/// in watch mode, go back to the primary src url this often after rotating away from it
+const PRIMARY_URL_RETRY_CYCLES: usize = 10;
+
+/// final numbers of a run for --output-json-stats and --output-json
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    session_id: String,
+    xfer_secs: f64,
+    transfer_rate_mbps: f64,
+    files_xferred: u64,
+    bytes_xferred: u64,
+    #[serde(flatten)]
+    stats: StatsSnapshot,
+    paths_listed: u64,
+    paths_stat_ed: u64,
+    paths_queued: u64,
+    list: &'a ListResults,
+}
+
 fn main() {
-    if let Err(err) = run() {
+    let res = if std::env::args().nth(1).as_deref() == Some("maintenance") {
+        maintenance(MaintenanceCli::from_iter(std::env::args().skip(1)))
+    } else {
+        run()
+    };
+    if let Err(err) = res {
         error!("Error: {}\n{:?}\n{:#?}", &err, &err, &err);
         std::process::exit(1);
     }
 }
 
+fn maintenance(cli: MaintenanceCli) -> Result<()> {
+    util::init_log(cli.log_level, None);
+    match cli.op {
+        MaintenanceOp::CompactWal { track, max_track_age, tracker_backend } =>
+            Tracker::merge_wal_then_compact(&track, max_track_age, tracker_backend),
+        MaintenanceOp::Export { track, max_track_age, tracker_backend, output } => {
+            let mut tracker = Tracker::new(&track, max_track_age, tracker_backend, 0, 0.0)?;
+            let mut out: Box<dyn Write> = match &output {
+                None => Box::new(std::io::stdout()),
+                Some(p) => Box::new(std::fs::File::create(p).with_context(|| format!("creating csv file \"{}\"", p.display()))?),
+            };
+            let count = tracker.export_csv(&mut out)?;
+            info!("exported {} entries from \"{}\"", count, track.display());
+            // the file tracker opened a fresh WAL that must not be left behind
+            tracker.commit()
+        }
+        MaintenanceOp::Import { track, max_track_age, tracker_backend, input } => {
+            let mut tracker = Tracker::new(&track, max_track_age, tracker_backend, 0, 0.0)?;
+            let mut inp: Box<dyn Read> = match &input {
+                None => Box::new(std::io::stdin()),
+                Some(p) => Box::new(std::fs::File::open(p).with_context(|| format!("opening csv file \"{}\"", p.display()))?),
+            };
+            let count = tracker.import_csv(&mut inp, max_track_age)?;
+            info!("imported {} entries into \"{}\"", count, track.display());
+            tracker.commit()
+        }
+        MaintenanceOp::MergeTracker { into, from, max_track_age, tracker_backend } => {
+            let mut tracker = Tracker::new(&into, max_track_age, tracker_backend, 0, 0.0)?;
+            tracker.merge_from(&from, max_track_age)?;
+            tracker.commit()
+        }
+        MaintenanceOp::Forget { track, max_track_age, tracker_backend, re, dry_run } => {
+            let mut tracker = Tracker::new(&track, max_track_age, tracker_backend, 0, 0.0)?;
+            if dry_run {
+                let found = tracker.matching(&re)?;
+                for t in &found {
+                    println!("{}", t.src_path.display());
+                }
+                info!("would forget {} entries from \"{}\"", found.len(), track.display());
+            } else {
+                let count = tracker.remove_paths(&re)?;
+                info!("forgot {} entries from \"{}\"", count, track.display());
+            }
+            tracker.commit()
+        }
+        MaintenanceOp::QueryTracker { track, max_track_age, tracker_backend, prefix, from, to, json } => {
+            let mut tracker = Tracker::new(&track, max_track_age, tracker_backend, 0, 0.0)?;
+            let mut found = match (from, to) {
+                (None, None) => tracker.query_by_path_prefix(&prefix)?,
+                _ => {
+                    let now = SystemTime::now();
+                    let from = from.map_or(SystemTime::UNIX_EPOCH, |d| now - d);
+                    let to = to.map_or(now, |d| now - d);
+                    let mut found = tracker.query_by_age_range(from, to)?;
+                    found.retain(|t| t.src_path.starts_with(&prefix));
+                    found
+                }
+            };
+            found.sort_by(|a, b| a.src_path.cmp(&b.src_path));
+            print_tracks(&found, json)?;
+            info!("{} entries under \"{}\" in \"{}\"", found.len(), prefix.display(), track.display());
+            tracker.commit()
+        }
+        MaintenanceOp::Inspect { track, max_track_age, tracker_backend, json } => {
+            // measured first since opening a file tracker starts a fresh WAL
+            let mut wal = track.as_os_str().to_owned();
+            wal.push(".wal");
+            let on_disk = [track.clone(), PathBuf::from(wal)].iter()
+                .filter_map(|p| std::fs::metadata(p).ok())
+                .map(|md| md.len())
+                .sum();
+            let mut tracker = Tracker::new(&track, max_track_age, tracker_backend, 0, 0.0)?;
+            let all = tracker.query_all()?;
+            let summary = TrackerSummary {
+                track: track.to_string_lossy(),
+                entries: all.len(),
+                bytes_on_disk: on_disk,
+                // --cache-dirs directories carry DIR_SIZE rather than a size
+                tracked_bytes: all.iter().filter(|t| t.size != track::DIR_SIZE).map(|t| t.size).sum(),
+                oldest_lastmod_epoch: all.iter().map(|t| t.lastmod).min(),
+                newest_lastmod_epoch: all.iter().map(|t| t.lastmod).max(),
+            };
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout(), &summary)?;
+                println!();
+            } else {
+                println!("track {}", summary.track);
+                println!("entries {}", summary.entries);
+                println!("bytes_on_disk {}", summary.bytes_on_disk);
+                println!("tracked_bytes {}", summary.tracked_bytes);
+                println!("oldest_lastmod_epoch {}", summary.oldest_lastmod_epoch.map_or("-".to_string(), |e| e.to_string()));
+                println!("newest_lastmod_epoch {}", summary.newest_lastmod_epoch.map_or("-".to_string(), |e| e.to_string()));
+            }
+            tracker.commit()
+        }
+        MaintenanceOp::Verify { track, max_track_age, tracker_backend, src_url, src_pk, src_password, known_hosts, timeout, tcp_connect_timeout, ssh_handshake_timeout, purge_stale } => {
+            let password = match src_password {
+                Some(p) if p.as_str() == "env:" => Some(std::env::var("PULLPUSH_SRC_PASS").context("password set to env: but PULLPUSH_SRC_PASS is not set")?),
+                p => p.map(|p| p.as_str().to_string()),
+            };
+            let known_hosts = known_hosts.unwrap_or_else(|| PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh").join("known_hosts"));
+            let timeouts = vfs::Timeouts::new(timeout, tcp_connect_timeout, ssh_handshake_timeout);
+            let opts = vfs::ConnectOptions {
+                perm: None,
+                pk: &src_pk,
+                password: password.as_deref(),
+                timeouts,
+                fingerprint_pin: None,
+                known_hosts: Some(&known_hosts),
+                jump: None,
+                azure_sas_token: None,
+                tcp_buf: vfs::TcpBuffers::default(),
+            };
+            let src = Vfs::new(&src_url, &opts, None)?;
+            let mut tracker = Tracker::new(&track, max_track_age, tracker_backend, 0, 0.0)?;
+            let stale = tracker.verify(&src)?;
+            for p in &stale {
+                println!("{}", p.display());
+            }
+            if purge_stale {
+                let count = tracker.remove_listed(&stale)?;
+                info!("purged {} stale entries from \"{}\"", count, track.display());
+            }
+            tracker.commit()
+        }
+        MaintenanceOp::ConvertTracker { format, input, output } => {
+            track::FileTracker::convert(&input, &output, format)?;
+            Ok(())
+        }
+    }
+}
+
+/// what maintenance inspect prints
+#[derive(Serialize)]
+struct TrackerSummary<'a> {
+    track: std::borrow::Cow<'a, str>,
+    entries: usize,
+    bytes_on_disk: u64,
+    tracked_bytes: u64,
+    oldest_lastmod_epoch: Option<u64>,
+    newest_lastmod_epoch: Option<u64>,
+}
+
+/// one query-tracker row
+#[derive(Serialize)]
+struct TrackRow<'a> {
+    path: std::borrow::Cow<'a, str>,
+    lastmod_epoch: u64,
+    size_bytes: u64,
+    checksum: Option<&'a str>,
+}
+
+fn print_tracks(tracks: &[track::Track], json: bool) -> Result<()> {
+    let rows = tracks.iter().map(|t| TrackRow {
+        path: t.src_path.to_string_lossy(),
+        lastmod_epoch: t.lastmod,
+        size_bytes: t.size,
+        checksum: t.checksum.as_deref(),
+    });
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &rows.collect::<Vec<_>>())?;
+        println!();
+    } else {
+        let mut csv = csv::Writer::from_writer(std::io::stdout());
+        for r in rows {
+            csv.serialize(r)?;
+        }
+        csv.flush()?;
+    }
+    Ok(())
+}
+
 fn run() -> Result<()> {
+    let syslog_ident = format!("pullpush-{}", *SESSION_ID);
     let cli = Arc::new({
-        let mut cli = Cli::from_args();
-        check_url(&cli.src_url)?;
-        check_url(&cli.dst_url)?;
+        let mut cli = Cli::from_args_and_config()?;
+        // logging first so the checks below are not failing silently
+        // like syslog, a log file that cannot be opened falls back to stderr alone
+        let log_file = match &cli.log_file {
+            None => None,
+            Some(p) => match util::RotatingFile::open(p, cli.log_max_size, cli.log_rotate_count) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    eprintln!("unable to log to file so using stderr only: {:#}", e);
+                    None
+                }
+            },
+        };
+        util::init_log_bridged(if cli.quiet { LevelFilter::Off } else { cli.log_level }, if cli.syslog_output { Some(&syslog_ident) } else { None }, cli.otlp_endpoint.is_some(), log_file);
+        cli.resolve_passwords()?;
+        cli.load_re_file()?;
+        cli.load_routing_file()?;
+        check_url(&glob_base(&cli.src_url), cli.src_pk.is_some() || cli.src_password.is_some(), false)?;
+        check_url(&cli.dst_url, cli.dst_pk.is_some() || cli.dst_password.is_some(), true)?;
+        for route in &cli.routes {
+            check_url(&route.dst_url, cli.dst_pk.is_some() || cli.dst_password.is_some(), true)?;
+        }
+        for alt in &cli.src_url_alt {
+            check_url(&glob_base(alt), cli.src_pk.is_some() || cli.src_password.is_some(), false)?;
+            if alt.path() != cli.src_url.path() {
+                return Err(anyhow!("alternate source url {} must have the same path as {} since they are mirrors", alt, &cli.src_url));
+            }
+        }
+        if cli.min_age >= cli.max_age {
+            return Err(anyhow!("min-age ({:?}) must be less than max-age ({:?})", cli.min_age, cli.max_age));
+        }
+        if cli.threads == 0 {
+            return Err(anyhow!("threads must be at least 1"));
+        }
+        if cli.copy_buffer_size == 0 {
+            return Err(anyhow!("copy-buffer-size must be positive"));
+        }
+        if cli.cache_dirs && !cli.disable_overwrite {
+            return Err(anyhow!("cache-dirs needs disable-overwrite since a file changed in place leaves its directory's mtime alone"));
+        }
+        if cli.cache_dirs && cli.depth == 1 {
+            warn!("cache-dirs does nothing with a depth of 1");
+        }
         cli
     });
 
-    util::init_log(cli.log_level);
+    debug!("session id: {}", *SESSION_ID);
 
-    for _ in 0..cli.number_of_ssh_startups {
-        SSH_SEMA.release();
+    // dropped at the end of run so the batched spans are flushed
+    let _otel = match &cli.otlp_endpoint {
+        Some(endpoint) => Some(otel::init(endpoint)?),
+        None => None,
+    };
+    let _run_span = tracing::info_span!("run", session.id = %*SESSION_ID).entered();
+
+    if cli.sftp_strict_mode && !cfg!(debug_assertions) {
+        return Err(anyhow!("--sftp-strict-mode is for debugging only and is not available in release builds"));
+    }
+    if cli.mtime_preserve && cli.dst_url.scheme() != "file" && cli.dst_url.scheme() != "sftp" {
+        return Err(anyhow!("--mtime-preserve needs a file or sftp dst url but got {}", &cli.dst_url));
+    }
+    if cli.no_tmp_rename {
+        if cli.dst_create_mode == CreateMode::Exclusive {
+            return Err(anyhow!("--no-tmp-rename cannot be used with --dst-create-mode exclusive since there is no tmp file to lock"));
+        }
+        warn!("--no-tmp-rename writes straight to the final dst path - a failed or killed xfer leaves a partial file there");
+    }
+
+    if cli.connection_test {
+        return connection_test(&cli);
+    }
+
+    if let Some(p) = &cli.transfer_log {
+        let w: Box<dyn Write + Send> = if p.as_os_str() == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(std::fs::OpenOptions::new().create(true).append(true).open(p)
+                .with_context(|| format!("opening transfer log \"{}\"", p.display()))?)
+        };
+        *TRANSFER_LOG.lock().unwrap() = Some(w);
     }
 
-    let src = vfs::Vfs::new(&cli.src_url, cli.dst_perm, &cli.src_pk, Some(cli.timeout))?;
-    // we do not use this dst but it is done to make sure the downstream can connect before too much machinery
-    // get going.  Might be removed later.
-    let _dst = vfs::Vfs::new(&cli.dst_url, cli.dst_perm, &cli.dst_pk, Some(cli.timeout))?;
+    let tic_dur = cli.ticker_interval;
+    let csv = match &cli.metrics_csv {
+        Some(p) => Some(metrics::CsvLog::open(p, cli.metrics_csv_max_size)?),
+        None => None,
+    };
+    let csv_interval = cli.metrics_interval;
+    let tic_json = cli.ticker_format == TickerFormat::Json;
+    let tic_start = Instant::now();
+    let _h_tic = spawn(move || ticker(tic_dur, tic_json, tic_start, csv, csv_interval));
 
-    let tracker = Arc::new(RwLock::new(Tracker::new(&cli.track, cli.max_track_age)?));
+    if let Some(port) = cli.metrics_port {
+        let _h_metrics = metrics::start(port, &cli.src_url, &cli.dst_url)?;
+    }
 
-    let (send, recv) = crossbeam_channel::unbounded();
+    for sig in &[signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        // a second signal while still draining kills the process the old way
+        signal_hook::flag::register_conditional_shutdown(*sig, 1, SHUTDOWN_REQUESTED.clone()).context("registering signal handler")?;
+        signal_hook::flag::register(*sig, SHUTDOWN_REQUESTED.clone()).context("registering signal handler")?;
+    }
 
-    let mut xfer_threads = vec![];
-    for i in 0..cli.threads {
-        let recv_c = recv.clone();
-        let cli_c = cli.clone();
-        let mut tracker_c = tracker.clone();
+    let interval = match cli.watch_interval {
+        None => {
+            let failed = run_pass(&cli)?;
+            if failed > 0 {
+                return Err(anyhow!("{} files failed to xfer", failed));
+            }
+            return Ok(());
+        }
+        Some(interval) => interval,
+    };
+    let mut cycle = 0usize;
+    let mut failures = 0;
+    loop {
+        if cycle > 0 && cycle.is_multiple_of(PRIMARY_URL_RETRY_CYCLES) && SRC_URL_IDX.swap(0, Ordering::Relaxed) != 0 {
+            warn!("retrying primary source url {}", &cli.src_url);
+        }
+        debug!("watch pass {}", cycle);
+        let wait = match run_pass(&cli) {
+            Ok(failed) => {
+                if failed > 0 {
+                    warn!("watch pass {} left {} failed files for the next pass", cycle, failed);
+                }
+                if failures > 0 {
+                    info!("watch pass {} worked after {} failed passes", cycle, failures);
+                }
+                failures = 0;
+                interval
+            }
+            Err(e) if XFER_ABORTED.load(Ordering::Relaxed) => return Err(e),
+            Err(e) => {
+                failures += 1;
+                if failures >= cli.max_connect_failures {
+                    return Err(e.context(format!("giving up after {} failed watch passes in a row", failures)));
+                }
+                error!("watch pass {} failed, {} of {} in a row - reconnecting in {:?}: {:#}", cycle, failures, cli.max_connect_failures, cli.retry_delay, e);
+                cli.retry_delay
+            }
+        };
+        cycle += 1;
+        let until = Instant::now() + wait;
+        while !SHUTDOWN_REQUESTED.load(Ordering::Relaxed) && Instant::now() < until {
+            sleep(Duration::from_millis(250).min(until - Instant::now()));
+        }
+        if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+            info!("shutdown requested - exiting after {} passes", cycle);
+            return Ok(());
+        }
+    }
+}
+
+type XferSender = Sender<Option<(PathBuf, FileStatus)>>;
+
+/// where the lister sends each file: the xfer threads of the first
+/// --routing-file route whose re matches the file name, else --dst-url's
+#[derive(Clone)]
+struct XferQueue {
+    default: XferSender,
+    routes: Vec<(pcre2::bytes::Regex, XferSender)>,
+}
+
+impl XferQueue {
+    fn send(&self, item: (PathBuf, FileStatus)) -> Result<()> {
+        let name = item.0.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let send = self.routes.iter()
+            .find(|(re, _)| re.is_match(name.as_bytes()).unwrap_or(false))
+            .map_or(&self.default, |(_, s)| s);
+        send.send(Some(item)).context("queueing file for xfer")
+    }
+}
+
+/// one listing and transfer of everything it finds, returning how many files failed
+fn run_pass(cli: &Arc<Cli>) -> Result<usize> {
+    SSH_SEMA.reset(cli.number_of_ssh_startups as isize);
+
+    let src = src_vfs(cli)?;
+    // --dst-url first, then a cli per --routing-file route that differs only in its dst_url
+    let dsts = std::iter::once(cli.clone()).chain(cli.routes.iter().map(|r| {
+        let mut c = (**cli).clone();
+        c.dst_url = r.dst_url.clone();
+        Arc::new(c)
+    })).collect::<Vec<_>>();
+    // these dsts are only for the free space check and to make sure the downstream can connect before too much
+    // machinery gets going.
+    for d in &dsts {
+        let dst = dst_vfs(d)?;
+        check_dst_free(d, &dst, 0)?;
+    }
+
+    let tracker = Arc::new(RwLock::new(Tracker::new(&cli.track, cli.max_track_age, cli.tracker_backend, cli.tracker_memory_limit, cli.bloom_fpr)?));
+
+    let quarantine = match (&cli.quarantine_tracker, cli.on_error) {
+        (Some(q), OnError::Quarantine) => Some(Arc::new(RwLock::new(Tracker::new(q, cli.max_track_age, TrackerBackend::File, 0, 0.0)?))),
+        _ => None,
+    };
+
+    IDLE_XFER_THREADS.store(0, Ordering::Relaxed);
+    IDLE_ENDED.store(false, Ordering::Relaxed);
+    PENDING_LINKS.lock().unwrap().clear();
 
-        let h = Builder::new().name(format!("{}:{}", "xfer", i)).spawn(move || xferring(&recv_c, &cli_c, &mut tracker_c)).unwrap();
-        xfer_threads.push(h);
+    // each dst gets an equal share of the threads with the rest going to --dst-url
+    let share = (cli.threads / dsts.len()).max(1);
+    let mut xfer_threads = vec![];
+    let mut channels = vec![];
+    let thread_stats = Arc::new(Mutex::new(vec![]));
+    for (d, dst_cli) in dsts.iter().enumerate() {
+        let (send, recv) = crossbeam_channel::unbounded();
+        let n = if d == 0 { share + cli.threads.saturating_sub(share * dsts.len()) } else { share };
+        for _ in 0..n {
+            let recv_c = recv.clone();
+            let cli_c = dst_cli.clone();
+            let mut tracker_c = tracker.clone();
+            let quarantine_c = quarantine.clone();
+            let thread_stats_c = thread_stats.clone();
+            let span = tracing::Span::current();
+            let id = xfer_threads.len();
+
+            let h = Builder::new().name(format!("{}:{}", "xfer", id)).spawn(move || span.in_scope(|| xferring(id, &recv_c, &cli_c, &mut tracker_c, &quarantine_c, &thread_stats_c))).unwrap();
+            xfer_threads.push(h);
+        }
+        if d > 0 {
+            debug!("{} xfer threads for route to {}", n, dst_cli.dst_url);
+        }
+        channels.push((send, n));
     }
+    XFER_THREAD_COUNT.store(xfer_threads.len(), Ordering::Relaxed);
+    let send = XferQueue {
+        default: channels[0].0.clone(),
+        routes: cli.routes.iter().zip(&channels[1..]).map(|(r, (s, _))| (r.re.clone(), s.clone())).collect(),
+    };
 
     let start = Instant::now();
 
     debug!("listing source");
 
-    let tic_dur = cli.ticker_interval;
-    let _h_tic = spawn(move || ticker(tic_dur));
-
     let h_lister_thread = {
         let (cli_c, tracker_c, send_c) = (cli.clone(), tracker.clone(), send.clone());
         debug!("starting lister thread");
-        Builder::new().name("lister".to_string()).spawn(move || lister_thread(&cli_c, src, &tracker_c, &send_c)).context("lister thread start failed")?
+        let span = tracing::info_span!("lister_thread", src.url = %cli.src_url);
+        Builder::new().name("lister".to_string()).spawn(move || span.in_scope(|| lister_thread(&cli_c, src, &tracker_c, &send_c))).context("lister thread start failed")?
     };
     trace!("lister has started");
-    let l_s = h_lister_thread.join().unwrap()?;
-
-    info!("paths list {} in {:?}  total {:?}", l_s.paths_listed, l_s.dir_list_time, l_s.total_time);
-    info!("paths filtered in {:?}  files stat'ed {} / {:?}", l_s.path_filter_time, l_s.paths_stat_ed, l_s.stat_filter_time);
-    info!("paths q'ed {} in {:?}", l_s.paths_queued, l_s.queue_after_time);
-    info!("write(s) all to tracker: {} in {:?}", l_s.add_all_to_tracker, l_s.add_all_to_tracker_time);
+    // a lister stalled on its upstream is left behind once --idle-timeout ends the pass
+    if cli.idle_timeout.is_some() {
+        while !h_lister_thread.is_finished() && !IDLE_ENDED.load(Ordering::Relaxed) {
+            sleep(Duration::from_millis(100));
+        }
+    }
+    let l_s = if !h_lister_thread.is_finished() && IDLE_ENDED.load(Ordering::Relaxed) {
+        warn!("lister still busy when --idle-timeout ended the pass - leaving it behind");
+        None
+    } else {
+        match h_lister_thread.join().unwrap() {
+            // its queue went away under it
+            Err(e) if IDLE_ENDED.load(Ordering::Relaxed) => {
+                warn!("listing cut short by --idle-timeout: {:#}", e);
+                None
+            }
+            l_s => Some(l_s?),
+        }
+    };
+    if let Some(l_s) = &l_s {
+        STATS.paths_queued.fetch_add(l_s.paths_queued as usize, Ordering::Relaxed);
+
+        info!("paths list {} in {:?}  total {:?}", l_s.paths_listed, l_s.dir_list_time, l_s.total_time);
+        info!("paths filtered in {:?}  files stat'ed {} / {:?}", l_s.path_filter_time, l_s.paths_stat_ed, l_s.stat_filter_time);
+        info!("paths q'ed {} in {:?}", l_s.paths_queued, l_s.queue_after_time);
+        info!("file sizes smallest {}  median {}  largest {}  by <1K/10K/100K/1M/10M/100M/1G/10G/100G/more: {:?}",
+              l_s.smallest_file_bytes, l_s.median_file_bytes, l_s.largest_file_bytes, l_s.size_histogram);
+        info!("write(s) all to tracker: {} in {:?}", l_s.add_all_to_tracker, l_s.add_all_to_tracker_time);
+    }
+    let l_s = l_s.unwrap_or_default();
 
     let mut count = 0u64;
     let mut size = 0u64;
-    for _ in &xfer_threads {
-        send.send(None)?;
+    let mut failed = vec![];
+    let mut aborted = None;
+    for (s, n) in &channels {
+        for _ in 0..*n {
+            // fails only once every thread of the channel has ended on --idle-timeout
+            if s.send(None).is_err() {
+                break;
+            }
+        }
     }
     for h in xfer_threads {
-        let (c, s) = h.join().unwrap();
-        count += c;
-        size += s;
+        match h.join().unwrap() {
+            Ok((c, s, f)) => {
+                count += c;
+                size += s;
+                failed.extend(f);
+            }
+            Err(e) => aborted = aborted.or(Some(e)),
+        }
     }
 
+    log_thread_stats(&mut thread_stats.lock().unwrap());
+
     let mb = (size as f64) / (1024.0 * 1024.0);
 
     let first_xfer = STATS.first_xfer_time.lock().unwrap().take();
-    if first_xfer.is_some() {
-        let first_xfer = first_xfer.unwrap();
+    let mut xfer_secs = start.elapsed().as_secs_f64();
+    if let Some(first_xfer) = first_xfer {
+        xfer_secs = first_xfer.elapsed().as_secs_f64();
         let rate = size as f64 / first_xfer.elapsed().as_secs_f64();
         info!("transferred {} files {:.3} MB in {:.3} secs NOT counting list time Rate: {:.3}MB/s", count, mb, first_xfer.elapsed().as_secs_f64(), rate/(1024.0*1024.0));
         debug!("xfer time: {:.3}", first_xfer.elapsed().as_secs_f64());
     } else {
         info!("transferred {} files {:.3} MB in {:.3} secs counting list time", count, mb, start.elapsed().as_secs_f64());
     }
-    tracker.write().unwrap().commit()?;
+    if cli.compact_on_commit {
+        tracker.write().unwrap().compact(cli.max_track_age)?;
+    }
+    tracing::info_span!("tracker_commit").in_scope(|| tracker.write().unwrap().commit())?;
+    if let Some(q) = &quarantine {
+        q.write().unwrap().commit()?;
+    }
+
+    if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+        warn!("shutdown requested - in flight transfers were finished and the rest left for the next run");
+        warn_orphan_tmps(cli);
+    }
+
+    if !failed.is_empty() {
+        report_failed(cli, &failed)?;
+    }
+    if let Some(e) = aborted {
+        return Err(e);
+    }
 
     debug!("STATS: {:#?}", *STATS);
 
+    if cli.output_json_stats || cli.output_json.is_some() {
+        let snapshot = RunSummary {
+            session_id: SESSION_ID.clone(),
+            xfer_secs,
+            transfer_rate_mbps: if xfer_secs > 0.0 { mb / xfer_secs } else { 0.0 },
+            files_xferred: count,
+            bytes_xferred: size,
+            stats: STATS.snapshot(start),
+            paths_listed: l_s.paths_listed,
+            paths_stat_ed: l_s.paths_stat_ed,
+            paths_queued: l_s.paths_queued,
+            list: &l_s,
+        };
+        if cli.output_json_stats {
+            serde_json::to_writer(std::io::stdout(), &snapshot).context("writing json stats to stdout")?;
+            println!();
+        }
+        match &cli.output_json {
+            Some(p) if p.as_os_str() == "-" => {
+                serde_json::to_writer_pretty(std::io::stdout(), &snapshot).context("writing json output to stdout")?;
+                println!();
+            }
+            Some(p) => {
+                let f = std::fs::File::create(p).with_context(|| format!("creating json output \"{}\"", p.display()))?;
+                serde_json::to_writer_pretty(BufWriter::new(f), &snapshot).with_context(|| format!("writing json output \"{}\"", p.display()))?;
+            }
+            None => (),
+        }
+    }
+
+    Ok(failed.len())
+}
+
+/// one line per xfer thread so a slow connection stands out from the rest
+fn log_thread_stats(stats: &mut [ThreadStats]) {
+    if stats.len() < 2 {
+        return;
+    }
+    stats.sort_by_key(|t| t.thread_id);
+    info!("{:>6} {:>10} {:>16} {:>6} {:>10} {:>10}", "thread", "files", "bytes", "errors", "secs", "MB/s");
+    for t in stats.iter() {
+        let rate = if t.wall_secs > 0.0 { t.bytes as f64 / t.wall_secs / (1024.0 * 1024.0) } else { 0.0 };
+        info!("{:>6} {:>10} {:>16} {:>6} {:>10.3} {:>10.3}", t.thread_id, t.files, t.bytes, t.errors, t.wall_secs, rate);
+    }
+}
+
+/// --error-log gets the failures as a JSON array, otherwise they go to stderr as a table
+fn report_failed(cli: &Cli, failed: &[FailedFile]) -> Result<()> {
+    match &cli.error_log {
+        Some(p) => {
+            let f = std::fs::File::create(p).with_context(|| format!("creating error log \"{}\"", p.display()))?;
+            serde_json::to_writer_pretty(BufWriter::new(f), failed).with_context(|| format!("writing error log \"{}\"", p.display()))?;
+            error!("{} files failed to xfer - see \"{}\"", failed.len(), p.display());
+        }
+        None => {
+            let width = failed.iter().map(|f| f.path.to_string_lossy().len()).max().unwrap_or(0).max(4);
+            eprintln!("{:<width$}  {:>8}  error", "path", "attempts", width = width);
+            for f in failed {
+                eprintln!("{:<width$}  {:>8}  {}", f.path.display().to_string(), f.attempts, f.error, width = width);
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// --min-dst-free check, with room for needed bytes on top
+fn check_dst_free(cli: &Cli, dst: &Vfs, needed: u64) -> Result<()> {
+    if cli.min_dst_free == 0 {
+        return Ok(());
+    }
+    let dst_dir = PathBuf::from(cli.dst_url.path());
+    match dst.free_space(&dst_dir)? {
+        None => warn!("free space of {} cannot be found so --min-dst-free is not checked", &cli.dst_url),
+        Some(free) if free < cli.min_dst_free.saturating_add(needed) =>
+            return Err(anyhow!("only {} bytes free at {} but --min-dst-free is {} and {} bytes are queued - not starting the xfers",
+                free, &cli.dst_url, cli.min_dst_free, needed)),
+        Some(free) => debug!("{} bytes free at {} for {} bytes queued", free, &cli.dst_url, needed),
+    }
     Ok(())
 }
 
+/// tmp files left in the dst dir by transfers that were killed before their rename
+fn warn_orphan_tmps(cli: &Arc<Cli>) {
+    let dst_dir = PathBuf::from(cli.dst_url.path());
+    let list = dst_vfs(cli).and_then(|mut dst| read_dir_list(&mut dst, &dst_dir, Some(1), &|| {}, None));
+    match list {
+        Err(e) => warn!("cannot list dst dir \"{}\" for orphaned tmp files: {:#}", dst_dir.display(), e),
+        Ok(list) => for (p, _) in list.iter().filter(|(p, _)| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with(".tmp"))) {
+            warn!("orphaned tmp file at dst: \"{}\"", dst_dir.join(p).display());
+        },
+    }
+}
 
-fn check_url(url: &Url) -> Result<()> {
+/// writable is for the dst - a file url must then be a dir this user can create files in
+fn check_url(url: &Url, has_auth: bool, writable: bool) -> Result<()> {
     if url.scheme() == "sftp" {
+        if !has_auth { return Err(anyhow!("Url needs a private key or password: {}", &url)); }
         if url.port().is_none() { return Err(anyhow!("Url MUST set port explicitly: {}", &url)); }
-        if url.username().len() == 0 { return Err(anyhow!("Url MUST set username explicitly: {}", &url)); }
+        if url.username().is_empty() { return Err(anyhow!("Url MUST set username explicitly: {}", &url)); }
+        Ok(())
+    } else if url.scheme() == "s3" {
+        if url.host_str().is_none_or(|b| b.is_empty()) { return Err(anyhow!("Url MUST name the bucket as its host: {}", &url)); }
+        s3::s3_region(url)?;
+        Ok(())
+    } else if url.scheme() == "ftp" || url.scheme() == "ftps" {
+        if url.host_str().is_none_or(|h| h.is_empty()) { return Err(anyhow!("Url MUST name the ftp server: {}", &url)); }
+        Ok(())
+    } else if url.scheme() == "az" {
+        if url.host_str().is_none_or(|a| a.is_empty()) { return Err(anyhow!("Url MUST name the storage account as its host: {}", &url)); }
+        if url.path_segments().and_then(|mut s| s.next()).is_none_or(|c| c.is_empty()) { return Err(anyhow!("Url MUST start its path with the container: {}", &url)); }
         Ok(())
     } else if url.scheme() == "file" {
+        let md = std::fs::metadata(url.path()).with_context(|| format!("Url path cannot be read: {}", &url))?;
+        if !md.is_dir() { return Err(anyhow!("Url path is not a directory: {}", &url)); }
+        std::fs::read_dir(url.path()).with_context(|| format!("Url path is not readable: {}", &url))?;
+        if writable {
+            // creating and removing a probe is the only portable way to know a file can land here
+            let probe = Path::new(url.path()).join(format!(".pullpush-probe-{}", std::process::id()));
+            std::fs::OpenOptions::new().write(true).create_new(true).open(&probe)
+                .with_context(|| format!("Url path is not writable: {}", &url))?;
+            std::fs::remove_file(&probe).with_context(|| format!("removing write probe \"{}\"", probe.display()))?;
+        }
         Ok(())
     } else {
         Err(anyhow!("Scheme \"{}\" not handled in url: {}", url.scheme(), &url))?
     }
 }
 
-fn xferring(recv_c: &Receiver<Option<(PathBuf, FileStatus)>>, cli_c: &Arc<Cli>, tracker: &mut Arc<RwLock<Tracker>>) -> (u64, u64) {
-    match xferring_inn(recv_c, cli_c, tracker) {
+/// what one xfer thread did in a pass, logged as it exits and in a table at the end
+#[derive(Debug, Clone)]
+struct ThreadStats {
+    thread_id: usize,
+    files: u64,
+    bytes: u64,
+    errors: u32,
+    wall_secs: f64,
+}
+
+/// only an --on-error abort is passed up, other thread deaths are logged and left to the other threads
+fn xferring(thread_id: usize, recv_c: &Receiver<Option<(PathBuf, FileStatus)>>, cli_c: &Arc<Cli>, tracker: &mut Arc<RwLock<Tracker>>, quarantine: &Option<Arc<RwLock<Tracker>>>, thread_stats: &Arc<Mutex<Vec<ThreadStats>>>) -> Result<(u64, u64, Vec<FailedFile>)> {
+    let start = Instant::now();
+    match xferring_inn(recv_c, cli_c, tracker, quarantine) {
+        Err(e) if XFER_ABORTED.load(Ordering::Relaxed) => Err(e),
         Err(e) => {
             error!("sending thread died: {:#?} - maybe the others will get it down this round", e);
-            (0, 0)
+            Ok((0, 0, vec![]))
+        }
+        Ok(x) => {
+            let ts = ThreadStats { thread_id, files: x.0, bytes: x.1, errors: x.2.len() as u32, wall_secs: start.elapsed().as_secs_f64() };
+            info!("xfer thread {} done: {} files  {} bytes  {} errors in {:.3} secs", ts.thread_id, ts.files, ts.bytes, ts.errors, ts.wall_secs);
+            thread_stats.lock().unwrap().push(ts);
+            Ok(x)
         }
-        Ok(x) => x,
     }
 }
 
-fn xferring_inn(recv_c: &Receiver<Option<(PathBuf, FileStatus)>>, cli: &Arc<Cli>, tracker: &mut Arc<RwLock<Tracker>>) -> Result<(u64, u64)> {
-    let (src,dst) = {
-        let _l = SSH_SEMA.access();
-        (vfs::Vfs::new(&cli.src_url, cli.dst_perm, &cli.src_pk, Some(cli.timeout))?, vfs::Vfs::new(&cli.dst_url, cli.dst_perm, &cli.dst_pk, Some(cli.timeout))?)
+/// a missing src or a refused write fails the same way on every attempt, so
+/// only other errors, timeouts above all, are worth a reconnect and retry
+fn retryable(e: &anyhow::Error) -> bool {
+    e.chain().find_map(|c| c.downcast_ref::<vfs::VfsError>()).is_none_or(|ve| ve.is_retryable())
+}
+
+/// The next file off the queue, or None once the thread should end - on the
+/// end of queue marker, or with --idle-timeout once every xfer thread has gone
+/// that long without a file at the same time.  Idle time counts from the start
+/// of the pass, so a lister stalled before it queues anything ends it too.
+fn next_entry(recv_c: &Receiver<Option<(PathBuf, FileStatus)>>, idle_timeout: Option<Duration>, idle: &mut bool) -> Result<Option<(PathBuf, FileStatus)>> {
+    let t = match idle_timeout {
+        None => return recv_c.recv().context("receiving next entry in channel"),
+        Some(t) => t,
     };
+    loop {
+        match recv_c.recv_timeout(t) {
+            Ok(p) => {
+                if *idle {
+                    *idle = false;
+                    IDLE_XFER_THREADS.fetch_sub(1, Ordering::Relaxed);
+                }
+                return Ok(p);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if IDLE_ENDED.load(Ordering::Relaxed) {
+                    return Ok(None);
+                }
+                if !*idle {
+                    *idle = true;
+                    IDLE_XFER_THREADS.fetch_add(1, Ordering::Relaxed);
+                }
+                // only once every thread is idle at the same time is the pass over
+                if IDLE_XFER_THREADS.load(Ordering::Relaxed) >= XFER_THREAD_COUNT.load(Ordering::Relaxed) {
+                    if !IDLE_ENDED.swap(true, Ordering::Relaxed) {
+                        info!("no file to xfer in {:?} on any thread so ending the pass", t);
+                    }
+                    return Ok(None);
+                }
+            }
+            Err(e) => return Err(e).context("receiving next entry in channel"),
+        }
+    }
+}
+
+fn xferring_inn(recv_c: &Receiver<Option<(PathBuf, FileStatus)>>, cli: &Arc<Cli>, tracker: &mut Arc<RwLock<Tracker>>, quarantine: &Option<Arc<RwLock<Tracker>>>) -> Result<(u64, u64, Vec<FailedFile>)> {
+    let (mut src, mut dst) = connect(cli)?;
 
     let mut count = 0u64;
     let mut size = 0u64;
+    let mut failed = vec![];
     let mut rec_1st_xfer_time = false;
+    let mut last_ok = Instant::now();
+    let mut adaptive = copier::AdaptiveBuffer::new(cli.min_buffer, cli.max_buffer);
+    // started on the first threaded copy and kept for the thread's lifetime
+    let mut copy_threads: Option<copier::Copier> = None;
+    let mut idle = false;
     loop {
-        let p = recv_c.recv().context("receiving next entry in channel")?;
-        match p {
-            None => return Ok((count, size)),
+        match next_entry(recv_c, cli.idle_timeout, &mut idle)? {
+            None => return Ok((count, size, failed)),
+            // the aborting thread already logged why - leave the rest of the queue behind
+            Some(_) if XFER_ABORTED.load(Ordering::Relaxed) => return Ok((count, size, failed)),
+            Some((path, _)) if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) => {
+                trace!("shutting down so not xferring \"{}\"", path.display());
+            }
             Some((path, filestat)) => {
                 // record the first a file start xferring - for better xfer rate stats laters
                 if !rec_1st_xfer_time {
@@ -195,85 +985,696 @@ fn xferring_inn(recv_c: &Receiver<Option<(PathBuf, FileStatus)>>, cli: &Arc<Cli>
                     }
                     rec_1st_xfer_time = true;
                 }
-                let (c, s) = xfer_file(&cli, &path, &src, &dst)?;
+                if let Some(probe) = &cli.connection_probe_file {
+                    if last_ok.elapsed() >= cli.probe_skip_if_recent {
+                        STATS.probe_count.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = src.probe(probe).and_then(|_| dst.probe(probe)) {
+                            STATS.probe_fail.fetch_add(1, Ordering::Relaxed);
+                            warn!("connection probe failed so reconnecting: {:#}", e);
+                            let (s, d) = connect(cli)?;
+                            src = s;
+                            dst = d;
+                        }
+                    }
+                }
+                let mut filestat = filestat;
+                let mut attempt = 0;
+                let res = loop {
+                    match xfer_file(cli, &path, &mut filestat, &src, &dst, &mut adaptive, &mut copy_threads) {
+                        Ok(r) => break Some(r),
+                        Err(e) if !cli.fail_fast && attempt < cli.max_retries && retryable(&e) && !SHUTDOWN_REQUESTED.load(Ordering::Relaxed) => {
+                            let delay = cli.retry_delay * 2u32.pow(attempt.min(16));
+                            attempt += 1;
+                            warn!("xfer of \"{}\" failed on attempt {} of {} so reconnecting in {:?}: {:#}", path.display(), attempt, cli.max_retries + 1, delay, e);
+                            sleep(delay);
+                            // the session itself may be dead so start over from scratch
+                            match connect(cli) {
+                                Ok((s, d)) => { src = s; dst = d; }
+                                Err(e) => warn!("reconnect failed: {:#}", e),
+                            }
+                        }
+                        Err(e) => {
+                            STATS.xfer_failures.fetch_add(1, Ordering::Relaxed);
+                            error!("giving up on \"{}\" after {} attempts: {:#}", path.display(), attempt + 1, e);
+                            failed.push(FailedFile { path: path.clone(), error: format!("{:#}", e), attempts: attempt + 1 });
+                            if cli.fail_fast {
+                                XFER_ABORTED.store(true, Ordering::Relaxed);
+                                return Err(e.context(format!("aborting run since \"{}\" failed with --fail-fast", path.display())));
+                            }
+                            match (cli.on_error, quarantine) {
+                                (OnError::Abort, _) => {
+                                    XFER_ABORTED.store(true, Ordering::Relaxed);
+                                    return Err(e.context(format!("aborting run since \"{}\" failed with --on-error abort", path.display())));
+                                }
+                                (OnError::Quarantine, Some(q)) => {
+                                    let zero = FileStatus { file_type: vfs::FileType::Regular, size: 0, mtime: std::time::UNIX_EPOCH, hard_link: None };
+                                    q.write().unwrap().xferred(&path, zero, None)?;
+                                }
+                                _ => (),
+                            }
+                            break None;
+                        }
+                    }
+                };
+                // failures are already logged - the file stays untracked so a later run picks it up
+                let (c, s, checksum) = match res {
+                    None => {
+                        // its hard links stay untracked too and turn up again with it next run
+                        PENDING_LINKS.lock().unwrap().remove(&path);
+                        if cli.delete_source_on_error {
+                            delete_source(&src, &path);
+                        }
+                        continue;
+                    }
+                    Some(r) => r,
+                };
+                last_ok = Instant::now();
                 STATS.xfer_count.fetch_add(1, Ordering::Relaxed);
+                STATS.bytes_transferred.fetch_add(s, Ordering::Relaxed);
                 size += s;
                 count += c;
-                tracker.write().unwrap().xferred(&path, filestat)?;
+                if let Some(sum) = &checksum {
+                    if let TrackDelta::ChecksumChange = tracker.read().unwrap().check(&path, filestat, Some(sum))? {
+                        warn!("content of \"{}\" changed with no size or mtime change - digest now {}", path.display(), sum);
+                    }
+                }
+                tracker.write().unwrap().xferred(&path, filestat, checksum)?;
+                for (link, link_stat) in PENDING_LINKS.lock().unwrap().remove(&path).unwrap_or_default() {
+                    tracker.write().unwrap().xferred(&link, link_stat, None)?;
+                }
+                if cli.delete_source {
+                    delete_source(&src, &path);
+                }
+                if let Some(archive_dir) = &cli.archive_dir {
+                    archive_source(cli, &src, &path, archive_dir);
+                }
+                if cli.transfer_dedup_window.is_some() {
+                    RECENT_XFERS.lock().unwrap().insert(path, Instant::now());
+                }
             }
         }
     }
-    // Ok((count, size))
+    // Ok((count, size, failed))
 }
 
-fn xfer_file(cli_c: &Arc<Cli>, path: &PathBuf, src: &Vfs, dst: &Vfs) -> Result<(u64, u64)> {
+/// empty --sentinel-ext marker next to a delivered file
+fn write_sentinel(dst: &Vfs, sentinel_path: &Path) -> Result<()> {
+    let mut f = dst.create(sentinel_path)?;
+    // s3 and ftp uploads complete on flush
+    f.flush()?;
+    Ok(())
+}
+
+/// --connection-test: connects to src and every dst, stats and lists a few
+/// entries of each base dir, times a few stat round trips and writes and
+/// removes a file on each dst.  Prints one line per check and errors if any
+/// failed, without touching the tracker.
+fn connection_test(cli: &Arc<Cli>) -> Result<()> {
+    let mut dsts = vec![cli.dst_url.clone()];
+    dsts.extend(cli.routes.iter().map(|r| r.dst_url.clone()));
+    let mut checks: Vec<(String, Result<String>)> = vec![];
+    let src = glob_base(&cli.src_url);
+    connection_checks(&mut checks, "src", &src, src_vfs(cli), None);
+    for url in dsts {
+        let mut c = (**cli).clone();
+        c.dst_url = url.clone();
+        let test_file = PathBuf::from(url.path()).join(format!(".pullpush_test_{}", chrono::Utc::now().format("%Y%m%d%H%M%S%.3f")));
+        connection_checks(&mut checks, "dst", &url, dst_vfs(&c), Some(test_file));
+    }
+
+    let failed = checks.iter().filter(|(_, r)| r.is_err()).count();
+    for (name, res) in &checks {
+        match res {
+            Ok(detail) => println!("ok    {}  {}", name, detail),
+            Err(e) => println!("FAIL  {}  {:#}", name, e),
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} connection checks failed", failed, checks.len()));
+    }
+    println!("all {} connection checks passed", checks.len());
+    Ok(())
+}
+
+/// the checks of one side of --connection-test, skipping the rest once the
+/// connection or the base dir is not there
+fn connection_checks(checks: &mut Vec<(String, Result<String>)>, side: &str, url: &Url, vfs: Result<Vfs>, test_file: Option<PathBuf>) {
+    let start_f = Instant::now();
+    let mut vfs = match vfs {
+        Err(e) => return checks.push((format!("{} connect {}", side, url), Err(e))),
+        Ok(v) => v,
+    };
+    checks.push((format!("{} connect {}", side, url), Ok(format!("in {:?}", start_f.elapsed()))));
+
+    let base = PathBuf::from(url.path());
+    let stat = vfs.stat_follow(&base).map_err(anyhow::Error::from).and_then(|fs| match fs.file_type {
+        vfs::FileType::Directory => Ok(format!("dir last modified {}", chrono::DateTime::<chrono::Utc>::from(fs.mtime).format("%Y-%m-%d %H:%M:%S"))),
+        t => Err(anyhow!("\"{}\" is a {:?} and not a directory", base.display(), t)),
+    });
+    let stat_ok = stat.is_ok();
+    checks.push((format!("{} stat {}", side, base.display()), stat));
+    if !stat_ok {
+        return;
+    }
+
+    let listed = vfs.open_dir(&base).and_then(|mut dir| dir.iter().take(5).collect::<Result<Vec<_>>>())
+        .map(|entries| match entries.is_empty() {
+            true => "empty".to_string(),
+            false => entries.iter().map(|(p, _)| p.file_name().unwrap_or(p.as_os_str()).to_string_lossy().into_owned()).collect::<Vec<_>>().join(" "),
+        });
+    checks.push((format!("{} list first 5", side), listed));
+
+    let mut rtts = vec![];
+    for _ in 0..5 {
+        let start_f = Instant::now();
+        if vfs.stat_follow(&base).is_ok() {
+            rtts.push(start_f.elapsed());
+        }
+    }
+    let latency = match rtts.is_empty() {
+        true => Err(anyhow!("no stat round trip worked")),
+        false => Ok(format!("min {:?} avg {:?} of {}", rtts.iter().min().unwrap(), rtts.iter().sum::<Duration>() / rtts.len() as u32, rtts.len())),
+    };
+    checks.push((format!("{} stat round trip", side), latency));
+
+    if let Some(test_file) = test_file {
+        let start_f = Instant::now();
+        let written = (|| -> Result<String> {
+            let mut f = vfs.create(&test_file).with_context(|| format!("creating \"{}\"", test_file.display()))?;
+            f.write_all(b"pullpush connection test\n")?;
+            // s3 and ftp uploads complete on flush
+            f.flush()?;
+            drop(f);
+            vfs.remove(&test_file).with_context(|| format!("removing \"{}\"", test_file.display()))?;
+            Ok(format!("wrote and removed \"{}\" in {:?}", test_file.display(), start_f.elapsed()))
+        })();
+        checks.push((format!("{} write test file", side), written));
+    }
+}
+
+/// creates the src and dst connections for a transfer, limited by SSH_SEMA
+pub fn connect(cli: &Arc<Cli>) -> Result<(Vfs, Vfs)> {
+    let _l = SSH_SEMA.access();
+    Ok((src_vfs(cli)?, dst_vfs(cli)?))
+}
+
+/// connects to the active source url, and with --src-url-rotate-on-error
+/// walks on through the alternates round-robin until one connects
+fn src_vfs(cli: &Cli) -> Result<Vfs> {
+    let kh = known_hosts(cli);
+    let opts = connect_options(cli, kh.as_deref(), true);
+    if !cli.src_url_rotate_on_error || cli.src_url_alt.is_empty() {
+        return Ok(new_vfs(cli, &glob_base(&cli.src_url), &opts)?.strict_mode(cli.sftp_strict_mode).keepalive(cli.sftp_keepalive_interval));
+    }
+    let urls = std::iter::once(&cli.src_url).chain(cli.src_url_alt.iter()).map(glob_base).collect::<Vec<_>>();
+    let start = SRC_URL_IDX.load(Ordering::Relaxed) % urls.len();
+    let mut last_err = None;
+    for i in 0..urls.len() {
+        let idx = (start + i) % urls.len();
+        match new_vfs(cli, &urls[idx], &opts) {
+            Ok(v) => {
+                if idx != start && SRC_URL_IDX.compare_exchange(start, idx, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                    warn!("rotated source from {} to {}", urls[start], urls[idx]);
+                }
+                return Ok(v.strict_mode(cli.sftp_strict_mode).keepalive(cli.sftp_keepalive_interval));
+            }
+            Err(e) => {
+                warn!("source {} unavailable: {:#}", urls[idx], e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap().context(format!("none of the {} source urls could be connected", urls.len())))
+}
+
+/// --known-hosts, else ~/.ssh/known_hosts, or None to skip the check
+fn known_hosts(cli: &Cli) -> Option<PathBuf> {
+    if cli.insecure_skip_host_check {
+        return None;
+    }
+    match &cli.known_hosts {
+        Some(kh) => Some(kh.clone()),
+        None => Some(PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh").join("known_hosts")),
+    }
+}
+
+/// the jump host and the key it authenticates with
+fn jump_host<'a>(jump: &'a Option<Url>, pk: &'a Option<PathBuf>, cli: &'a Cli) -> Option<(&'a Url, &'a Option<PathBuf>)> {
+    jump.as_ref().map(|j| (j, if cli.jump_pk.is_some() { &cli.jump_pk } else { pk }))
+}
+
+/// --tcp-connect-timeout and --ssh-handshake-timeout fall back to --timeout
+fn timeouts(cli: &Cli) -> vfs::Timeouts {
+    vfs::Timeouts::new(cli.timeout, cli.tcp_connect_timeout, cli.ssh_handshake_timeout)
+}
+
+fn tcp_buffers(cli: &Cli) -> vfs::TcpBuffers {
+    vfs::TcpBuffers { rcvbuf: cli.sftp_tcp_rcvbuf, sndbuf: cli.sftp_tcp_sndbuf }
+}
+
+/// Vfs::new plus, with --sftp-channel-per-thread, the one ssh session per url
+/// that every thread opens its sftp channel on.  A session no channel can be
+/// opened on any more is dropped so the next try connects a fresh one.
+/// the src or dst side's connect options, borrowing known_hosts from the caller
+fn connect_options<'a>(cli: &'a Cli, known_hosts: Option<&'a Path>, src: bool) -> vfs::ConnectOptions<'a> {
+    let (pk, password, fingerprint_pin, jump) = if src {
+        (&cli.src_pk, &cli.src_password, &cli.src_fingerprint_pin, &cli.src_jump_host)
+    } else {
+        (&cli.dst_pk, &cli.dst_password, &cli.dst_fingerprint_pin, &cli.dst_jump_host)
+    };
+    vfs::ConnectOptions {
+        perm: cli.dst_perm,
+        pk,
+        password: password.as_ref().map(|p| p.as_str()),
+        timeouts: timeouts(cli),
+        fingerprint_pin: fingerprint_pin.as_deref(),
+        known_hosts,
+        jump: jump_host(jump, pk, cli),
+        azure_sas_token: cli.azure_sas_token.as_deref(),
+        tcp_buf: tcp_buffers(cli),
+    }
+}
+
+fn new_vfs(cli: &Cli, url: &Url, opts: &vfs::ConnectOptions) -> Result<Vfs> {
+    let validate = |vfs: Vfs| -> Result<Vfs> {
+        if cli.validate_sftp_subsystem {
+            vfs.validate_subsystem(url)?;
+        }
+        Ok(vfs)
+    };
+    if !cli.sftp_channel_per_thread || url.scheme() != "sftp" {
+        return Vfs::new(url, opts, None).and_then(validate);
+    }
+    let sess = {
+        let mut sessions = SHARED_SESSIONS.lock().unwrap();
+        match sessions.get(url.as_str()) {
+            Some(sess) => sess.clone(),
+            None => {
+                let sess = Arc::new(Mutex::new(vfs::sftp_session(url, opts)?));
+                vfs::start_keepalive(&sess, cli.sftp_keepalive_interval);
+                sessions.insert(url.to_string(), sess.clone());
+                sess
+            }
+        }
+    };
+    let res = Vfs::new(url, opts, Some(&sess)).and_then(validate);
+    if res.is_err() {
+        SHARED_SESSIONS.lock().unwrap().remove(url.as_str());
+    }
+    res
+}
+
+fn dst_vfs(cli: &Cli) -> Result<Vfs> {
+    let kh = known_hosts(cli);
+    Ok(new_vfs(cli, &cli.dst_url, &connect_options(cli, kh.as_deref(), false))?.strict_mode(cli.sftp_strict_mode).keepalive(cli.sftp_keepalive_interval))
+}
+
+/// returns the count and size xferred, plus the src digest with --checksum-algorithm
+fn xfer_file(cli_c: &Arc<Cli>, path: &PathBuf, filestat: &mut FileStatus, src: &Vfs, dst: &Vfs, adaptive: &mut copier::AdaptiveBuffer, copy_threads: &mut Option<copier::Copier>) -> Result<(u64, u64, Option<String>)> {
+    let span = tracing::info_span!("xfer_file",
+        file.name = %path.display(),
+        file.size = filestat.size,
+        transfer.rate_mbps = tracing::field::Empty,
+        dst.host = cli_c.dst_url.host_str().unwrap_or("localhost"));
+    let _span = span.enter();
 
     let start_dst_chk = Instant::now();
 
     let mut dst_path = PathBuf::from(cli_c.dst_url.path());
     let mut tmp_path = PathBuf::from(cli_c.dst_url.path());
     let name = path.file_name().unwrap().to_str().unwrap();
-    let tmpname = format!(".tmp{}", name);
+    let name = match cli_c.compress.ext() {
+        Some(ext) if !cli_c.compress_keep_ext => format!("{}.{}", name, ext),
+        _ => name.to_string(),
+    };
     dst_path.push(&name[..]);
-    tmp_path.push(&tmpname[..]);
+    // --no-tmp-rename writes in place so the "tmp" is the final file
+    if cli_c.no_tmp_rename {
+        tmp_path = dst_path.clone();
+    } else {
+        tmp_path.push(format!(".tmp{}", name));
+    }
 
     match dst.stat(&dst_path) {
         Err(_) => (), // silencing useless info... for now warn!("continue with error during stat of dest remote \"{}\", {}", &dst_path.display(), e),
         Ok(_) => {
             if cli_c.disable_overwrite {
                 warn!("file: \"{}\" already at {} and recording it as xferred - no overwrite so skipping", &path.file_name().unwrap().to_string_lossy(), &cli_c.dst_url);
-                return Ok((0, 0));
+                return Ok((0, 0, None));
             } else {
                 warn!("overwriting changed file: \"{}\" already at {} ", &path.file_name().unwrap().to_string_lossy(), &cli_c.dst_url);
             }
         }
     }
+    if cli_c.create_dst_dirs {
+        let dir = dst_path.parent().unwrap();
+        dst.mkdir_all(dir).with_context(|| format!("creating dst dir \"{}\"", dir.display()))?;
+    }
     let start_open = Instant::now();
     let dst_chk_time = start_open.duration_since(start_dst_chk);
 
 
-    let (time_xfer, open_time, size) = if !cli_c.threaded_copy {
-        let mut f_in = BufReader::with_capacity(cli_c.copy_buffer_size, src.open(&path).with_context(|| format!("opening src file direct: {}", path.display()))?);
+    // a compressed stream cannot be written at offsets so it is never chunked
+    let link = filestat.file_type == vfs::FileType::Symlink;
+    let chunked = cli_c.parallel_write_chunks > 1 && cli_c.split_large_files > 0 && filestat.size > cli_c.split_large_files
+        && cli_c.compress == Compression::None && !link;
+    let src_hasher = if cli_c.verify_checksum || cli_c.checksum_algorithm.is_some() {
+        Some(Arc::new(Mutex::new(Checksum::new(checksum_algorithm(cli_c)))))
+    } else {
+        None
+    };
+    let mut digest = None;
+
+    // only a tmp file this xfer made is cleaned up - an exclusive create can
+    // fail on one another instance owns
+    let tmp_cleanup = TmpCleanup { dst, tmp_path: &tmp_path, armed: std::cell::Cell::new(false) };
+    let create = || -> Result<Box<dyn Write + Send>> {
+        let w = create_tmp(cli_c, dst, &tmp_path)?;
+        tmp_cleanup.armed.set(true);
+        Ok(w)
+    };
+    let copied = (|| -> Result<_> { Ok(if chunked {
+        // create (truncate) the tmp file once - chunks then write into it at their offsets
+        drop(create().context("creating dst file for chunked write")?);
+        let time_xfer = Instant::now();
+        let open_time = time_xfer.duration_since(start_open);
+
+        let size = chunked::chunked_copy(cli_c, path, &tmp_path, filestat.size)?;
+        // chunks are read out of order so there is no single stream to tee
+        if let Some(hasher) = &src_hasher {
+            std::io::copy(&mut open_src(cli_c, src, path, filestat, &src_hasher)?, &mut std::io::sink())?;
+            trace!("chunked src digest {} read separately", verify::finish(hasher));
+        }
+        (time_xfer, open_time, size, size as u64)
+    } else if !link && zero_copy_ok(cli_c, src, dst, &src_hasher) {
+        let f_in = std::fs::File::open(path).with_context(|| format!("opening src file for zero copy: {}", path.display()))?;
+        if cli_c.sftp_src_stat_on_open {
+            let stat = FileStatus::try_from(&f_in.metadata()?)?;
+            if stat.size != filestat.size || stat.mtime != filestat.mtime {
+                warn!("src file \"{}\" changed since listing: size {} -> {}  mtime {:?} -> {:?}", path.display(), filestat.size, stat.size, filestat.mtime, stat.mtime);
+            }
+            *filestat = stat;
+        }
+        let f_out = std::fs::File::create(&tmp_path).with_context(|| format!("opening dst file for zero copy: {}", tmp_path.display()))?;
+        tmp_cleanup.armed.set(true);
+        let time_xfer = Instant::now();
+        let open_time = time_xfer.duration_since(start_open);
+
+        let size = copier::copy_local(&f_in, &f_out, cli_c.copy_buffer_size)?;
+        (time_xfer, open_time, size as usize, size)
+    } else if !cli_c.threaded_copy && cli_c.adaptive_buffer {
+        let mut f_in = open_src(cli_c, src, path, filestat, &src_hasher).with_context(|| format!("opening src file direct: {}", path.display()))?;
+        let mut f_out = CompressWriter::new(create().context("opening dst file direct")?, cli_c.compress, cli_c.compress_level)?;
+        let time_xfer = Instant::now();
+        let open_time = time_xfer.duration_since(start_open);
+
+        let size = adaptive.copy(&mut f_in, &mut f_out)? as usize;
+        debug!("adaptive buffer for \"{}\" ended at {} bytes", path.display(), adaptive.size());
+        let wire_size = f_out.finish()?;
+        (time_xfer, open_time, size, wire_size)
+    } else if !cli_c.threaded_copy {
+        let mut f_in = BufReader::with_capacity(cli_c.copy_buffer_size, open_src(cli_c, src, path, filestat, &src_hasher).with_context(|| format!("opening src file direct: {}", path.display()))?);
         let mut f_out = BufWriter::with_capacity(cli_c.copy_buffer_size,
-                                                 dst.create(&tmp_path).context("opening dst file direct")?);
+                                                 CompressWriter::new(create().context("opening dst file direct")?, cli_c.compress, cli_c.compress_level)?);
         let time_xfer = Instant::now();
         let open_time = time_xfer.duration_since(start_open);
 
-        (time_xfer, open_time, std::io::copy(&mut f_in, &mut f_out)? as usize)
+        let size = std::io::copy(&mut f_in, &mut f_out)? as usize;
+        // surfaces write errors a drop would swallow, s3 uploads happen here
+        let wire_size = f_out.into_inner().map_err(|e| e.into_error())?.finish()?;
+        (time_xfer, open_time, size, wire_size)
     } else {
-        let mut f_in = Arc::new(Mutex::new(src.open(&path).with_context(|| format!("opening src file direct: {}", path.display()))?));// as Arc<Mutex<Box<dyn Read + Send>>>;
-        let mut f_out =Arc::new(Mutex::new(dst.create(&tmp_path).context("opening dst file direct")?));// as Arc<Mutex<Box<dyn Write + Send>>>;
+        let f_in = Arc::new(Mutex::new(open_src(cli_c, src, path, filestat, &src_hasher).with_context(|| format!("opening src file direct: {}", path.display()))?));// as Arc<Mutex<Box<dyn Read + Send>>>;
+        let c_out = Arc::new(Mutex::new(CompressWriter::new(create().context("opening dst file direct")?, cli_c.compress, cli_c.compress_level)?));
+        let f_out = Arc::new(Mutex::new(Box::new(compress::SharedWriter(c_out.clone())) as Box<dyn Write + Send>));
 
         let time_xfer = Instant::now();
         let open_time = time_xfer.duration_since(start_open);
 
-        (time_xfer, open_time, copier::copier(&mut f_in, &mut f_out, cli_c.copy_buffer_size, cli_c.buffer_ring_size)?)
-    };
+        let threads = copy_threads.get_or_insert_with(|| copier::Copier::new(cli_c.copy_buffer_size, cli_c.buffer_ring_size));
+        let size = match threads.copy(f_in, f_out) {
+            Ok(size) => size,
+            Err(e) => {
+                // fresh threads for the next file in case one of these died
+                *copy_threads = None;
+                return Err(e);
+            }
+        };
+        let wire_size = c_out.lock().unwrap().finish()?;
+        (time_xfer, open_time, size, wire_size)
+    }) })();
+    let (time_xfer, open_time, size, wire_size) = copied?;
 
     let start_rename = Instant::now();
     let xfer_time = start_rename.duration_since(time_xfer);
 
-    match dst.rename(&tmp_path, &dst_path) {
-        Err(e) => error!("Cannot rename remote tmp to final: \"{}\" to \"{}\" due to {:?}", &tmp_path.display(), &dst_path.display(), e),
+    let renamed = if cli_c.no_tmp_rename { Ok(()) } else { dst.rename(&tmp_path, &dst_path) };
+    match renamed {
+        // an error here keeps the file untracked and the source in place - retries and --on-error take it from there
+        Err(e) => return Err(anyhow::Error::from(e).context(format!("cannot rename remote tmp to final: \"{}\" to \"{}\"", tmp_path.display(), dst_path.display()))),
         Ok(()) => {
+            // the tmp file is the dst file now - it is verify_dst's to remove
+            tmp_cleanup.armed.set(false);
             let rename_time = start_rename.elapsed();
             let t = xfer_time.as_secs_f64();
             let r = (size as f64) / t;
-            info!("xferred: \"{}\" to {} \"{}\"  size: {}  rate: {:.3}MB/s  chk_time: {:?} open time: {:?} xfer_time: {:?} mv_time: {:?}",
+            STATS.raw_bytes.fetch_add(size, Ordering::Relaxed);
+            STATS.wire_bytes.fetch_add(wire_size as usize, Ordering::Relaxed);
+            if cli_c.compress != Compression::None {
+                debug!("compressed \"{}\" from {} to {} bytes", path.display(), size, wire_size);
+            }
+            let sentinel = match &cli_c.sentinel_ext {
+                None => String::new(),
+                Some(ext) => {
+                    let sentinel_path = dst_path.with_extension(ext);
+                    if let Err(e) = write_sentinel(dst, &sentinel_path) {
+                        warn!("cannot create sentinel \"{}\" - the xfer itself stands: {:#}", sentinel_path.display(), e);
+                    }
+                    format!("  sentinel: \"{}\"", sentinel_path.display())
+                }
+            };
+            let mut verified = String::new();
+            if let Some(hasher) = &src_hasher {
+                let src_digest = verify::finish(hasher);
+                if cli_c.verify_checksum {
+                    verified = format!(" verify_time: {:?}", verify_dst(cli_c, dst, &dst_path, &src_digest)?);
+                }
+                if cli_c.checksum_algorithm.is_some() {
+                    digest = Some(src_digest);
+                }
+            }
+            span.record("transfer.rate_mbps", r / (1024f64 * 1024f64));
+            info!("xferred: \"{}\" to {} \"{}\"  size: {}  rate: {:.3}MB/s  chk_time: {:?} open time: {:?} xfer_time: {:?} mv_time: {:?}{}{}",
                   path.display(), &cli_c.dst_url, &path.file_name().unwrap().to_string_lossy(),
-                  size, r / (1024f64 * 1024f64), dst_chk_time, open_time, xfer_time, rename_time);
+                  size, r / (1024f64 * 1024f64), dst_chk_time, open_time, xfer_time, rename_time, verified, sentinel);
             if let Err(e) = dst.set_perm(&dst_path) {
                 error!("could not set dst permissions for {} due to {}", dst_path.display(), e);
             }
+            if cli_c.mtime_preserve {
+                if let Err(e) = dst.set_mtime(&dst_path, filestat.mtime) {
+                    error!("could not set dst mtime for {} due to {}", dst_path.display(), e);
+                }
+            }
+            log_transfer(path, &dst_path, size, xfer_time, r / (1024f64 * 1024f64));
+            if let Some(template) = &cli_c.post_cmd {
+                run_post_cmd(cli_c, template, path, &dst_path, size)?;
+            }
+
+        }
+    }
+
+
+    Ok((1, size as u64, digest))
+}
+
+/// removes the dst tmp file of an xfer that fails anywhere before its rename
+struct TmpCleanup<'a> {
+    dst: &'a Vfs,
+    tmp_path: &'a PathBuf,
+    armed: std::cell::Cell<bool>,
+}
+
+impl Drop for TmpCleanup<'_> {
+    fn drop(&mut self) {
+        if self.armed.get() {
+            match self.dst.remove(self.tmp_path) {
+                Err(e) => warn!("cannot remove tmp file \"{}\" of the failed xfer: {:#}", self.tmp_path.display(), e),
+                Ok(()) => debug!("removed tmp file \"{}\" of the failed xfer", self.tmp_path.display()),
+            }
+        }
+    }
+}
+
+/// local to local with the bytes left as they are, so the kernel can copy
+/// them without a trip through a userspace buffer
+fn zero_copy_ok(cli: &Cli, src: &Vfs, dst: &Vfs, hasher: &Option<Arc<Mutex<Checksum>>>) -> bool {
+    cfg!(feature = "zero-copy-local") && src.is_local() && dst.is_local() && hasher.is_none()
+        && cli.compress == Compression::None && cli.bandwidth_limit == 0 && cli.min_rate == 0 && cli.dst_create_mode == CreateMode::Normal
+}
+
+/// --checksum-algorithm, with sha256 when only --verify-checksum is given
+fn checksum_algorithm(cli: &Cli) -> ChecksumAlgorithm {
+    cli.checksum_algorithm.unwrap_or(ChecksumAlgorithm::Sha256)
+}
+
+/// runs the --post-cmd hook for a file now at dst.  A failing hook only fails
+/// the xfer with --post-cmd-strict.
+fn run_post_cmd(cli: &Arc<Cli>, template: &str, src: &Path, dst: &Path, size: usize) -> Result<()> {
+    let start_f = Instant::now();
+    let out = post_cmd(template, src, dst, size).output()
+        .with_context(|| format!("cannot run post cmd: {}", template))?;
+    debug!("post cmd \"{}\" for \"{}\" exited with {} in {:?}  stdout: {}  stderr: {}", template, dst.display(), out.status, start_f.elapsed(),
+           String::from_utf8_lossy(&out.stdout).trim_end(), String::from_utf8_lossy(&out.stderr).trim_end());
+    if !out.status.success() {
+        if cli.post_cmd_strict {
+            return Err(anyhow!("post cmd \"{}\" for \"{}\" failed with {}", template, dst.display(), out.status));
+        }
+        error!("post cmd \"{}\" for \"{}\" failed with {}", template, dst.display(), out.status);
+    }
+    Ok(())
+}
+
+/// sh -c with the --post-cmd placeholders turned into positional parameters.
+/// The values go in as args rather than into the script, so a file named
+/// "a;rm -rf ~" or "$(curl ..)" is only ever data.
+fn post_cmd(template: &str, src: &Path, dst: &Path, size: usize) -> std::process::Command {
+    let script = template
+        .replace("{src}", "$1")
+        .replace("{dst}", "$2")
+        .replace("{size}", "$3")
+        .replace("{name}", "$4");
+    let mut cmd = std::process::Command::new("sh");
+    cmd.arg("-c").arg(script).arg("pullpush")
+        .arg(src).arg(dst).arg(size.to_string()).arg(dst.file_name().unwrap_or_default());
+    cmd
+}
+
+/// appends a --transfer-log record - a failed write is logged but does not fail the xfer
+fn log_transfer(src: &Path, dst: &Path, size: usize, xfer_time: Duration, rate_mbps: f64) {
+    let mut log = TRANSFER_LOG.lock().unwrap();
+    if let Some(w) = log.as_mut() {
+        let rec = TransferRecord {
+            ts: chrono::Utc::now().to_rfc3339(),
+            src: &src.to_string_lossy(),
+            dst: &dst.to_string_lossy(),
+            size,
+            duration_ms: xfer_time.as_millis(),
+            rate_mbps,
+        };
+        let res = serde_json::to_writer(&mut *w, &rec).map_err(anyhow::Error::from)
+            .and_then(|_| Ok(w.write_all(b"\n").and_then(|_| w.flush())?));
+        if let Err(e) = res {
+            error!("cannot write transfer log record for \"{}\": {:#}", src.display(), e);
+        }
+    }
+}
+
+/// opens the src file - with --sftp-src-stat-on-open the handle is stat'ed
+/// too and filestat is refreshed so the tracker records what was really read.
+/// With a hasher every byte read is also digested.
+fn open_src(cli: &Arc<Cli>, src: &Vfs, path: &Path, filestat: &mut FileStatus, hasher: &Option<Arc<Mutex<Checksum>>>) -> Result<Box<dyn Read + Send>> {
+    let f = if filestat.file_type == vfs::FileType::Symlink {
+        // --include-symlinks copy - the link's content is the path it points to
+        let target = src.read_link(path)?;
+        Box::new(std::io::Cursor::new(target.to_string_lossy().into_owned().into_bytes()))
+    } else if !cli.sftp_src_stat_on_open {
+        src.open(path)?
+    } else {
+        let (f, stat) = src.open_with_stat(path)?;
+        if stat.size != filestat.size || stat.mtime != filestat.mtime {
+            warn!("src file \"{}\" changed since listing: size {} -> {}  mtime {:?} -> {:?}", path.display(), filestat.size, stat.size, filestat.mtime, stat.mtime);
+        }
+        *filestat = stat;
+        f
+    };
+    let f = rate_limited(cli, f, 1);
+    match hasher {
+        None => Ok(f),
+        Some(h) => Ok(Box::new(verify::HashingReader::new(f, h.clone()))),
+    }
+}
 
+/// applies --bandwidth-limit and --min-rate to a src reader.  A file read as
+/// several streams at once splits both evenly, so together they keep to them.
+pub fn rate_limited(cli: &Cli, f: Box<dyn Read + Send>, streams: usize) -> Box<dyn Read + Send> {
+    let f: Box<dyn Read + Send> = if cli.bandwidth_limit > 0 {
+        let rate = (cli.bandwidth_limit / streams).max(1);
+        Box::new(copier::ThrottledReader::new(f, copier::RateLimiter::new(rate, cli.copy_buffer_size)))
+    } else {
+        f
+    };
+    if cli.min_rate > 0 {
+        Box::new(copier::MinRateReader::new(f, (cli.min_rate / streams).max(1)))
+    } else {
+        f
+    }
+}
+
+/// reads back the renamed dst file and compares its digest to the src one.
+/// A mismatched file is removed or moved to --quarantine-dir and an error
+/// returned so the file is neither counted nor tracked.  Gives how long the
+/// read back took.
+fn verify_dst(cli: &Arc<Cli>, dst: &Vfs, dst_path: &Path, src_digest: &str) -> Result<Duration> {
+    let start_f = Instant::now();
+    let dst_digest = verify::digest_of(compress::decoder(dst.open(dst_path)?, cli.compress)?, cli.copy_buffer_size, checksum_algorithm(cli))
+        .with_context(|| format!("reading back \"{}\" for checksum", dst_path.display()))?;
+    if dst_digest == src_digest {
+        debug!("checksum of \"{}\" verified as {} in {:?}", dst_path.display(), dst_digest, start_f.elapsed());
+        return Ok(start_f.elapsed());
+    }
+    STATS.checksum_mismatches.fetch_add(1, Ordering::Relaxed);
+    match &cli.quarantine_dir {
+        Some(dir) => {
+            let q_path = dir.join(dst_path.file_name().unwrap());
+            dst.rename(dst_path, &q_path).with_context(|| format!("moving bad file \"{}\" to quarantine", dst_path.display()))?;
+            error!("moved \"{}\" to \"{}\"", dst_path.display(), q_path.display());
         }
+        None => dst.remove(dst_path).with_context(|| format!("removing bad file \"{}\"", dst_path.display()))?,
     }
+    Err(anyhow!("checksum mismatch for \"{}\": src {} dst {}", dst_path.display(), src_digest, dst_digest))
+}
+
+/// removes a src file once its xfer is done - a failure is only warned about
+fn delete_source(src: &Vfs, path: &Path) {
+    match src.remove(path) {
+        Err(e) => warn!("cannot delete source file \"{}\": {:#}", path.display(), e),
+        Ok(()) => debug!("deleted source file \"{}\"", path.display()),
+    }
+}
 
+fn archive_source(cli: &Arc<Cli>, src: &Vfs, path: &Path, archive_dir: &str) {
+    // a globbed src url has no one dir, so the archive goes under the part before the first wildcard
+    let dir = PathBuf::from(glob_base(&cli.src_url).path()).join(chrono::Local::now().format(archive_dir).to_string());
+    let to = dir.join(path.file_name().unwrap());
+    match src.mkdir_all(&dir).and_then(|_| src.rename(path, &to)) {
+        Err(e) => warn!("cannot archive source file \"{}\" to \"{}\": {:#}", path.display(), to.display(), e),
+        Ok(()) => debug!("archived source file \"{}\" to \"{}\"", path.display(), to.display()),
+    }
+}
 
-    Ok((1, size as u64))
+/// creates the dst tmp file - in exclusive mode an existing tmp file means
+/// another instance may be writing it, so wait and retry before giving up
+fn create_tmp(cli: &Arc<Cli>, dst: &Vfs, tmp_path: &Path) -> Result<Box<dyn Write + Send>> {
+    match cli.dst_create_mode {
+        CreateMode::Normal => Ok(dst.create(tmp_path)?),
+        CreateMode::Exclusive => {
+            for attempt in 0..=cli.exclusive_lock_max_retries {
+                match dst.create_exclusive(tmp_path)? {
+                    Some(f) => return Ok(f),
+                    None => {
+                        warn!("tmp file \"{}\" already exists on attempt {} of {}", tmp_path.display(), attempt + 1, cli.exclusive_lock_max_retries + 1);
+                        if attempt < cli.exclusive_lock_max_retries {
+                            sleep(cli.exclusive_lock_retry_delay);
+                        }
+                    }
+                }
+            }
+            Err(anyhow!("tmp file \"{}\" still exists after {} attempts - another instance may own it", tmp_path.display(), cli.exclusive_lock_max_retries + 1))
+        }
+    }
 }
 
-fn get_file_age(path: &PathBuf, filestat: &FileStatus) -> Duration {
+fn get_file_age(path: &Path, filestat: &FileStatus) -> Duration {
     match SystemTime::now().duration_since(filestat.mtime) {
         Err(e) => {
             warn!("got \"future\" time for path \"{}\", so assuming 0 age.  {:#?}", path.display(), &e);
@@ -283,6 +1684,17 @@ fn get_file_age(path: &PathBuf, filestat: &FileStatus) -> Duration {
     }
 }
 
+/// stable split into --priority-re matches then the rest, so each bucket
+/// keeps its --sort-order and the priority ones are queued, and capped, first
+fn priority_first(list: Vec<(PathBuf, FileStatus)>, re: &pcre2::bytes::Regex) -> Vec<(PathBuf, FileStatus)> {
+    let (mut priority, normal): (Vec<_>, Vec<_>) = list.into_iter().partition(|(p, _)| {
+        p.file_name().is_some_and(|n| re.is_match(n.to_string_lossy().as_bytes()).expect("RE checked failed in priority_first"))
+    });
+    debug!("{} priority files queued ahead of {} others", priority.len(), normal.len());
+    priority.extend(normal);
+    priority
+}
+
 fn keep_path(cli: &Arc<Cli>, path: &PathBuf, tracker: &Arc<RwLock<Tracker>>) -> bool {
     STATS.path_check.fetch_add(1, Ordering::Relaxed);
 
@@ -294,11 +1706,16 @@ fn keep_path(cli: &Arc<Cli>, path: &PathBuf, tracker: &Arc<RwLock<Tracker>>) ->
         Some(s) => s.to_string_lossy(),
     };
 
-    if !cli.re.is_match(&s.as_bytes()).expect("RE checked failed in keep_path") {
+    if !cli.re.is_empty() && !cli.re.iter().any(|re| re.is_match(s.as_bytes()).expect("RE checked failed in keep_path")) {
         trace!("file \"{}\" does not match RE", s);
         return false;
     }
 
+    if cli.exclude_re.iter().any(|re| re.is_match(s.as_bytes()).expect("exclude RE checked failed in keep_path")) {
+        trace!("file \"{}\" matches exclude RE", s);
+        return false;
+    }
+
     if s.starts_with('.') && !cli.include_dot_files {
         trace!("file \"{}\" excluded as a dot file or hidden", &path.display());
         return false;
@@ -308,16 +1725,16 @@ fn keep_path(cli: &Arc<Cli>, path: &PathBuf, tracker: &Arc<RwLock<Tracker>>) ->
         // we only exclude on path check IF we are NOT in overwrite mode
         // yes this slows things down for NFS/NAS sources, but we must do it
         // for safest default path
-        if tracker.read().expect("Unable to read lock track for path check").path_exists_in_tracker(&path) {
+        if tracker.read().expect("Unable to read lock track for path check").path_exists_in_tracker(path) {
             trace!("file \"{}\" already in tracker", &path.display());
-            return false;
+            false
         } else {
             trace!("file \"{}\" not already in tracker", &path.display());
-            return true;
+            true
         }
     } else {
         trace!("file overwrite enable so stat check is needed for \"{}\"", &path.display());
-        return true;
+        true
     }
 }
 
@@ -325,20 +1742,29 @@ const FILE_TOO_OLD: u32 = 1;
 const FILE_TOO_YOUNG: u32 = 2;
 const FILE_NOT_A_FILE: u32 = 4;
 const SRC_FILE_NOT_CHANGED: u32 = 8;
+const FILE_TOO_SMALL: u32 = 16;
+const FILE_TOO_LARGE: u32 = 32;
 
-fn keep_status(cli: &Arc<Cli>, path: &PathBuf, filestatus: FileStatus, tracker: &Arc<RwLock<Tracker>>) -> Result<u32> {
+fn keep_status(cli: &Arc<Cli>, path: &Path, filestatus: FileStatus, tracker: &Arc<RwLock<Tracker>>) -> Result<u32> {
     STATS.stat_check.fetch_add(1, Ordering::Relaxed);
 
-    if filestatus.file_type == vfs::FileType::Regular {
-        let age = get_file_age(&path, &filestatus);
+    let copied_link = filestatus.file_type == vfs::FileType::Symlink && symlink_mode(cli) == SymlinkMode::Copy;
+    if filestatus.file_type == vfs::FileType::Regular || copied_link {
+        let age = get_file_age(path, &filestatus);
         if age > cli.max_age {
             trace!("file \"{}\" too old at {:?}", &path.display(), age);
-            return Ok(FILE_TOO_OLD);
+            Ok(FILE_TOO_OLD)
         } else if age < cli.min_age {
             trace!("file \"{}\" too new at {:?}", &path.display(), age);
-            return Ok(FILE_TOO_YOUNG);
+            Ok(FILE_TOO_YOUNG)
+        } else if filestatus.size < cli.min_size {
+            trace!("file \"{}\" too small at {}", &path.display(), filestatus.size);
+            Ok(FILE_TOO_SMALL)
+        } else if filestatus.size > cli.max_size {
+            trace!("file \"{}\" too large at {}", &path.display(), filestatus.size);
+            Ok(FILE_TOO_LARGE)
         } else if !cli.disable_overwrite {
-            match tracker.read().expect("could not lock reader in keep_status").check(&path, filestatus)? {
+            match tracker.read().expect("could not lock reader in keep_status").check(path, filestatus, None)? {
                 TrackDelta::SizeChange => {
                     info!("src file changed size: \"{}\"",path.display());
                     Ok(0)
@@ -354,38 +1780,340 @@ fn keep_status(cli: &Arc<Cli>, path: &PathBuf, filestatus: FileStatus, tracker:
             Ok(0)
         }
     } else {
-        trace!("dir: {}", &path.display());
+        trace!("not a regular file: {} {:?}", &path.display(), filestatus.file_type);
         Ok(FILE_NOT_A_FILE)
     }
 
 }
 
-fn lister_thread(cli: &Arc<Cli>, src: Vfs, tracker: &Arc<RwLock<Tracker>>, send: &Sender<Option<(PathBuf, FileStatus)>>) -> Result<ListResults> {
+fn lister_thread(cli: &Arc<Cli>, src: Vfs, tracker: &Arc<RwLock<Tracker>>, send: &XferQueue) -> Result<ListResults> {
     match inner_lister_thread(cli, src, tracker, send) {
         Err(e) => {
             error!("lister thread failed: {:?}", e);
-            return Err(e);
+            Err(e)
         }
-        Ok(list_stats) => return Ok(list_stats),
+        Ok(list_stats) => Ok(list_stats),
     }
 }
 
+#[derive(Serialize, Default)]
 struct ListResults {
     pub paths_listed: u64,
+    #[serde(serialize_with = "util::duration_secs")]
     pub dir_list_time: Duration,
 
+    #[serde(serialize_with = "util::duration_secs")]
     pub path_filter_time: Duration,
+    #[serde(serialize_with = "util::duration_secs")]
     pub stat_filter_time: Duration,
+    #[serde(serialize_with = "util::duration_secs")]
     pub queue_after_time: Duration,
+    #[serde(serialize_with = "util::duration_secs")]
     pub add_all_to_tracker_time: Duration,
 
     pub paths_stat_ed: u64,
     pub paths_queued: u64,
     pub add_all_to_tracker: u64,
+    #[serde(serialize_with = "util::duration_secs")]
     pub total_time: Duration,
+
+    /// sizes of the regular files stat'ed - 0 when there were none
+    pub largest_file_bytes: u64,
+    pub smallest_file_bytes: u64,
+    pub median_file_bytes: u64,
+    /// counts by size: <1K, 1K-10K, 10K-100K and so on by tens, the last >=100G
+    pub size_histogram: [u64; 10],
+}
+
+/// which size_histogram bucket a file size falls in
+fn size_bucket(size: u64) -> usize {
+    let mut bound = 1024u64;
+    let mut bucket = 0;
+    while bucket < 9 && size >= bound {
+        bound *= 10;
+        bucket += 1;
+    }
+    bucket
+}
+
+type DirList = Vec<(PathBuf, Option<FileStatus>)>;
+
+fn sort_list(list: &mut [(PathBuf, FileStatus)], order: SortOrder) {
+    match order {
+        SortOrder::SizeAsc => list.sort_by_key(|a| a.1.size),
+        SortOrder::SizeDesc => list.sort_by_key(|a| std::cmp::Reverse(a.1.size)),
+        SortOrder::MtimeAsc => list.sort_by_key(|a| a.1.mtime),
+        SortOrder::MtimeDesc => list.sort_by_key(|a| std::cmp::Reverse(a.1.mtime)),
+        SortOrder::NameAsc => list.sort_by(|a, b| a.0.cmp(&b.0)),
+        SortOrder::NameDesc => list.sort_by(|a, b| b.0.cmp(&a.0)),
+    }
+}
+
+/// --cache-dirs state for one listing: the sub directories the tracker knows
+/// under each directory, so an unchanged one can be stepped through without
+/// listing it, and the directories that did get listed.
+struct DirCache {
+    tracker: Arc<RwLock<Tracker>>,
+    subdirs: HashMap<PathBuf, Vec<PathBuf>>,
+    listed: Mutex<Vec<(PathBuf, FileStatus)>>,
+    skipped: AtomicUsize,
+}
+
+impl DirCache {
+    fn new(tracker: &Arc<RwLock<Tracker>>, base: &Path) -> Result<DirCache> {
+        let mut subdirs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        for t in tracker.read().unwrap().query_by_path_prefix(base)?.into_iter().filter(|t| t.size == track::DIR_SIZE) {
+            if let Some(parent) = t.src_path.parent() {
+                subdirs.entry(parent.to_path_buf()).or_default().push(t.src_path.clone());
+            }
+        }
+        Ok(DirCache { tracker: tracker.clone(), subdirs, listed: Mutex::new(vec![]), skipped: AtomicUsize::new(0) })
+    }
+
+    fn unchanged(&self, dir: &Path, status: &FileStatus) -> Option<Vec<PathBuf>> {
+        match self.tracker.read().unwrap().check_dir(dir, status.mtime) {
+            Ok(TrackDelta::DirectoryUnchanged) => {
+                trace!("dir \"{}\" unchanged so not listed", dir.display());
+                self.skipped.fetch_add(1, Ordering::Relaxed);
+                Some(self.subdirs.get(dir).cloned().unwrap_or_default())
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("tracker check of dir \"{}\" failed so listing it: {:#}", dir.display(), e);
+                None
+            }
+        }
+    }
+
+    /// tracks the listed dirs that had nothing left for a later listing.  As
+    /// with --watch-dir-mtime the mtime has to be clearly in the past, since
+    /// it can be whole seconds.
+    fn record(&self, unsettled: &HashSet<PathBuf>) -> Result<()> {
+        let listed = std::mem::take(&mut *self.listed.lock().unwrap());
+        let mut tracker = self.tracker.write().unwrap();
+        let mut count = 0;
+        for (dir, status) in listed.iter().filter(|(d, _)| !unsettled.contains(d)) {
+            let settled = SystemTime::now().duration_since(status.mtime).map(|d| d > Duration::from_secs(1)).unwrap_or(false);
+            if settled {
+                tracker.dir_listed(dir, status.mtime)?;
+                count += 1;
+            }
+        }
+        info!("cache dirs: {} unchanged dirs not listed, {} of {} listed dirs tracked", self.skipped.load(Ordering::Relaxed), count, listed.len());
+        Ok(())
+    }
+}
+
+fn read_dir_list(src: &mut Vfs, dir_path: &Path, depth: Option<usize>, tick: &dyn Fn(), cache: Option<&DirCache>) -> Result<DirList> {
+    trace!("opening dir: {}", dir_path.display());
+    if depth != Some(1) {
+        let mut listed = vec![];
+        let list = match cache {
+            None => src.list_recursive(dir_path, depth, tick, &|_, _| None, &mut listed),
+            Some(c) => src.list_recursive(dir_path, depth, tick, &|d, fs| c.unchanged(d, fs), &mut listed),
+        }.context("error on recursive listing")?;
+        if let Some(c) = cache {
+            // a timed out attempt may have listed some of them already
+            *c.listed.lock().unwrap() = listed;
+        }
+        return Ok(list);
+    }
+    let mut dir = src.open_dir(dir_path).with_context(|| format!("open dir on base directory: {}", dir_path.display()))?;
+    tick();
+    dir.read_all_dir_entry_tick(tick).context("error on next_dir_entry")
+}
+
+enum ListMsg {
+    Tick,
+    Done(Vfs, Result<DirList>),
+}
+
+/// Lists the base directory.  With --readdir-rpc-timeout the listing runs in
+/// its own thread that ticks after every readdir call; if no tick arrives in
+/// time the hung thread and its connection are abandoned and the listing is
+/// retried on a fresh connection up to --listing-max-retries times.
+fn list_dir(cli: &Arc<Cli>, mut src: Vfs, dir_path: &Path, cache: Option<&Arc<DirCache>>) -> Result<(Vfs, DirList)> {
+    let depth = if cli.depth == 0 { None } else { Some(cli.depth) };
+    let timeout = match cli.readdir_rpc_timeout {
+        None => {
+            let list = read_dir_list(&mut src, dir_path, depth, &|| (), cache.map(|c| &**c))?;
+            return Ok((src, list));
+        }
+        Some(t) => t,
+    };
+
+    let mut src = Some(src);
+    for attempt in 0..=cli.listing_max_retries {
+        let mut s = match src.take() {
+            Some(s) => s,
+            None => {
+                let _l = SSH_SEMA.access();
+                src_vfs(cli)?
+            }
+        };
+        let (send, recv) = crossbeam_channel::unbounded();
+        let (path_c, cache_c) = (dir_path.to_path_buf(), cache.cloned());
+        Builder::new().name("readdir".to_string()).spawn(move || {
+            let send_c = send.clone();
+            let r = read_dir_list(&mut s, &path_c, depth, &move || { let _ = send_c.send(ListMsg::Tick); }, cache_c.as_deref());
+            let _ = send.send(ListMsg::Done(s, r));
+        }).context("readdir thread start failed")?;
+
+        loop {
+            match recv.recv_timeout(timeout) {
+                Ok(ListMsg::Tick) => continue,
+                Ok(ListMsg::Done(s, r)) => return Ok((s, r?)),
+                Err(_) => {
+                    STATS.readdir_timeouts.fetch_add(1, Ordering::Relaxed);
+                    warn!("readdir of \"{}\" took longer than {:?} on attempt {} of {}", dir_path.display(), timeout, attempt + 1, cli.listing_max_retries + 1);
+                    break;
+                }
+            }
+        }
+    }
+    Err(anyhow!("listing \"{}\" timed out on every one of {} attempts", dir_path.display(), cli.listing_max_retries + 1))
+}
+
+/// true for a --src-url path with glob characters in it
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// the url cut back to the dirs before the first glob component, which is
+/// what the src vfs connects to and checks
+fn glob_base(url: &Url) -> Url {
+    if !is_glob(url.path()) {
+        return url.clone();
+    }
+    let mut base = String::from("/");
+    for comp in url.path().split('/').filter(|c| !c.is_empty()) {
+        if is_glob(comp) {
+            break;
+        }
+        base.push_str(comp);
+        base.push('/');
+    }
+    let mut url = url.clone();
+    url.set_path(&base);
+    url
+}
+
+/// the dirs a globbed --src-url matches.  Local paths go to glob::glob, the
+/// others are walked a component at a time matching the listed names.
+fn src_glob_dirs(src: &mut Vfs, pattern: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = if src.is_local() {
+        let mut dirs = vec![];
+        for p in glob::glob(&pattern.to_string_lossy()).with_context(|| format!("bad glob in src url path \"{}\"", pattern.display()))? {
+            let p = p.context("reading glob match")?;
+            if p.is_dir() {
+                dirs.push(p);
+            }
+        }
+        dirs
+    } else {
+        let mut dirs = vec![PathBuf::from("/")];
+        for comp in pattern.iter().skip(1) {
+            let name = comp.to_string_lossy();
+            if !is_glob(&name) {
+                dirs = dirs.into_iter().map(|d| d.join(comp)).collect();
+                continue;
+            }
+            let pat = glob::Pattern::new(&name).with_context(|| format!("bad glob \"{}\" in src url path \"{}\"", name, pattern.display()))?;
+            let mut matched = vec![];
+            for d in &dirs {
+                let entries = match src.open_dir(d).and_then(|mut h| h.read_all_dir_entry()) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        debug!("cannot list \"{}\" while globbing: {:#}", d.display(), e);
+                        continue;
+                    }
+                };
+                for (p, status) in entries {
+                    let full = d.join(p.file_name().unwrap_or_default());
+                    if !p.file_name().is_some_and(|n| pat.matches(&n.to_string_lossy())) {
+                        continue;
+                    }
+                    let is_dir = match status {
+                        Some(s) => s.file_type == vfs::FileType::Directory,
+                        None => src.stat(&full).is_ok_and(|s| s.file_type == vfs::FileType::Directory),
+                    };
+                    if is_dir {
+                        matched.push(full);
+                    }
+                }
+            }
+            dirs = matched;
+        }
+        dirs.retain(|d| src.stat_follow(d).is_ok_and(|s| s.file_type == vfs::FileType::Directory));
+        dirs
+    };
+    dirs.sort();
+    if dirs.is_empty() {
+        warn!("src url glob \"{}\" matched no directories", pattern.display());
+    }
+    Ok(dirs)
+}
+
+/// --streaming-listing: the path checks run on each entry as it is read, so
+/// only the kept ones are held and the filtering overlaps the remote readdir.
+/// Returns the kept entries and how many were read in all.
+fn stream_dir(cli: &Arc<Cli>, src: &mut Vfs, dir_path: &Path, tracker: &Arc<RwLock<Tracker>>) -> Result<(DirList, usize)> {
+    trace!("opening dir for streaming: {}", dir_path.display());
+    let mut dir = src.open_dir(dir_path).with_context(|| format!("open dir on base directory: {}", dir_path.display()))?;
+    let (mut kept, mut listed) = (vec![], 0usize);
+    for entry in dir.iter() {
+        let (p, status) = entry.context("error on next_dir_entry")?;
+        listed += 1;
+        if keep_path(cli, &dir_path.join(&p), tracker) {
+            kept.push((p, status));
+        }
+    }
+    debug!("streamed {} entries of \"{}\" keeping {}", listed, dir_path.display(), kept.len());
+    Ok((kept, listed))
 }
 
-fn inner_lister_thread(cli: &Arc<Cli>, mut src: Vfs, tracker: &Arc<RwLock<Tracker>>, send: &Sender<Option<(PathBuf, FileStatus)>>) -> Result<ListResults> {
+/// the stat threads until done is set, cancelling them if a shutdown or an
+/// abort comes in meanwhile so the lister does not sit out the rest
+fn stats_fast_cancellable(cli: &Arc<Cli>, paths: &mut Vec<PathBuf>) -> Result<Vec<(PathBuf, FileStatus)>> {
+    let (cancel, cancel_recv) = crossbeam_channel::bounded(1);
+    let done = Arc::new(AtomicBool::new(false));
+    let done_c = done.clone();
+    spawn(move || {
+        while !done_c.load(Ordering::Relaxed) {
+            if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) || XFER_ABORTED.load(Ordering::Relaxed) || IDLE_ENDED.load(Ordering::Relaxed) {
+                let _ = cancel.send(());
+                return;
+            }
+            sleep(Duration::from_millis(100));
+        }
+    });
+    let res = fast_stat::get_stats_fast(cli.local_file_stat_thread_pool_size, paths, cancel_recv).context("get fast stats failure");
+    done.store(true, Ordering::Relaxed);
+    res
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn local_stats(cli: &Arc<Cli>, paths: &mut Vec<PathBuf>) -> Result<Vec<(PathBuf, FileStatus)>> {
+    stats_fast_cancellable(cli, paths)
+}
+
+/// io_uring when the kernel allows it, the stat threads otherwise
+#[cfg(feature = "io-uring")]
+fn local_stats(cli: &Arc<Cli>, paths: &mut Vec<PathBuf>) -> Result<Vec<(PathBuf, FileStatus)>> {
+    let mut uring_paths = paths.clone();
+    match fast_stat::get_stats_uring(cli.local_file_stat_batch_size, &mut uring_paths) {
+        Ok(x) => {
+            paths.clear();
+            Ok(x)
+        }
+        Err(e) => {
+            warn!("io_uring stats not possible so using stat threads: {:#}", e);
+            stats_fast_cancellable(cli, paths)
+        }
+    }
+}
+
+fn inner_lister_thread(cli: &Arc<Cli>, src: Vfs, tracker: &Arc<RwLock<Tracker>>, send: &XferQueue) -> Result<ListResults> {
 
     let mut stats = ListResults{
         dir_list_time: Default::default(),
@@ -397,90 +2125,252 @@ fn inner_lister_thread(cli: &Arc<Cli>, mut src: Vfs, tracker: &Arc<RwLock<Tracke
         add_all_to_tracker_time: Default::default(),
         total_time: Default::default(),
         paths_queued: 0,
-        add_all_to_tracker: 0
+        add_all_to_tracker: 0,
+        largest_file_bytes: 0,
+        smallest_file_bytes: 0,
+        median_file_bytes: 0,
+        size_histogram: [0; 10],
     };
 
     let start_f = Instant::now();
     let dir_path = &PathBuf::from(cli.src_url.path());
-    trace!("opening dir: {}", dir_path.display());
-    let mut dir = src.open_dir(&dir_path).with_context(|| format!("open dir on base directory: {}", dir_path.display()))?;
 
-    let list = &dir.read_all_dir_entry().context("error on next_dir_entry")?;
+    let globbed = is_glob(cli.src_url.path());
+    let dir_mtime = if cli.watch_dir_mtime && !globbed {
+        let mtime = src.stat_follow(dir_path).with_context(|| format!("stat of source dir {}", dir_path.display()))?.mtime;
+        if *LAST_SRC_DIR_MTIME.lock().unwrap() == Some(mtime) {
+            STATS.listing_skips.fetch_add(1, Ordering::Relaxed);
+            info!("source dir \"{}\" mtime unchanged at {:?} so skipping listing", dir_path.display(), mtime);
+            stats.total_time = start_f.elapsed();
+            return Ok(stats);
+        }
+        Some(mtime)
+    } else {
+        None
+    };
+    let too_young_before = STATS.too_young.load(Ordering::Relaxed);
+    let cache = if cli.cache_dirs && !globbed {
+        Some(Arc::new(DirCache::new(tracker, dir_path)?))
+    } else {
+        None
+    };
+
+    // streaming filters as it reads so it only works where the path checks come first
+    let streaming = !globbed && cli.streaming_listing && cli.depth == 1 && cli.readdir_rpc_timeout.is_none() && !(cli.sftp_stat_before_filter && src.is_sftp());
+    let (src, list, listed) = if globbed {
+        let mut src = src;
+        let mut all = vec![];
+        for dir in src_glob_dirs(&mut src, dir_path)? {
+            let (s, list) = list_dir(cli, src, &dir, None)?;
+            src = s;
+            debug!("glob dir \"{}\" listed {} entries", dir.display(), list.len());
+            // made absolute so the dir_path joins below leave them be
+            all.extend(list.into_iter().map(|(p, o)| (dir.join(p), o)));
+        }
+        let listed = all.len();
+        (src, all, listed)
+    } else if streaming {
+        let mut src = src;
+        let (list, listed) = stream_dir(cli, &mut src, dir_path, tracker)?;
+        (src, list, listed)
+    } else {
+        let (src, list) = list_dir(cli, src, dir_path, cache.as_ref())?;
+        let listed = list.len();
+        (src, list, listed)
+    };
+    let list = &list;
     stats.dir_list_time = start_f.elapsed();
-    stats.paths_listed = list.len() as u64;
+    stats.paths_listed = listed as u64;
 
     info!("file list {} in {:?}", list.len(), start_f.elapsed());
 
     let mut xfer_list = vec![];
     let mut with_stat_list = vec![];
 
-    let has_stat = list.len() > 0 && list[0].1.is_some();
+    let has_stat = !list.is_empty() && list.iter().all(|(_p, o)| o.is_some());
 
     let start_path_filter = Instant::now();
 
     // this check is faster so done in list
-    let list = if !has_stat {
+    let list = if !has_stat && cli.sftp_stat_before_filter && src.is_sftp() {
         let start_f = Instant::now();
-        let mut path_checked_list = list.iter()
-            .map(|(p, o)| (dir_path.join(&p), o))
+        let all_list = list.iter().map(|(p, _o)| dir_path.join(p)).collect::<Vec<_>>();
+        let x = src.stat_many(&all_list, cli.sftp_stat_pipeline)?;
+        info!("sftp stat before filter of {} in {:?}", x.len(), start_f.elapsed());
+        let start_f = Instant::now();
+        let x = x.into_iter()
             .filter(|(p, _o)| keep_path(cli, p, tracker))
+            .collect::<Vec<_>>();
+        info!("path based checks of {} in {:?}", list.len(), start_f.elapsed());
+        x
+    } else if !has_stat {
+        let start_f = Instant::now();
+        let mut path_checked_list = list.iter()
+            .map(|(p, o)| (dir_path.join(p), o))
+            .filter(|(p, _o)| streaming || keep_path(cli, p, tracker))
             .map(|(p, _o)| p).collect::<Vec<_>>();
         info!("path based checks of {} in {:?}", list.len(), start_f.elapsed());
         let start_f = Instant::now();
-        let x = fast_stat::get_stats_fast(cli.local_file_stat_thread_pool_size, &mut path_checked_list).context("get fast stats failure")?;
-        info!("fast file stat of {} in {:?}", x.len(), start_f.elapsed());
-        x
+        if !src.is_local() {
+            let x = src.stat_many(&path_checked_list, cli.sftp_stat_pipeline)?;
+            info!("sftp stat after filter of {} in {:?}", x.len(), start_f.elapsed());
+            x
+        } else {
+            let x = local_stats(cli, &mut path_checked_list)?;
+            info!("fast file stat of {} in {:?}", x.len(), start_f.elapsed());
+            x
+        }
     } else {
-        list.iter().map(|(p,o)| (dir_path.join(p).clone(), o.unwrap().clone()))
-            .filter(|(p, _o)| keep_path(cli, p, tracker))
+        list.iter().map(|(p,o)| (dir_path.join(p).clone(), o.unwrap()))
+            .filter(|(p, _o)| streaming || keep_path(cli, p, tracker))
             .collect::<Vec<_>>()
     };
 
+    let list = match symlink_mode(cli) {
+        SymlinkMode::Follow => resolve_links(&src, list),
+        SymlinkMode::Copy => list,
+        SymlinkMode::Skip => list.into_iter().filter(|(p, fs)| {
+            let link = fs.file_type == vfs::FileType::Symlink;
+            if link {
+                trace!("skipping symlink \"{}\"", p.display());
+            }
+            !link
+        }).collect(),
+    };
+
+    let mut list = list;
+    if let Some(order) = cli.sort_order {
+        sort_list(&mut list, order);
+    }
+    if let Some(re) = &cli.priority_re {
+        list = priority_first(list, re);
+    }
+
     stats.path_filter_time = start_path_filter.elapsed();
 
     // this check can be slower so option to send as we find
     let start_stat_filter = Instant::now();
+    let (mut queued_files, mut queued_bytes, mut capped) = (0usize, 0u64, 0usize);
+    let mut seen_links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut dry_list = vec![];
+    let mut sizes = vec![];
+    // dirs with files that may still turn up in a later listing
+    let mut unsettled_dirs: HashSet<PathBuf> = HashSet::new();
+    let mut cut_short = false;
     for (path, filestatus) in list.iter() {
-        let k_s = keep_status(&cli, &path, *filestatus, &tracker)?;
+        if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+            info!("shutdown requested so ending listing after {} of {} paths", stats.paths_stat_ed, list.len());
+            cut_short = true;
+            break;
+        }
+        if XFER_ABORTED.load(Ordering::Relaxed) {
+            debug!("xfer aborted so ending listing after {} of {} paths", stats.paths_stat_ed, list.len());
+            cut_short = true;
+            break;
+        }
+        if IDLE_ENDED.load(Ordering::Relaxed) {
+            debug!("--idle-timeout ended the pass so ending listing after {} of {} paths", stats.paths_stat_ed, list.len());
+            cut_short = true;
+            break;
+        }
+        let k_s = keep_status(cli, path, *filestatus, tracker)?;
         stats.paths_stat_ed +=1;
+        if filestatus.file_type == vfs::FileType::Regular {
+            sizes.push(filestatus.size);
+            stats.size_histogram[size_bucket(filestatus.size)] += 1;
+        }
         if k_s & FILE_NOT_A_FILE != 0 || k_s & FILE_TOO_OLD != 0 {
             // these file should never be transferred in the future
             STATS.never2xfer.fetch_add(1, Ordering::Relaxed);
             with_stat_list.push((path.clone(), filestatus));
         } else if k_s & FILE_TOO_YOUNG != 0 {
             STATS.too_young.fetch_add(1, Ordering::Relaxed);
+            unsettled_dirs.extend(path.parent().map(Path::to_path_buf));
             // do nothing but it will show up again and be old enough
             // and should be xferred
+        } else if k_s & (FILE_TOO_SMALL | FILE_TOO_LARGE) != 0 {
+            // not tracked either - the size may change by the next listing
+            unsettled_dirs.extend(path.parent().map(Path::to_path_buf));
+            if k_s & FILE_TOO_SMALL != 0 {
+                STATS.too_small.fetch_add(1, Ordering::Relaxed);
+            } else {
+                STATS.too_large.fetch_add(1, Ordering::Relaxed);
+            }
         } else if k_s & SRC_FILE_NOT_CHANGED != 0 {
             trace!("path stats have not changed: \"{}\"", path.display());
+        } else if recently_xferred(cli, path) {
+            STATS.dedup_skips.fetch_add(1, Ordering::Relaxed);
+            debug!("skipping \"{}\" - already xferred within the dedup window", path.display());
+        } else if let Some(first) = filestatus.hard_link.and_then(|id| seen_links.get(&id)) {
+            // same content under another name - tracked with first so a failed xfer leaves both for the next run
+            STATS.hard_link_skips.fetch_add(1, Ordering::Relaxed);
+            info!("skipping hard link \"{}\" of already queued \"{}\"", path.display(), first.display());
+            if !cli.dry_run {
+                PENDING_LINKS.lock().unwrap().entry(first.clone()).or_default().push((path.clone(), *filestatus));
+            }
+        } else if (cli.max_files > 0 && queued_files >= cli.max_files) || (cli.max_bytes > 0 && queued_bytes >= cli.max_bytes) {
+            // left untracked for the next run
+            capped += 1;
+            unsettled_dirs.extend(path.parent().map(Path::to_path_buf));
         } else {
+            // the xfer may yet fail, so the dir is only cached once a listing finds it all tracked
+            unsettled_dirs.extend(path.parent().map(Path::to_path_buf));
+            queued_files += 1;
+            queued_bytes += filestatus.size;
+            if let Some(id) = filestatus.hard_link {
+                seen_links.insert(id, path.clone());
+            }
             if !cli.dry_run {
                 if !cli.disable_queue_as_found {
                     trace!("queueing file: {}", path.display());
-                    send.send(Some((path.clone(), *filestatus)))?;
-                } else {
-                    xfer_list.push((path.clone(), filestatus.clone()));
+                    send.send((path.clone(), *filestatus))?;
                     stats.paths_queued += 1;
+                } else {
+                    xfer_list.push((path.clone(), *filestatus));
                 }
             } else {
                 trace!("would have xferred file: {}", path.display());
+                dry_list.push(path);
             }
         }
     }
     stats.stat_filter_time = start_stat_filter.elapsed();
+    if !sizes.is_empty() {
+        sizes.sort_unstable();
+        stats.smallest_file_bytes = sizes[0];
+        stats.largest_file_bytes = sizes[sizes.len() - 1];
+        stats.median_file_bytes = sizes[sizes.len() / 2];
+    }
+    if let Some(out) = &cli.dry_run_output {
+        write_dry_run_list(out, &dry_list)?;
+    }
+    if capped > 0 {
+        info!("run capped at {} files / {} bytes by --max-files / --max-bytes - {} files left for the next run", queued_files, queued_bytes, capped);
+    }
 
     let start_queue_time = Instant::now();
+    if cli.disable_queue_as_found && !xfer_list.is_empty() {
+        // everything is known before anything is sent, so it has to fit too
+        check_dst_free(cli, &dst_vfs(cli)?, queued_bytes)?;
+    }
     if cli.disable_queue_as_found {
         trace!("queueing all files for xfer at once");
         let start_f = Instant::now();
         let count = xfer_list.len();
+        // popped from the back so flip it to keep --sort-order
+        if cli.sort_order.is_some() {
+            xfer_list.reverse();
+        }
         loop {
+            if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) || XFER_ABORTED.load(Ordering::Relaxed) || IDLE_ENDED.load(Ordering::Relaxed) {
+                break;
+            }
             match xfer_list.pop() {
                 None => break,
                 Some(x) => {
                     trace!("queueing file: {}", x.0.display());
                     stats.paths_queued += 1;
-                    send.send(Some(x))?
+                    send.send(x)?
                 }
             }
         }
@@ -506,21 +2396,177 @@ fn inner_lister_thread(cli: &Arc<Cli>, mut src: Vfs, tracker: &Arc<RwLock<Tracke
 
     stats.add_all_to_tracker_time = start_add_all_to_filter.elapsed();
 
+    if let Some(cache) = &cache {
+        if !cut_short && !cli.dry_run {
+            cache.record(&unsettled_dirs)?;
+        }
+    }
+
+    if let Some(mtime) = dir_mtime {
+        // only trust the mtime when nothing was left for a later pass and it is
+        // clearly older than this listing - mtimes can be whole seconds
+        let settled = SystemTime::now().duration_since(mtime).map(|d| d > Duration::from_secs(1)).unwrap_or(false);
+        let left_behind = STATS.too_young.load(Ordering::Relaxed) != too_young_before || capped > 0;
+        *LAST_SRC_DIR_MTIME.lock().unwrap() = if settled && !left_behind { Some(mtime) } else { None };
+    }
+
     stats.total_time = start_f.elapsed();
 
     info!("lister thread returning after {:?} secs and listing {} files and local stat'ings of {}", start_f.elapsed(), stats.paths_listed, stats.paths_stat_ed);
     Ok(stats)
 }
 
-fn ticker(interval: Duration) {
+/// writes the --dry-run-output list, through a .tmp file so a reader never
+/// sees half of it
+fn write_dry_run_list(out: &Path, list: &[&PathBuf]) -> Result<()> {
+    let mut text = String::with_capacity(list.len() * 64);
+    for p in list {
+        text.push_str(&p.to_string_lossy());
+        text.push('\n');
+    }
+    if out.as_os_str() == "-" {
+        std::io::stdout().write_all(text.as_bytes())?;
+        return Ok(());
+    }
+    let mut tmp = out.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, text).with_context(|| format!("writing dry run list \"{}\"", Path::new(&tmp).display()))?;
+    std::fs::rename(&tmp, out).with_context(|| format!("renaming dry run list into \"{}\"", out.display()))?;
+    info!("wrote {} would be xferred paths to \"{}\"", list.len(), out.display());
+    Ok(())
+}
+
+/// true when --transfer-dedup-window is set and path was xferred within it
+fn recently_xferred(cli: &Cli, path: &PathBuf) -> bool {
+    match cli.transfer_dedup_window {
+        None => false,
+        Some(window) => match RECENT_XFERS.lock().unwrap().get(path) {
+            None => false,
+            Some(when) => when.elapsed() < window,
+        },
+    }
+}
+
+fn symlink_mode(cli: &Cli) -> SymlinkMode {
+    if cli.sftp_src_readdir_skip_links { SymlinkMode::Skip } else { cli.include_symlinks }
+}
+
+/// listings report symlinks as links - replace them with the status of
+/// their target, dropping dangling ones
+fn resolve_links(src: &Vfs, list: Vec<(PathBuf, FileStatus)>) -> Vec<(PathBuf, FileStatus)> {
+    list.into_iter().filter_map(|(p, fs)| {
+        if fs.file_type != vfs::FileType::Symlink {
+            return Some((p, fs));
+        }
+        STATS.stat_check.fetch_add(1, Ordering::Relaxed);
+        match src.stat_follow(&p) {
+            Err(e) => {
+                warn!("cannot follow symlink \"{}\" so skipping it: {}", p.display(), e);
+                None
+            }
+            Ok(target) => Some((p, target)),
+        }
+    }).collect()
+}
+
+/// --ticker-format json writes each tick to stderr as one json line, text
+/// only logs the main counters at debug
+fn ticker(interval: Duration, json: bool, start: Instant, mut csv: Option<metrics::CsvLog>, csv_interval: Duration) {
+    let mut last_row = Instant::now();
+    let (mut last_tick, mut last_bytes, mut last_files) = (Instant::now(), 0u64, 0usize);
     loop {
         sleep(interval);
-        let xfer = STATS.xfer_count.fetch_add(0, Ordering::Relaxed);
-        let dirs = STATS.dirs_check.fetch_add(0, Ordering::Relaxed);
-        let path_ck = STATS.path_check.fetch_add(0, Ordering::Relaxed);
-        let st_ck = STATS.stat_check.fetch_add(0, Ordering::Relaxed);
-        let nev = STATS.never2xfer.fetch_add(0, Ordering::Relaxed);
-        let yo = STATS.too_young.fetch_add(0, Ordering::Relaxed);
-        debug!("xfer: {}  dir_entry: {}  paths: {}  stats: {}  never2xfer: {}  tooyoung: {}", xfer, dirs, path_ck, st_ck, nev, yo);
-    }
-}
\ No newline at end of file
+        if let Some(log) = &mut csv {
+            if last_row.elapsed() >= csv_interval {
+                last_row = Instant::now();
+                if let Err(e) = log.write_row() {
+                    warn!("metrics csv row failed: {:#}", e);
+                }
+            }
+        }
+        let snap = STATS.snapshot(start);
+        if json {
+            match serde_json::to_string(&snap) {
+                Ok(line) => eprintln!("{}", line),
+                Err(e) => warn!("ticker json failed: {}", e),
+            }
+        } else {
+            debug!("xfer: {}  dir_entry: {}  paths: {}  stats: {}  never2xfer: {}  tooyoung: {}", snap.xfer_count, snap.dirs_check, snap.path_check, snap.stat_check, snap.never2xfer, snap.too_young);
+            // queued is only known once a listing is done, the eta waits for it
+            let secs = last_tick.elapsed().as_secs_f64();
+            let (bytes, files) = (snap.bytes_transferred - last_bytes, snap.xfer_count - last_files);
+            let remaining = STATS.paths_queued.load(Ordering::Relaxed).saturating_sub(snap.xfer_count + snap.xfer_failures);
+            if bytes > 0 || files > 0 {
+                let eta = if files > 0 && remaining > 0 {
+                    format!("{:.0?}", Duration::from_secs_f64(remaining as f64 * secs / files as f64))
+                } else {
+                    "-".to_string()
+                };
+                info!("rate: {:.3}MB/s  files remaining: {}  eta: {}", bytes as f64 / secs / (1024.0 * 1024.0), remaining, eta);
+            }
+        }
+        last_tick = Instant::now();
+        last_bytes = snap.bytes_transferred;
+        last_files = snap.xfer_count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_cmd_names_are_not_run() {
+        // a shell that parsed the name would create the canary file in dir
+        let dir = std::env::temp_dir().join(format!("pullpush-post-cmd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = "a;touch canary;$(touch canary).csv";
+        let dst = dir.join(name);
+        let out = post_cmd("printf %s \"{name}\"", Path::new("/src/x"), &dst, 3).current_dir(&dir).output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout), name);
+        let out = post_cmd("echo {size} \"{src}\"", Path::new("/src/x y"), &dst, 3).current_dir(&dir).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "3 /src/x y\n");
+        assert!(!dir.join("canary").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn idle_timeout_ends_a_pass_with_a_stalled_lister() {
+        IDLE_XFER_THREADS.store(0, Ordering::Relaxed);
+        IDLE_ENDED.store(false, Ordering::Relaxed);
+        XFER_THREAD_COUNT.store(2, Ordering::Relaxed);
+        // the lister holds the sender and never sends - as if stuck listing a hung upstream
+        let (_stalled_lister, recv) = crossbeam_channel::unbounded::<Option<(PathBuf, FileStatus)>>();
+        let start = Instant::now();
+        let threads = (0..2).map(|_| {
+            let recv = recv.clone();
+            spawn(move || next_entry(&recv, Some(Duration::from_millis(50)), &mut false).map(|p| p.is_none()))
+        }).collect::<Vec<_>>();
+        for t in threads {
+            assert!(t.join().unwrap().unwrap());
+        }
+        assert!(IDLE_ENDED.load(Ordering::Relaxed));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn glob_base_stops_at_the_first_glob_component() {
+        let base = |u: &str| glob_base(&Url::parse(u).unwrap()).to_string();
+        assert_eq!(base("sftp://u@h:22/data/in/*/day=*/x.csv"), "sftp://u@h:22/data/in/");
+        assert_eq!(base("file:///data/in/2024-*/x.csv"), "file:///data/in/");
+        assert_eq!(base("file:///[ab]/x"), "file:///");
+        assert_eq!(base("sftp://u@h:22/data/in"), "sftp://u@h:22/data/in");
+    }
+
+    #[test]
+    fn priority_first_keeps_each_bucket_in_order() {
+        let fs = FileStatus { file_type: vfs::FileType::Regular, size: 0, mtime: std::time::UNIX_EPOCH, hard_link: None };
+        let list = ["/a/1.csv", "/a/1.urgent", "/b/2.csv", "/urgent/3.csv", "/b/4.urgent"].iter()
+            .map(|p| (PathBuf::from(p), fs)).collect::<Vec<_>>();
+        let re = pcre2::bytes::Regex::new(r"\.urgent$").unwrap();
+        let got = priority_first(list, &re).into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+        // only the file name is matched, so /urgent/3.csv is not a priority
+        assert_eq!(got, ["/a/1.urgent", "/b/4.urgent", "/a/1.csv", "/b/2.csv", "/urgent/3.csv"].iter().map(PathBuf::from).collect::<Vec<_>>());
+    }
+}
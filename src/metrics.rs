@@ -0,0 +1,120 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{Builder, JoinHandle};
+
+use anyhow::{anyhow, Context};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use url::Url;
+
+use crate::{Result, STATS};
+
+/// Starts the thread serving /metrics.  It is never joined and lives as long
+/// as the process does.
+pub fn start(port: u16, src_url: &Url, dst_url: &Url) -> Result<JoinHandle<()>> {
+    let server = tiny_http::Server::http(("0.0.0.0", port))
+        .map_err(|e| anyhow!("cannot bind metrics port {}: {}", port, e))?;
+    let labels = format!("src_host=\"{}\",dst_host=\"{}\"", label_value(src_url), label_value(dst_url));
+    info!("serving metrics on port {}", port);
+    Builder::new().name("metrics".to_string()).spawn(move || {
+        for req in server.incoming_requests() {
+            let res = if *req.method() == tiny_http::Method::Get && req.url() == "/metrics" {
+                let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..]).unwrap();
+                req.respond(tiny_http::Response::from_string(render(&labels)).with_header(header))
+            } else {
+                req.respond(tiny_http::Response::from_string("not found\n").with_status_code(404))
+            };
+            if let Err(e) = res {
+                warn!("metrics response failed: {}", e);
+            }
+        }
+    }).context("metrics thread start failed")
+}
+
+const CSV_HEADER: &str = "timestamp_iso8601,xfer_count,dirs_check,path_check,stat_check,never2xfer,too_young";
+
+/// --metrics-csv rows appended by the ticker, for places a metrics port
+/// cannot be opened.  Past max_size the file is renamed to <path>.1, replacing
+/// the one before, and a fresh file started.
+pub struct CsvLog {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl CsvLog {
+    pub fn open(path: &Path, max_size: u64) -> Result<CsvLog> {
+        let mut log = CsvLog { path: path.to_path_buf(), max_size, file: CsvLog::open_file(path)?, size: 0 };
+        log.size = log.file.metadata()?.len();
+        if log.size == 0 {
+            log.append(&format!("{}\n", CSV_HEADER))?;
+        }
+        Ok(log)
+    }
+
+    fn open_file(path: &Path) -> Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("opening metrics csv \"{}\"", path.display()))
+    }
+
+    fn append(&mut self, line: &str) -> Result<()> {
+        self.file.write_all(line.as_bytes()).with_context(|| format!("writing metrics csv \"{}\"", self.path.display()))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn write_row(&mut self) -> Result<()> {
+        if self.max_size > 0 && self.size >= self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, &rotated).with_context(|| format!("rotating metrics csv \"{}\"", self.path.display()))?;
+            *self = CsvLog::open(&self.path, self.max_size)?;
+        }
+        let row = format!("{},{},{},{},{},{},{}\n", chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            STATS.xfer_count.load(Ordering::Relaxed),
+            STATS.dirs_check.load(Ordering::Relaxed),
+            STATS.path_check.load(Ordering::Relaxed),
+            STATS.stat_check.load(Ordering::Relaxed),
+            STATS.never2xfer.load(Ordering::Relaxed),
+            STATS.too_young.load(Ordering::Relaxed));
+        self.append(&row)
+    }
+}
+
+fn label_value(url: &Url) -> String {
+    url.host_str().unwrap_or("localhost").replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render(labels: &str) -> String {
+    let counters: [(&str, &AtomicUsize, &str); 18] = [
+        ("xfer_count", &STATS.xfer_count, "files transferred"),
+        ("dirs_check", &STATS.dirs_check, "directory entries read"),
+        ("path_check", &STATS.path_check, "paths checked against the filters"),
+        ("stat_check", &STATS.stat_check, "paths stat'ed"),
+        ("never2xfer", &STATS.never2xfer, "paths that will never be transferred"),
+        ("too_young", &STATS.too_young, "files skipped as younger than --min-age"),
+        ("too_small", &STATS.too_small, "files skipped as smaller than --min-size"),
+        ("too_large", &STATS.too_large, "files skipped as larger than --max-size"),
+        ("probe_count", &STATS.probe_count, "connection probes"),
+        ("probe_fail", &STATS.probe_fail, "failed connection probes"),
+        ("readdir_timeouts", &STATS.readdir_timeouts, "directory reads that timed out"),
+        ("listing_skips", &STATS.listing_skips, "listings skipped as the src dir did not change"),
+        ("dedup_skips", &STATS.dedup_skips, "transfers skipped inside --transfer-dedup-window"),
+        ("hard_link_skips", &STATS.hard_link_skips, "files skipped as hard links of a file already queued"),
+        ("xfer_failures", &STATS.xfer_failures, "transfers that failed after all retries"),
+        ("checksum_mismatches", &STATS.checksum_mismatches, "transfers failing --verify-checksum"),
+        ("raw_bytes", &STATS.raw_bytes, "bytes read from src"),
+        ("wire_bytes", &STATS.wire_bytes, "bytes written to dst after compression"),
+    ];
+    let mut out = String::new();
+    for (name, v, help) in counters.iter() {
+        writeln!(out, "# HELP pullpush_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE pullpush_{} counter", name).unwrap();
+        writeln!(out, "pullpush_{}{{{}}} {}", name, labels, v.load(Ordering::Relaxed)).unwrap();
+    }
+    out
+}
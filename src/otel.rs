@@ -0,0 +1,41 @@
+use anyhow::Context;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+use url::Url;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// Sends the tracing spans to an OTLP collector over http/protobuf.  Without
+/// it no subscriber is set, so the spans in main.rs cost next to nothing.
+///
+/// Dropping it flushes the spans still batched.
+pub struct OtelGuard {
+    provider: SdkTracerProvider,
+}
+
+pub fn init(endpoint: &Url) -> Result<OtelGuard> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint.as_str())
+        .build()
+        .with_context(|| format!("creating otlp exporter for {}", endpoint))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("pullpush").build())
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("pullpush"));
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+        .context("setting tracing subscriber")?;
+    Ok(OtelGuard { provider })
+}
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("flushing otlp spans failed: {}", e);
+        }
+    }
+}
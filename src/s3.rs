@@ -0,0 +1,328 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rusoto_core::credential::DefaultCredentialsProvider;
+use rusoto_core::{ByteStream, HttpClient, Region};
+use rusoto_s3::{CopyObjectRequest, DeleteObjectRequest, GetObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+use tokio::runtime::Runtime;
+use url::Url;
+
+use crate::vfs::{FileStatus, FileType};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// names the local spool files of uploads in flight
+static SPOOL_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Object storage behind an `s3://bucket/prefix?region=...` or
+/// `s3://bucket/prefix?endpoint=http://host:9000` url.
+///
+/// Keys are the url path without the leading '/', and '/' separated key
+/// prefixes are treated as directories.  rusoto is async, so every call is
+/// run to completion on a runtime owned by this vfs.
+pub struct S3Vfs {
+    bucket: String,
+    base_dir: PathBuf,
+    client: S3Client,
+    rt: Arc<Mutex<Runtime>>,
+}
+
+impl S3Vfs {
+    pub fn new(url: &Url) -> Result<S3Vfs> {
+        let bucket = url.host_str().ok_or_else(|| anyhow!("s3 url needs a bucket: {}", url))?.to_string();
+        let region = s3_region(url)?;
+
+        let rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build()
+            .context("Unable to start runtime for s3")?;
+        let client = S3Client::new_with(HttpClient::new().context("Unable to create s3 http client")?,
+                                        DefaultCredentialsProvider::new().context("Unable to find s3 credentials")?,
+                                        region);
+        let vfs = S3Vfs {
+            bucket,
+            base_dir: PathBuf::from(url.path()),
+            client,
+            rt: Arc::new(Mutex::new(rt)),
+        };
+        // same as the sftp stat check of the base path - a bad bucket or key fails here
+        vfs.list(&vfs.base_dir.clone(), Some(1)).with_context(|| format!("Cannot list s3 url \"{}\"", url))?;
+        info!("creating s3 vfs for {}", url);
+        Ok(vfs)
+    }
+
+    pub fn base_dir(&self) -> &PathBuf {
+        &self.base_dir
+    }
+
+    /// lists the objects and common prefixes directly under dir, following
+    /// continuation tokens.  Prefixes come back as directories.
+    pub fn list(&self, dir: &Path, max_keys: Option<i64>) -> Result<Vec<(PathBuf, Option<FileStatus>)>> {
+        let mut prefix = key(dir);
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let mut list = vec![];
+        let mut token = None;
+        loop {
+            let req = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.clone()),
+                delimiter: Some("/".to_string()),
+                continuation_token: token.take(),
+                max_keys,
+                ..Default::default()
+            };
+            let out = self.rt.lock().unwrap().block_on(self.client.list_objects_v2(req))?;
+            let before = list.len();
+            for cp in out.common_prefixes.unwrap_or_default() {
+                if let Some(p) = cp.prefix {
+                    let name = p[prefix.len()..].trim_end_matches('/').to_string();
+                    list.push((PathBuf::from(name), Some(FileStatus { file_type: FileType::Directory, size: 0, mtime: SystemTime::UNIX_EPOCH, hard_link: None })));
+                }
+            }
+            for o in out.contents.unwrap_or_default() {
+                if let Some(k) = o.key {
+                    if k.len() <= prefix.len() {
+                        continue; // the "directory" marker object itself
+                    }
+                    let status = FileStatus {
+                        file_type: FileType::Regular,
+                        size: o.size.unwrap_or(0) as u64,
+                        mtime: parse_time(o.last_modified.as_deref(), false)?,
+                        hard_link: None,
+                    };
+                    list.push((PathBuf::from(&k[prefix.len()..]), Some(status)));
+                }
+            }
+            crate::STATS.dirs_check.fetch_add(list.len() - before, Ordering::Relaxed);
+            match out.next_continuation_token {
+                Some(t) if max_keys.is_none() && out.is_truncated.unwrap_or(false) => token = Some(t),
+                _ => return Ok(list),
+            }
+        }
+    }
+
+    pub fn open(&self, path: &Path, range: Option<String>) -> Result<Box<dyn Read + Send>> {
+        let req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key(path),
+            range,
+            ..Default::default()
+        };
+        let out = self.rt.lock().unwrap().block_on(self.client.get_object(req))?;
+        let body = out.body.ok_or_else(|| anyhow!("s3 object \"{}\" has no body", path.display()))?;
+        Ok(Box::new(S3Reader { rt: self.rt.clone(), body, buf: vec![], pos: 0 }))
+    }
+
+    /// S3 needs the length up front, so writes go to a local spool file that
+    /// is uploaded with a single PutObject on flush - this caps objects at 5GB
+    pub fn create(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        let spool = std::env::temp_dir().join(format!(".pullpush-s3-{}-{}", std::process::id(), SPOOL_SEQ.fetch_add(1, Ordering::Relaxed)));
+        let file = File::create(&spool).with_context(|| format!("Unable to create s3 spool file \"{}\"", spool.display()))?;
+        Ok(Box::new(S3Writer {
+            rt: self.rt.clone(),
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key: key(path),
+            spool,
+            file: Some(file),
+        }))
+    }
+
+    pub fn stat(&self, path: &Path) -> Result<FileStatus> {
+        let req = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key(path),
+            ..Default::default()
+        };
+        let res = self.rt.lock().unwrap().block_on(self.client.head_object(req));
+        match res {
+            Ok(out) => Ok(FileStatus {
+                file_type: FileType::Regular,
+                size: out.content_length.unwrap_or(0) as u64,
+                mtime: parse_time(out.last_modified.as_deref(), true)?,
+                hard_link: None,
+            }),
+            // no object by that name may still be a prefix other objects live under
+            Err(e) => {
+                if self.list(path, Some(1))?.is_empty() {
+                    // NotFound so vfs callers see it as VfsError::NotFound, not a retryable failure
+                    let msg = format!("no s3 object or prefix \"{}\": {}", path.display(), e);
+                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, msg).into());
+                }
+                Ok(FileStatus { file_type: FileType::Directory, size: 0, mtime: SystemTime::UNIX_EPOCH, hard_link: None })
+            }
+        }
+    }
+
+    /// server side copy then delete - S3 has no rename
+    pub fn rename(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut rt = self.rt.lock().unwrap();
+        let copy = CopyObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key(dst),
+            copy_source: format!("{}/{}", self.bucket, url_encode_key(&key(src))),
+            ..Default::default()
+        };
+        rt.block_on(self.client.copy_object(copy)).with_context(|| format!("s3 copy of \"{}\" to \"{}\"", src.display(), dst.display()))?;
+        self.delete_with(&mut rt, src)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        let mut rt = self.rt.lock().unwrap();
+        self.delete_with(&mut rt, path)
+    }
+
+    fn delete_with(&self, rt: &mut Runtime, path: &Path) -> Result<()> {
+        let del = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key(path),
+            ..Default::default()
+        };
+        rt.block_on(self.client.delete_object(del)).with_context(|| format!("s3 delete of \"{}\"", path.display()))?;
+        Ok(())
+    }
+}
+
+/// region from ?region=, or a custom endpoint from ?endpoint= for MinIO and friends
+pub fn s3_region(url: &Url) -> Result<Region> {
+    let mut region = None;
+    let mut endpoint = None;
+    for (k, v) in url.query_pairs() {
+        match &*k {
+            "region" => region = Some(v.to_string()),
+            "endpoint" => endpoint = Some(v.to_string()),
+            _ => return Err(anyhow!("unknown s3 url parameter \"{}\" in {}", k, url)),
+        }
+    }
+    match (region, endpoint) {
+        (name, Some(endpoint)) => Ok(Region::Custom { name: name.unwrap_or_else(|| "us-east-1".to_string()), endpoint }),
+        (Some(name), None) => name.parse::<Region>().map_err(|e| anyhow!("bad s3 region in {}: {}", url, e)),
+        (None, None) => Err(anyhow!("s3 url must set region= or endpoint= in its query: {}", url)),
+    }
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().trim_start_matches('/').to_string()
+}
+
+fn url_encode_key(key: &str) -> String {
+    key.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// listings use ISO 8601 times, HeadObject uses RFC 2822 ones
+fn parse_time(t: Option<&str>, rfc2822: bool) -> Result<SystemTime> {
+    let t = match t {
+        None => return Ok(SystemTime::UNIX_EPOCH),
+        Some(t) => t,
+    };
+    let dt = if rfc2822 {
+        chrono::DateTime::parse_from_rfc2822(t)
+    } else {
+        chrono::DateTime::parse_from_rfc3339(t)
+    }.with_context(|| format!("bad s3 time \"{}\"", t))?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(dt.timestamp().max(0) as u64))
+}
+
+struct S3Reader {
+    rt: Arc<Mutex<Runtime>>,
+    body: ByteStream,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for S3Reader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            let body = &mut self.body;
+            match self.rt.lock().unwrap().block_on(body.next()) {
+                None => return Ok(0),
+                Some(chunk) => {
+                    self.buf = chunk?.to_vec();
+                    self.pos = 0;
+                }
+            }
+        }
+        let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct S3Writer {
+    rt: Arc<Mutex<Runtime>>,
+    client: S3Client,
+    bucket: String,
+    key: String,
+    spool: PathBuf,
+    file: Option<File>,
+}
+
+impl S3Writer {
+    fn upload(&mut self) -> Result<()> {
+        let mut file = match self.file.take() {
+            None => return Ok(()),
+            Some(f) => f,
+        };
+        file.flush()?;
+        drop(file);
+        let len = std::fs::metadata(&self.spool)?.len();
+        let mut rdr = File::open(&self.spool)?;
+        let stream = futures::stream::unfold((), move |_| {
+            let mut buf = vec![0u8; 256 * 1024];
+            let r = match rdr.read(&mut buf) {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some(Ok(bytes::Bytes::from(buf)))
+                }
+                Err(e) => Some(Err(e)),
+            };
+            futures::future::ready(r.map(|r| (r, ())))
+        });
+        let req = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            content_length: Some(len as i64),
+            body: Some(ByteStream::new_with_size(stream, len as usize)),
+            ..Default::default()
+        };
+        let res = self.rt.lock().unwrap().block_on(self.client.put_object(req));
+        let _ = std::fs::remove_file(&self.spool);
+        res.with_context(|| format!("s3 upload of \"{}\"", self.key))?;
+        trace!("uploaded {} bytes to s3 key \"{}\"", len, self.key);
+        Ok(())
+    }
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.file {
+            Some(f) => f.write(buf),
+            None => Err(std::io::Error::other("s3 object already uploaded")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.upload().map_err(|e| std::io::Error::other(format!("{:#}", e)))
+    }
+}
+
+impl Drop for S3Writer {
+    fn drop(&mut self) {
+        if let Err(e) = self.upload() {
+            error!("s3 upload on close failed: {:#}", e);
+        }
+    }
+}
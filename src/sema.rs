@@ -76,12 +76,19 @@ impl Semaphore {
         self.cvar.notify_one();
     }
 
+    /// Sets the count back to the number of resources specified, waking any
+    /// threads that can now acquire one.
+    pub fn reset(&self, count: isize) {
+        *self.lock.lock().unwrap() = count;
+        self.cvar.notify_all();
+    }
+
     /// Acquires a resource of this semaphore, returning an RAII guard to
     /// release the semaphore when dropped.
     ///
     /// This function is semantically equivalent to an `acquire` followed by a
     /// `release` when the guard returned is dropped.
-    pub fn access(&self) -> SemaphoreGuard {
+    pub fn access(&self) -> SemaphoreGuard<'_> {
         self.acquire();
         SemaphoreGuard { sem: self }
     }
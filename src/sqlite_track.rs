@@ -0,0 +1,212 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+#[allow(unused_imports)]
+use log::{info, debug, warn, error, trace};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::track::{mtime_too_old, system_time_to_u64, TrackDelta};
+use crate::vfs::FileStatus;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// how long to wait on another process holding the database lock
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tracker kept in a SQLite database next to the --track path.
+///
+/// Every change is its own transaction, so there is no WAL of our own to
+/// replay and commit has nothing left to do.  The database runs in sqlite's
+/// WAL journal mode with a busy timeout, so several pullpush processes can
+/// share it.  The connection sits behind a Mutex since sqlite connections
+/// cannot be shared across reader threads.
+pub struct SqliteTracker {
+    conn: Mutex<Connection>,
+    file: PathBuf,
+    mtime_too_old: u64,
+}
+
+impl SqliteTracker {
+    fn db_path(file: &Path) -> PathBuf {
+        let mut db_filename = file.file_name().unwrap().to_owned();
+        db_filename.push(".sqlite");
+        file.with_file_name(db_filename)
+    }
+
+    pub fn new(file: &Path, max_track_age: Duration) -> Result<Self> {
+        let db_path = Self::db_path(file);
+
+        let conn = Connection::open(&db_path)
+            .with_context(|| format!("Unable to open sqlite tracker \"{}\"", &db_path.display()))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .with_context(|| format!("Unable to switch \"{}\" to WAL journal mode", &db_path.display()))?;
+        // early databases named the table tracks
+        let old: i64 = conn.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'tracks'", params![], |r| r.get(0))?;
+        if old > 0 {
+            info!("renaming tracks table to transfers in \"{}\"", &db_path.display());
+            conn.execute_batch("ALTER TABLE tracks RENAME TO transfers")?;
+        }
+        conn.execute_batch("CREATE TABLE IF NOT EXISTS transfers (path TEXT PRIMARY KEY, lastmod INTEGER, size INTEGER, checksum TEXT)")
+            .with_context(|| format!("Unable to create transfers table in \"{}\"", &db_path.display()))?;
+
+        let mtime_too_old = mtime_too_old(max_track_age);
+
+        let tracker = SqliteTracker {
+            conn: Mutex::new(conn),
+            file: db_path,
+            mtime_too_old,
+        };
+        tracker.entries_from()?;
+        Ok(tracker)
+    }
+
+    /// there is no WAL of ours to merge, so drop expired rows and VACUUM
+    pub fn compact(file: &Path, max_track_age: Duration) -> Result<()> {
+        let start_f = Instant::now();
+        let tracker = SqliteTracker::new(file, max_track_age)?;
+        tracker.conn.lock().unwrap().execute_batch("VACUUM")
+            .with_context(|| format!("Unable to vacuum \"{}\"", tracker.file.display()))?;
+        info!("compacted \"{}\" to {} entries / {} bytes in {:?}", tracker.file.display(), tracker.num_entries(),
+              std::fs::metadata(&tracker.file)?.len(), start_f.elapsed());
+        Ok(())
+    }
+
+    /// counts the live entries and drops the ones past max_track_age, the
+    /// same as the file tracker does by not loading them
+    fn entries_from(&self) -> Result<usize> {
+        let start_f = Instant::now();
+        let conn = self.conn.lock().unwrap();
+        let count = conn.query_row("SELECT count(*) FROM transfers WHERE lastmod > ? OR lastmod = 0",
+                                   params![self.mtime_too_old as i64], |r| r.get::<_, i64>(0))? as usize;
+        let expired = conn.execute("DELETE FROM transfers WHERE lastmod <= ? AND lastmod != 0", params![self.mtime_too_old as i64])?;
+        if expired > 0 {
+            debug!("dropped {} expired entries from \"{}\"", expired, self.file.display());
+        }
+        info!("read {} entries from \"{}\" in {:?}", count, &self.file.display(), start_f.elapsed());
+        Ok(count)
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.conn.lock().unwrap().query_row("SELECT count(*) FROM transfers", params![], |r| r.get::<_, i64>(0))
+            .unwrap_or(0) as usize
+    }
+
+    pub fn for_each(&self, f: &mut dyn FnMut(&str, u64, u64) -> Result<()>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT path, lastmod, size FROM transfers")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            f(&row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64)?;
+        }
+        Ok(())
+    }
+
+    /// the other tracker's rows go in where they are newer - checksums are not carried over.
+    /// The other database is opened read-only, so it is neither created nor expired.
+    pub fn merge_from(&mut self, other_path: &Path, max_track_age: Duration) -> Result<usize> {
+        let start_f = Instant::now();
+        let other_file = Self::db_path(other_path);
+        let other = Connection::open_with_flags(&other_file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Unable to open sqlite tracker \"{}\" read-only", &other_file.display()))?;
+        other.busy_timeout(BUSY_TIMEOUT)?;
+        // early databases named the table tracks
+        let old: i64 = other.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'tracks'", params![], |r| r.get(0))?;
+        let table = if old > 0 { "tracks" } else { "transfers" };
+        let mut stmt = other.prepare(&format!("SELECT path, lastmod, size FROM {} WHERE lastmod > ? OR lastmod = 0", table))?;
+        let mut rows = stmt.query(params![mtime_too_old(max_track_age) as i64])?;
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            let path = PathBuf::from(row.get::<_, String>(0)?);
+            let (lastmod, size) = (row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64);
+            match self.lookup(&path)? {
+                Some((l, _, _)) if l >= lastmod => (),
+                _ => {
+                    self.upsert(&path, lastmod, size, None)?;
+                    count += 1;
+                }
+            }
+        }
+        info!("merged {} entries from \"{}\" into \"{}\" in {:?}", count, other_file.display(), self.file.display(), start_f.elapsed());
+        Ok(count)
+    }
+
+    /// deletes the rows past max_age - each delete is committed as it happens
+    pub fn expire(&mut self, max_age: Duration) -> Result<usize> {
+        let removed = self.conn.lock().unwrap().execute("DELETE FROM transfers WHERE lastmod <= ? AND lastmod != 0", params![mtime_too_old(max_age) as i64])?;
+        info!("removed {} expired entries from \"{}\"", removed, self.file.display());
+        Ok(removed)
+    }
+
+    /// nothing to do - every write was already committed by sqlite
+    pub fn commit(&mut self) -> Result<()> {
+        info!("sqlite tracker \"{}\" holds {} entries", self.file.display(), self.num_entries());
+        Ok(())
+    }
+
+    pub fn path_exists_in_tracker(&self, path: &Path) -> bool {
+        match self.lookup(path) {
+            Err(e) => {
+                error!("sqlite lookup of \"{}\" failed so assuming not tracked: {}", path.display(), e);
+                false
+            }
+            Ok(found) => found.is_some(),
+        }
+    }
+
+    pub fn check(&self, path: &Path, filestat: FileStatus, checksum: Option<&str>) -> Result<TrackDelta> {
+        match self.lookup(path)? {
+            None => Ok(TrackDelta::None),
+            Some((lastmod, size, tracked)) => {
+                if size != filestat.size {
+                    Ok(TrackDelta::SizeChange)
+                } else if lastmod != system_time_to_u64(filestat.mtime) {
+                    Ok(TrackDelta::LastModChange)
+                } else if checksum.is_some() && tracked.is_some() && checksum != tracked.as_deref() {
+                    Ok(TrackDelta::ChecksumChange)
+                } else {
+                    Ok(TrackDelta::Equal)
+                }
+            }
+        }
+    }
+
+    pub fn insert_path(&mut self, path: &PathBuf) -> Result<()> {
+        self.upsert(path, 0, 0, None)
+            .with_context(|| anyhow!("Not able to record just path to tracker: {:?}", &path))
+    }
+
+    pub fn insert_path_and_status(&mut self, path: &PathBuf, filestat: FileStatus) -> Result<()> {
+        self.upsert(path, system_time_to_u64(filestat.mtime), filestat.size, None)
+            .with_context(|| anyhow!("Not able to record just path to tracker: {:?}", (&path, filestat)))
+    }
+
+    pub fn xferred(&mut self, path: &PathBuf, filestat: FileStatus, checksum: Option<String>) -> Result<()> {
+        self.upsert(path, system_time_to_u64(filestat.mtime), filestat.size, checksum.as_deref())
+            .with_context(|| anyhow!("Not able to add entry to tracker: {:?}", (&path, filestat)))
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM transfers WHERE path = ?", params![path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn lookup(&self, path: &Path) -> Result<Option<(u64, u64, Option<String>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT lastmod, size, checksum FROM transfers WHERE path = ?")?;
+        let found = stmt.query_row(params![path.to_string_lossy()],
+                                   |r| Ok((r.get::<_, i64>(0)? as u64, r.get::<_, i64>(1)? as u64, r.get::<_, Option<String>>(2)?)))
+            .optional()?;
+        Ok(found)
+    }
+
+    fn upsert(&mut self, path: &Path, lastmod: u64, size: u64, checksum: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("INSERT OR REPLACE INTO transfers (path, lastmod, size, checksum) VALUES (?, ?, ?, ?)")?;
+        stmt.execute(params![path.to_string_lossy(), lastmod as i64, size as i64, checksum])?;
+        Ok(())
+    }
+}
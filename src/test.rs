@@ -7,9 +7,16 @@
 use anyhow::{anyhow, Context};
 use log::{debug, error, info, Record, trace, warn};
 mod track;
+mod bloom;
 mod vfs;
-use track::Tracker;
-use std::time::{Duration, Instant};
+mod fast_stat;
+mod sqlite_track;
+mod s3;
+mod ftp;
+mod azure;
+use track::{Tracker, TrackerBackend};
+use vfs::{FileStatus, FileType};
+use std::time::{Duration, Instant, SystemTime};
 use std::path::PathBuf;
 use std::sync::Mutex;
 use std::sync::atomic::AtomicUsize;
@@ -38,11 +45,9 @@ lazy_static! {
     };
 }
 fn main() {
-    match run() {
-        Err(e) => println!("top level error: {:#?}", e),
-        _ => (),
+    if let Err(e) = run() {
+        println!("top level error: {:#?}", e);
     }
-    return ()
 }
 
 fn run() -> Result<()>{
@@ -51,15 +56,36 @@ fn run() -> Result<()>{
         vec![
             TermLogger::new(LevelFilter::Debug, Config::default(), TerminalMode::Mixed)
             ]
-    );
+    )?;
 
     let tot_cpu = cpu_time::ProcessTime::now();
+    let dur = Duration::from_secs(3600 * 24 * 180);
+
+    // compare backends: insert N entries then load them back
+    let count = std::env::args().nth(1).map(|s| s.parse::<usize>()).unwrap_or(Ok(1_000_000))?;
+    for (backend, name) in &[(TrackerBackend::File, "bench_file.track"), (TrackerBackend::Sqlite, "bench_sqlite.track")] {
+        let path = PathBuf::from(name);
+        let start_f = Instant::now();
+        let start_cpu = cpu_time::ProcessTime::now();
+        let mut track = Tracker::new(&path, dur, *backend, 0, 0.001)?;
+        let fs = FileStatus { file_type: FileType::Regular, size: 1234, mtime: SystemTime::now(), hard_link: None };
+        for i in 0..count {
+            track.insert_path_and_status(&PathBuf::from(format!("/some/src/dir/file_{:08}.csv", i)), fs)?;
+        }
+        track.commit()?;
+        println!("{:?} insert {} entries in {:?}  cpu: {:?}", backend, count, start_f.elapsed(), start_cpu.elapsed());
+
+        let start_f = Instant::now();
+        let start_cpu = cpu_time::ProcessTime::now();
+        let track = Tracker::new(&path, dur, *backend, 0, 0.001)?;
+        println!("{:?} read {} entries in {:?}  cpu: {:?}", backend, track.num_entries(), start_f.elapsed(), start_cpu.elapsed());
+    }
+
     for _ in 0..8 {
         let start_f = Instant::now();
         let start_cpu = cpu_time::ProcessTime::now();
         let path = PathBuf::from("testit.track");
-        let dur = Duration::from_secs(3600 * 24 * 180);
-        let mut track = Tracker::new(&path, dur)?;
+        let mut track = Tracker::new(&path, dur, TrackerBackend::File, 0, 0.001)?;
         println!("read {} entries in {:?}  cpu: {:?}", track.num_entries(), start_f.elapsed(), start_cpu.elapsed());
 
     }
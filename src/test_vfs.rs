@@ -4,16 +4,29 @@
 
 use anyhow::{anyhow as ERR, Context};
 mod vfs;
+mod s3;
+mod ftp;
+mod azure;
+mod fast_stat;
 
 use vfs::Vfs;
 use url::Url;
 use std::time::Duration;
 use std::path::PathBuf;
 use ssh2::Sftp;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::sync::atomic::AtomicUsize;
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
+/// the vfs modules count into crate::STATS, which pullpush keeps in main.rs
+pub struct Stats {
+    pub dirs_check: AtomicUsize,
+    pub stat_check: AtomicUsize,
+}
+
+pub static STATS: Stats = Stats { dirs_check: AtomicUsize::new(0), stat_check: AtomicUsize::new(0) };
+
 fn main() -> Result<()> {
 
     let u = Url::parse(" sftp://steve@127.0.0.1:22/home/steve/testsrc/")?;
@@ -21,7 +34,19 @@ fn main() -> Result<()> {
     let pk = PathBuf::from("C:\\Users\\Steve\\.ssh\\id_rsa");
     let to = Duration::from_secs(30);
     eprintln!("create vfs");
-    let mut v = vfs::create_vfs(&u, None, &Some(pk), Some(to))?;
+    let pk = Some(pk);
+    let opts = vfs::ConnectOptions {
+        perm: None,
+        pk: &pk,
+        password: None,
+        timeouts: vfs::Timeouts::new(to, None, None),
+        fingerprint_pin: None,
+        known_hosts: None,
+        jump: None,
+        azure_sas_token: None,
+        tcp_buf: vfs::TcpBuffers::default(),
+    };
+    let mut v = Vfs::new(&u, &opts, None)?;
     eprintln!("clone base dir");
     let p = v.base_dir().clone();
     eprintln!("open dir {}", p.display());
@@ -29,12 +54,11 @@ fn main() -> Result<()> {
 
     eprintln!("listing");
 
-    loop {
-        match od.read_all_dir_entry(Some(|x|true), Some(|y| true)) {
-            Err(e) => return Err(ERR!("could not readdir {}", e)),
-            Ok(Some(path)) => eprintln!("path: {}", path.display()),
-            Ok(None) => break,
-        }
+    match od.read_all_dir_entry() {
+        Err(e) => return Err(ERR!("could not readdir {}", e)),
+        Ok(list) => for (path, _) in list {
+            eprintln!("path: {}", path.display());
+        },
     }
 
 
@@ -46,7 +70,7 @@ fn main() -> Result<()> {
         if len == 0 {
             break;
         }
-        std::io::stdout().lock().write(&buf[..len]);
+        std::io::stdout().lock().write_all(&buf[..len])?;
     }
 
     let s = r"
@@ -2,16 +2,19 @@
 // #![allow(unused_imports)]
 // #![allow(unused_variables)]
 //
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use anyhow::{Context, anyhow};
-use std::io::{BufWriter, BufRead, Write};
+use std::io::{BufReader, BufWriter, BufRead, Read, Write, Seek, SeekFrom};
 use std::fs::{File, remove_file};
 use std::time::{SystemTime, Duration, Instant};
 #[allow(unused_imports)]
 use log::{info, debug, warn, error, trace};
 use std::cmp::Ordering;
 use std::ops::{Add, Sub};
-use crate::vfs::{FileStatus};
+use std::sync::Mutex;
+use crate::vfs::{FileStatus, Vfs, VfsError};
+use crate::sqlite_track::SqliteTracker;
+use crate::bloom::Bloom;
 use std::hash::Hasher;
 
 //use hashbrown::HashSet - only add 5% so not using it
@@ -20,10 +23,11 @@ use std::collections::HashSet;
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
 #[derive(Debug, Eq, Clone)]
-struct Track {
-    src_path: PathBuf,
-    lastmod: u64,
-    size: u64,
+pub struct Track {
+    pub src_path: PathBuf,
+    pub lastmod: u64,
+    pub size: u64,
+    pub checksum: Option<String>,
 }
 
 
@@ -43,7 +47,7 @@ impl PartialEq for Track {
 
 impl PartialOrd for Track {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.src_path.cmp(&other.src_path))
+        Some(self.cmp(other))
     }
 }
 
@@ -53,15 +57,70 @@ impl Ord for Track {
     }
 }
 
-fn u64_to_system_time(mtime: u64) -> SystemTime {
+/// rename, falling back to copy and remove when the two paths are on
+/// different filesystems - e.g. a tracking file that is a symlink to another mount
+fn rename_or_copy(from: &PathBuf, to: &PathBuf) -> std::io::Result<()> {
+    match replace_file(from, to) {
+        Err(e) if e.raw_os_error() == Some(cross_device_error()) => {
+            debug!("rename of \"{}\" crosses filesystems so copying", from.display());
+            std::fs::copy(from, to)?;
+            remove_file(from)
+        }
+        r => r,
+    }
+}
+
+/// rename is already an atomic replace on unix
+#[cfg(not(windows))]
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::rename(from, to)
+}
+
+/// MoveFileExW with MOVEFILE_REPLACE_EXISTING replaces a tracking file that
+/// already exists.  The tmp file's own handle must be closed before this,
+/// which write_entries_as does by dropping it first.
+#[cfg(windows)]
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{MoveFileExW, MOVEFILE_REPLACE_EXISTING};
+    let wide = |p: &Path| p.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let (from_w, to_w) = (wide(from), wide(to));
+    if unsafe { MoveFileExW(from_w.as_ptr(), to_w.as_ptr(), MOVEFILE_REPLACE_EXISTING) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn cross_device_error() -> i32 { 18 }
+#[cfg(not(unix))]
+fn cross_device_error() -> i32 { 17 } // ERROR_NOT_SAME_DEVICE
+
+pub(crate) fn u64_to_system_time(mtime: u64) -> SystemTime {
     SystemTime::UNIX_EPOCH.add(Duration::from_secs(mtime))
 }
 
-fn system_time_to_u64(mtime: SystemTime) -> u64 {
+pub(crate) fn system_time_to_u64(mtime: SystemTime) -> u64 {
     let dur = mtime.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
     dur.as_secs()
 }
 
+/// entries last modified at or before this are past max_track_age and dropped
+pub(crate) fn mtime_too_old(max_track_age: Duration) -> u64 {
+    system_time_to_u64(SystemTime::now().sub(max_track_age))
+}
+
+/// a lastmod of 0 is a quarantined failure, kept until it is retried
+fn expired(lastmod: u64, too_old: u64) -> bool {
+    lastmod != 0 && lastmod <= too_old
+}
+
+/// the size recorded for a directory by --cache-dirs, its lastmod being the
+/// directory's mtime.  No file gets anywhere near it.
+pub const DIR_SIZE: u64 = u64::MAX;
+
+const CSV_HEADERS: [&str; 3] = ["path", "lastmod_epoch", "size_bytes"];
+
 fn to_err<T>(opt: Option<T>, msg: &'static str) -> Result<T> {
     match opt {
         None => Err(anyhow!(msg)),
@@ -82,6 +141,8 @@ impl Track {
             size: to_err(v.next(), "missing 3rd field in track record")?
                 .parse()
                 .with_context(|| format!("file size number cannot be parsed in \"{}\"", s))?,
+            // older track files have no 4th field
+            checksum: v.next().filter(|c| !c.is_empty()).map(|c| c.to_string()),
         })
     }
     /*
@@ -93,33 +154,171 @@ impl Track {
     of the split above
     */
 
-    pub fn from_sftp_entry(path: &PathBuf, filestat: FileStatus) -> Result<Self> {
+    pub fn from_sftp_entry(path: &Path, filestat: FileStatus) -> Result<Self> {
         Ok(Track {
-            src_path: path.clone(),
+            src_path: path.to_path_buf(),
             lastmod: system_time_to_u64(filestat.mtime),
             size: filestat.size,
+            checksum: None,
         })
     }
-    fn from_just_path(path: &PathBuf) -> Self {
+    fn from_just_path(path: &Path) -> Self {
         Track {
-            src_path: path.clone(),
+            src_path: path.to_path_buf(),
             lastmod: 0,
             size: 0,
+            checksum: None,
         }
     }
 
 
+    /// one postcard record of the binary format, paths written lossily as in text
+    pub fn write_bin(&self, f: &mut dyn Write) -> Result<()> {
+        let rec = TrackRec {
+            src_path: self.src_path.to_string_lossy(),
+            lastmod: self.lastmod,
+            size: self.size,
+            checksum: self.checksum.as_deref().map(std::borrow::Cow::Borrowed),
+        };
+        f.write_all(&postcard::to_stdvec(&rec)?)?;
+        Ok(())
+    }
+
     pub fn write(&self, f: &mut dyn Write) -> Result<()> {
-        write!(f, "{}\0{}\0{}\n", self.src_path.display(), self.lastmod, self.size)?;
+        match &self.checksum {
+            None => writeln!(f, "{}\0{}\0{}", self.src_path.display(), self.lastmod, self.size)?,
+            Some(c) => writeln!(f, "{}\0{}\0{}\0{}", self.src_path.display(), self.lastmod, self.size, c)?,
+        }
         Ok(())
     }
 }
 
 
-pub struct Tracker {
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TrackRec<'a> {
+    #[serde(borrow)]
+    src_path: std::borrow::Cow<'a, str>,
+    lastmod: u64,
+    size: u64,
+    #[serde(borrow)]
+    checksum: Option<std::borrow::Cow<'a, str>>,
+}
+
+/// starts every binary tracking file so it can be told from a text one
+const BIN_MAGIC: &[u8] = b"pptrack\x01";
+
+/// how a file tracker lays out its tracking file - the WAL is always text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackFormat {
+    Text,
+    Binary,
+}
+
+impl TrackFormat {
+    /// binary for a tracking file named *.bin
+    pub fn for_path(path: &Path) -> TrackFormat {
+        match path.extension() {
+            Some(ext) if ext == "bin" => TrackFormat::Binary,
+            _ => TrackFormat::Text,
+        }
+    }
+}
+
+/// which store the tracker keeps its entries in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerBackend {
+    File,
+    Sqlite,
+}
+
+pub enum Tracker {
+    File(FileTracker),
+    Sqlite(SqliteTracker),
+}
+
+pub struct FileTracker {
     set: HashSet<Track>,
     file: PathBuf,
     wal: Option<BufWriter<File>>,
+    spill: Option<SpillFile>,
+    /// set by compact so commit leaves out the same expired spill entries
+    too_old: u64,
+    /// every tracked path, spilled ones too, so most misses skip the set and spill
+    bloom: Option<Bloom>,
+}
+
+/// Older entries evicted from memory when --tracker-memory-limit is hit.
+///
+/// Records use the tracker line format sorted by path so lookups can
+/// binary search the file by byte offset.
+struct SpillFile {
+    path: PathBuf,
+    len: u64,
+    rdr: Mutex<BufReader<File>>,
+}
+
+impl SpillFile {
+    fn create(path: PathBuf, mut entries: Vec<Track>) -> Result<Self> {
+        entries.sort();
+        {
+            let file = File::create(&path)
+                .with_context(|| format!("Unable to create tracker spill file \"{}\"", &path.display()))?;
+            let mut buf = BufWriter::new(&file);
+            for e in &entries {
+                e.write(&mut buf)?;
+            }
+        }
+        let file = File::open(&path)?;
+        Ok(SpillFile {
+            len: file.metadata()?.len(),
+            rdr: Mutex::new(BufReader::new(file)),
+            path,
+        })
+    }
+
+    /// binary search over byte offsets - each probe resyncs to the start of
+    /// the next line so lo and the probed starts are always line starts
+    fn find(&self, path: &PathBuf) -> Result<Option<Track>> {
+        let mut rdr = self.rdr.lock().unwrap();
+        let (mut lo, mut hi) = (0u64, self.len);
+        let mut line = String::new();
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let start = if mid == lo {
+                rdr.seek(SeekFrom::Start(lo))?;
+                lo
+            } else {
+                rdr.seek(SeekFrom::Start(mid - 1))?;
+                line.clear();
+                mid - 1 + rdr.read_line(&mut line)? as u64
+            };
+            if start >= hi {
+                hi = mid;
+                continue;
+            }
+            line.clear();
+            let n = rdr.read_line(&mut line)? as u64;
+            let t = Track::from_str(line.trim_end_matches('\n'))
+                .with_context(|| format!("bad record at offset {} in spill file \"{}\"", start, self.path.display()))?;
+            match t.src_path.cmp(path) {
+                Ordering::Equal => return Ok(Some(t)),
+                Ordering::Less => lo = start + n,
+                Ordering::Greater => hi = start,
+            }
+        }
+        Ok(None)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(Track) -> Result<()>) -> Result<()> {
+        let mut rdr = self.rdr.lock().unwrap();
+        rdr.seek(SeekFrom::Start(0))?;
+        let mut line = String::new();
+        while rdr.read_line(&mut line)? > 0 {
+            f(Track::from_str(line.trim_end_matches('\n'))?)?;
+            line.clear();
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -129,16 +328,280 @@ pub enum TrackDelta {
     None,
     SizeChange,
     LastModChange,
+    ChecksumChange,
+    /// a --cache-dirs directory whose mtime is what it was when last listed
+    DirectoryUnchanged,
 }
 
 impl Tracker {
-    pub fn new(file: &PathBuf, max_track_age: Duration) -> Result<Self> {
+    /// bloom_fpr of 0 means no bloom filter - only the file tracker uses one
+    pub fn new(file: &PathBuf, max_track_age: Duration, backend: TrackerBackend, memory_limit: u64, bloom_fpr: f64) -> Result<Self> {
+        match backend {
+            TrackerBackend::File => Ok(Tracker::File(FileTracker::new(file, max_track_age, memory_limit, bloom_fpr)?)),
+            TrackerBackend::Sqlite => Ok(Tracker::Sqlite(SqliteTracker::new(file, max_track_age)?)),
+        }
+    }
+
+    /// folds the WAL into the tracking file and removes it, with no transfer
+    /// involved - for cron'ed maintenance after runs that were killed
+    pub fn merge_wal_then_compact(file: &PathBuf, max_track_age: Duration, backend: TrackerBackend) -> Result<()> {
+        match backend {
+            TrackerBackend::File => FileTracker::merge_wal_then_compact(file, max_track_age),
+            TrackerBackend::Sqlite => SqliteTracker::compact(file, max_track_age),
+        }
+    }
+
+    /// entries whose path starts with prefix, compared by whole components
+    /// the way Path::starts_with does.  Owned since sqlite and spilled
+    /// entries are not held in memory.
+    pub fn query_by_path_prefix(&self, prefix: &Path) -> Result<Vec<Track>> {
+        self.query(&|t| t.src_path.starts_with(prefix))
+    }
+
+    /// every entry, whatever its lastmod
+    pub fn query_all(&self) -> Result<Vec<Track>> {
+        self.query(&|_| true)
+    }
+
+    /// entries last modified from `from` up to and including `to`
+    pub fn query_by_age_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Track>> {
+        let (from, to) = (system_time_to_u64(from), system_time_to_u64(to));
+        self.query(&|t| t.lastmod >= from && t.lastmod <= to)
+    }
+
+    /// entries whose whole src_path matches any of the patterns
+    pub fn matching(&self, patterns: &[pcre2::bytes::Regex]) -> Result<Vec<Track>> {
+        self.query(&|t| {
+            let p = t.src_path.to_string_lossy();
+            patterns.iter().any(|re| re.is_match(p.as_bytes()).unwrap_or(false))
+        })
+    }
+
+    /// drops the matching entries so they are xferred again and returns how
+    /// many went.  The WAL has no way to record a removal, so for the file
+    /// tracker they are only gone once commit rewrites the tracking file.
+    pub fn remove_paths(&mut self, patterns: &[pcre2::bytes::Regex]) -> Result<usize> {
+        let found = self.matching(patterns)?.into_iter().map(|t| t.src_path).collect::<Vec<_>>();
+        self.remove_listed(&found)
+    }
+
+    /// remove_paths for exact paths
+    pub fn remove_listed(&mut self, paths: &[PathBuf]) -> Result<usize> {
+        for p in paths {
+            trace!("forgetting \"{}\"", p.display());
+            match self {
+                Tracker::File(f) => f.remove(p)?,
+                Tracker::Sqlite(s) => s.remove(p)?,
+            }
+        }
+        Ok(paths.len())
+    }
+
+    /// stats every entry on src and returns those src no longer has.  Stats
+    /// failing for other reasons are logged and the entry kept.
+    pub fn verify(&self, src: &Vfs) -> Result<Vec<PathBuf>> {
+        let all = self.query_all()?;
+        let start_f = Instant::now();
+        let mut stale = vec![];
+        for (i, t) in all.iter().enumerate() {
+            if i > 0 && i % 1000 == 0 {
+                info!("verified {} of {} entries, {} stale so far, in {:?}", i, all.len(), stale.len(), start_f.elapsed());
+            }
+            match src.stat(&t.src_path) {
+                Ok(_) => {}
+                Err(VfsError::NotFound(_)) => {
+                    debug!("\"{}\" is gone from the source", t.src_path.display());
+                    stale.push(t.src_path.clone());
+                }
+                Err(e) => warn!("cannot stat \"{}\" so keeping it: {}", t.src_path.display(), e),
+            }
+        }
+        info!("verified {} entries, {} stale, in {:?}", all.len(), stale.len(), start_f.elapsed());
+        Ok(stale)
+    }
+
+    fn query(&self, keep: &dyn Fn(&Track) -> bool) -> Result<Vec<Track>> {
+        let mut found = vec![];
+        match self {
+            Tracker::File(t) => t.for_each(&mut |e| {
+                if keep(e) {
+                    found.push(e.clone());
+                }
+                Ok(())
+            })?,
+            Tracker::Sqlite(t) => t.for_each(&mut |path, lastmod, size| {
+                let e = Track { src_path: PathBuf::from(path), lastmod, size, checksum: None };
+                if keep(&e) {
+                    found.push(e);
+                }
+                Ok(())
+            })?,
+        }
+        Ok(found)
+    }
+
+    /// writes every entry as path,lastmod_epoch,size_bytes and returns the row count
+    pub fn export_csv(&self, writer: &mut dyn Write) -> Result<u64> {
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(CSV_HEADERS)?;
+        let mut count = 0u64;
+        let mut row = |path: &str, lastmod: u64, size: u64| -> Result<()> {
+            csv.write_record([path, &lastmod.to_string(), &size.to_string()])?;
+            count += 1;
+            Ok(())
+        };
+        match self {
+            Tracker::File(t) => t.for_each(&mut |e| row(&e.src_path.to_string_lossy(), e.lastmod, e.size))?,
+            Tracker::Sqlite(t) => t.for_each(&mut |path, lastmod, size| row(path, lastmod, size))?,
+        }
+        csv.flush()?;
+        Ok(count)
+    }
+
+    /// loads rows in the export_csv format, skipping those past max_track_age
+    /// like entries_from does, and returns how many were loaded
+    pub fn import_csv(&mut self, reader: &mut dyn Read, max_track_age: Duration) -> Result<usize> {
+        let too_old = mtime_too_old(max_track_age);
+        let mut csv = csv::Reader::from_reader(reader);
+        if csv.headers()?.iter().ne(CSV_HEADERS.iter().copied()) {
+            return Err(anyhow!("csv headers must be {} but are {:?}", CSV_HEADERS.join(","), csv.headers()?));
+        }
+        let mut count = 0;
+        for (line, rec) in csv.records().enumerate() {
+            let rec = rec.with_context(|| format!("bad csv record at row {}", line + 1))?;
+            let lastmod: u64 = rec[1].parse().with_context(|| format!("last mod time number cannot be parsed at row {}", line + 1))?;
+            let size: u64 = rec[2].parse().with_context(|| format!("file size number cannot be parsed at row {}", line + 1))?;
+            if expired(lastmod, too_old) {
+                trace!("file \"{}\" too old at {:?}", &rec[0], u64_to_system_time(lastmod));
+                continue;
+            }
+            let fs = FileStatus { mtime: u64_to_system_time(lastmod), size, file_type: crate::vfs::FileType::Regular, hard_link: None };
+            self.insert_path_and_status(&PathBuf::from(&rec[0]), fs)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// folds in the entries of another tracker of the same backend, the one
+    /// with the later lastmod winning for paths in both.  Returns how many
+    /// entries were inserted or updated.
+    pub fn merge_from(&mut self, other_path: &PathBuf, max_track_age: Duration) -> Result<usize> {
+        match self {
+            Tracker::File(t) => t.merge_from(other_path, max_track_age),
+            Tracker::Sqlite(t) => t.merge_from(other_path, max_track_age),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn num_entries(&self) -> usize {
+        match self {
+            Tracker::File(t) => t.num_entries(),
+            Tracker::Sqlite(t) => t.num_entries(),
+        }
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        match self {
+            Tracker::File(t) => t.commit(),
+            Tracker::Sqlite(t) => t.commit(),
+        }
+    }
+
+    /// removes entries past max_age and persists what is left, returning the count removed
+    pub fn compact(&mut self, max_age: Duration) -> Result<usize> {
+        match self {
+            Tracker::File(t) => t.compact(max_age),
+            Tracker::Sqlite(t) => t.expire(max_age),
+        }
+    }
+
+    pub fn path_exists_in_tracker(&self, path: &PathBuf) -> bool {
+        match self {
+            Tracker::File(t) => t.path_exists_in_tracker(path),
+            Tracker::Sqlite(t) => t.path_exists_in_tracker(path),
+        }
+    }
+
+    /// checksum is compared only when both it and the tracked one are known
+    /// and size and mtime are equal
+    pub fn check(&self, path: &Path, filestat: FileStatus, checksum: Option<&str>) -> Result<TrackDelta> {
+        match self {
+            Tracker::File(t) => t.check(path, filestat, checksum),
+            Tracker::Sqlite(t) => t.check(path, filestat, checksum),
+        }
+    }
+
+    /// DirectoryUnchanged when dir was recorded by dir_listed with this same
+    /// mtime, otherwise how it differs
+    pub fn check_dir(&self, dir: &Path, mtime: SystemTime) -> Result<TrackDelta> {
+        match self.check(dir, Self::dir_status(mtime), None)? {
+            TrackDelta::Equal => Ok(TrackDelta::DirectoryUnchanged),
+            d => Ok(d),
+        }
+    }
+
+    /// records a directory whose entries were all dealt with as of mtime
+    pub fn dir_listed(&mut self, dir: &PathBuf, mtime: SystemTime) -> Result<()> {
+        self.xferred(dir, Self::dir_status(mtime), None)
+    }
+
+    fn dir_status(mtime: SystemTime) -> FileStatus {
+        FileStatus { mtime, size: DIR_SIZE, file_type: crate::vfs::FileType::Directory, hard_link: None }
+    }
+
+    #[allow(unused)]
+    pub fn insert_path(&mut self, path: &PathBuf) -> Result<()> {
+        match self {
+            Tracker::File(t) => t.insert_path(path),
+            Tracker::Sqlite(t) => t.insert_path(path),
+        }
+    }
+
+    pub fn insert_path_and_status(&mut self, path: &PathBuf, filestat: FileStatus) -> Result<()> {
+        match self {
+            Tracker::File(t) => t.insert_path_and_status(path, filestat),
+            Tracker::Sqlite(t) => t.insert_path_and_status(path, filestat),
+        }
+    }
+
+    pub fn xferred(&mut self, path: &PathBuf, filestat: FileStatus, checksum: Option<String>) -> Result<()> {
+        match self {
+            Tracker::File(t) => t.xferred(path, filestat, checksum),
+            Tracker::Sqlite(t) => t.xferred(path, filestat, checksum),
+        }
+    }
+}
+
+impl FileTracker {
+    pub fn merge_wal_then_compact(file: &PathBuf, max_track_age: Duration) -> Result<()> {
+        let start_f = Instant::now();
         let mut set = HashSet::default();
-        Tracker::entries_from(&file, &mut set, max_track_age)?;
+        FileTracker::entries_from(file, &mut set, max_track_age)?;
+        let wal_path = FileTracker::wal_path(file);
+        if !wal_path.exists() {
+            info!("no wal file \"{}\" so nothing to merge", wal_path.display());
+            return Ok(());
+        }
+        let merged = FileTracker::entries_from(&wal_path, &mut set, max_track_age)?;
+        let (count, _) = FileTracker::write_entries(file, &set, None, 0)?;
+        remove_file(&wal_path)?;
+        info!("merged {} wal entries from \"{}\" leaving {} entries / {} bytes in \"{}\" in {:?}",
+              merged, wal_path.display(), count, std::fs::metadata(file)?.len(), file.display(), start_f.elapsed());
+        Ok(())
+    }
 
+    fn wal_path(file: &Path) -> PathBuf {
         let mut wal_filename = file.file_name().unwrap().to_owned();
         wal_filename.push(".wal");
-        let wal_path = file.with_file_name(wal_filename);
+        file.with_file_name(wal_filename)
+    }
+
+    /// memory_limit of 0 means keep everything in memory
+    pub fn new(file: &PathBuf, max_track_age: Duration, memory_limit: u64, bloom_fpr: f64) -> Result<Self> {
+        let mut set = HashSet::default();
+        FileTracker::entries_from(file, &mut set, max_track_age)?;
+
+        let wal_path = FileTracker::wal_path(file);
 
         // recover wal file
         if wal_path.exists() {
@@ -147,43 +610,166 @@ impl Tracker {
             } else {
                 // note the wal may overwrite track entries which happened above
                 // the wal may have later updates.
-                Tracker::entries_from(&wal_path, &mut set, max_track_age)?;
+                FileTracker::entries_from(&wal_path, &mut set, max_track_age)?;
                 warn!("existing wal file: {}, read - so writing new tracker file to prevent further issues", &wal_path.display());
-                Tracker::write_entries(file, &set)?;
+                FileTracker::write_entries(file, &set, None, 0)?;
                 remove_file(&wal_path)?;
                 info!("removed existing wal file");
             }
         }
 
+        // built before spilling so spilled paths are in it too
+        let bloom = if bloom_fpr > 0.0 { Some(FileTracker::bloom_from(&set, bloom_fpr)) } else { None };
+        let spill = FileTracker::spill_over_limit(file, &mut set, memory_limit)?;
+
         let wal = BufWriter::new(std::fs::File::create(&wal_path)
             .with_context(|| format!("Unable to create WAL log file\"{}\"", &wal_path.display()))?);
 
-        Ok(Tracker {
+        Ok(FileTracker {
             file: file.clone(),
             wal: Some(wal),
             set,
+            spill,
+            too_old: 0,
+            bloom,
         })
     }
 
+    /// sized with room for the set to double during the run
+    fn bloom_from(set: &HashSet<Track>, fpr: f64) -> Bloom {
+        let start_f = Instant::now();
+        let mut bloom = Bloom::new_for_fp_rate((set.len() * 2).max(10_000), fpr);
+        for t in set {
+            bloom.set(t.src_path.as_os_str().to_string_lossy().as_bytes());
+        }
+        debug!("bloom filter of {} bits and {} hashes built from {} entries in {:?}", bloom.num_bits(), bloom.num_hashes(), set.len(), start_f.elapsed());
+        bloom
+    }
+
+    fn bloom_add(&mut self, path: &Path) {
+        if let Some(bloom) = &mut self.bloom {
+            bloom.set(path.as_os_str().to_string_lossy().as_bytes());
+        }
+    }
+
+    /// keeps the most recent entries that fit in memory_limit and moves the
+    /// rest into a sorted spill file next to the tracking file
+    fn spill_over_limit(file: &Path, set: &mut HashSet<Track>, memory_limit: u64) -> Result<Option<SpillFile>> {
+        if memory_limit == 0 || set.is_empty() {
+            return Ok(None);
+        }
+        // rough per entry cost: the struct, the path bytes and the table slot
+        let total: u64 = set.iter()
+            .map(|t| (std::mem::size_of::<Track>() + t.src_path.as_os_str().len() + 16) as u64)
+            .sum();
+        if total <= memory_limit {
+            return Ok(None);
+        }
+        let avg_entry_size = total / set.len() as u64;
+        let keep = (memory_limit / avg_entry_size) as usize;
+
+        let start_f = Instant::now();
+        let mut all = set.drain().collect::<Vec<_>>();
+        all.sort_by_key(|t| std::cmp::Reverse(t.lastmod));
+        let spilled = all.split_off(keep);
+        set.extend(all);
+
+        let mut filename = file.file_name().unwrap().to_owned();
+        filename.push(".spill");
+        let count = spilled.len();
+        let spill = SpillFile::create(file.with_file_name(filename), spilled)?;
+        info!("tracker estimated at {} bytes over limit {} so kept {} entries and spilled {} to \"{}\" in {:?}",
+              total, memory_limit, set.len(), count, spill.path.display(), start_f.elapsed());
+        Ok(Some(spill))
+    }
+
+    /// in memory first, then the spill file
+    fn get(&self, track: &Track) -> Result<Option<Track>> {
+        match self.set.get(track) {
+            Some(t) => Ok(Some(t.clone())),
+            None => match &self.spill {
+                None => Ok(None),
+                Some(spill) => spill.find(&track.src_path),
+            },
+        }
+    }
+
     #[allow(unused)]
     pub fn num_entries(&self) -> usize {
         self.set.len()
     }
 
+    /// the other tracker's WAL is read too, as new() would, but left in place
+    pub fn merge_from(&mut self, other_path: &PathBuf, max_track_age: Duration) -> Result<usize> {
+        let start_f = Instant::now();
+        let mut other = HashSet::default();
+        FileTracker::entries_from(other_path, &mut other, max_track_age)?;
+        let other_wal = FileTracker::wal_path(other_path);
+        if other_wal.exists() && std::fs::metadata(&other_wal)?.len() > 0 {
+            FileTracker::entries_from(&other_wal, &mut other, max_track_age)?;
+        }
+        let mut count = 0;
+        for t in other {
+            match self.get(&t)? {
+                Some(e) if e.lastmod >= t.lastmod => (),
+                _ => {
+                    self.bloom_add(&t.src_path);
+                    self.set.replace(t);
+                    count += 1;
+                }
+            }
+        }
+        info!("merged {} entries from \"{}\" into \"{}\" in {:?}", count, other_path.display(), self.file.display(), start_f.elapsed());
+        Ok(count)
+    }
+
+    /// in memory entries then the spilled ones not since updated in memory
+    fn for_each(&self, f: &mut dyn FnMut(&Track) -> Result<()>) -> Result<()> {
+        for t in &self.set {
+            f(t)?;
+        }
+        if let Some(spill) = &self.spill {
+            spill.for_each(&mut |t| if self.set.contains(&t) { Ok(()) } else { f(&t) })?;
+        }
+        Ok(())
+    }
+
+    /// drops entries that aged past max_age during the run and rewrites the tracking file
+    pub fn compact(&mut self, max_age: Duration) -> Result<usize> {
+        let start_f = Instant::now();
+        let too_old = mtime_too_old(max_age);
+        self.too_old = too_old;
+        let before = self.set.len();
+        self.set.retain(|t| !expired(t.lastmod, too_old));
+        let (count, spill_expired) = FileTracker::write_entries(&self.file, &self.set, self.spill.as_ref(), too_old)?;
+        let removed = before - self.set.len() + spill_expired;
+        info!("compacted track file {} to {} entries removing {} in {:?}", self.file.display(), count, removed, start_f.elapsed());
+        Ok(removed)
+    }
+
     pub fn commit(&mut self) -> Result<()> {
         let start_f = Instant::now();
-        Tracker::write_entries(&self.file, &self.set)?;
+        let (count, _) = FileTracker::write_entries(&self.file, &self.set, self.spill.as_ref(), self.too_old)?;
+        if let Some(spill) = self.spill.take() {
+            remove_file(&spill.path)?;
+        }
 
-        let mut filename = self.file.file_name().unwrap().to_owned();
-        filename.push(".wal");
-        let logpath = self.file.with_file_name(filename);
+        let logpath = FileTracker::wal_path(&self.file);
         self.wal = None; // should close the file....
         remove_file(&logpath)?;
-        info!("commited {} entries to track file {} in {:?}", self.set.len(), self.file.display(), start_f.elapsed());
+        info!("commited {} entries to track file {} in {:?}", count, self.file.display(), start_f.elapsed());
         Ok(())
     }
 
-    fn write_entries(path: &PathBuf, set: &HashSet<Track>) -> Result<()> {
+    /// spilled entries last modified at or before too_old are left out - returns
+    /// the count written and the count left out
+    fn write_entries(path: &PathBuf, set: &HashSet<Track>, spill: Option<&SpillFile>, too_old: u64) -> Result<(usize, usize)> {
+        FileTracker::write_entries_as(path, set, spill, too_old, TrackFormat::for_path(path))
+    }
+
+    fn write_entries_as(path: &PathBuf, set: &HashSet<Track>, spill: Option<&SpillFile>, too_old: u64, format: TrackFormat) -> Result<(usize, usize)> {
+        let mut count = set.len();
+        let mut left_out = 0;
         let mut tmppath = path.clone();
         let mut filename = String::from(".tmp_");
         filename.push_str(path.file_name().unwrap().to_str().unwrap());
@@ -193,83 +779,155 @@ impl Tracker {
             let file = File::create(&tmppath)
                 .with_context(|| format!("Unable to create tmpfile: \"{}\" to write tracking data too", &tmppath.display()))?;
             let mut buf = BufWriter::new(&file);
+            let write = |t: &Track, buf: &mut BufWriter<&File>| match format {
+                TrackFormat::Text => t.write(buf),
+                TrackFormat::Binary => t.write_bin(buf),
+            };
+            if format == TrackFormat::Binary {
+                buf.write_all(BIN_MAGIC)?;
+            }
             for e in set {
-                e.write(&mut buf)?;
+                write(e, &mut buf)?;
+            }
+            // spilled entries updated during the run are already written from set
+            if let Some(spill) = spill {
+                spill.for_each(&mut |t| if set.contains(&t) {
+                    Ok(())
+                } else if expired(t.lastmod, too_old) {
+                    left_out += 1;
+                    Ok(())
+                } else {
+                    count += 1;
+                    write(&t, &mut buf)
+                })?;
             }
         }
-        std::fs::rename(&tmppath, &path)
+        rename_or_copy(&tmppath, path)
             .with_context(|| format!("Unable to post rename tmp file after writing tracking information: rename \"{}\" to \"{}\"", &tmppath.display(), &path.display()))?;
-        Ok(())
+        Ok((count, left_out))
+    }
+
+    fn entries_from(path: &PathBuf, set: &mut HashSet<Track>, max_track_age: Duration) -> Result<usize> {
+        FileTracker::entries_from_cutoff(path, set, mtime_too_old(max_track_age))
     }
 
-    fn entries_from(path: &PathBuf, set: &mut HashSet<Track>, max_track_age: Duration) -> Result<()> {
+    /// reads either format whatever the file is named, so renaming a text
+    /// tracking file to *.bin converts it on the next commit
+    fn entries_from_cutoff(path: &PathBuf, set: &mut HashSet<Track>, mtime_too_old: u64) -> Result<usize> {
         trace!("reading state file: {}", path.display());
         let now = SystemTime::now();
 
-        let f_h = match File::open(&path) {
+        let mut f_h = match File::open(path) {
             Err(e) => {
                 warn!("There is no initial tracking file at \"{}\", so going with an initial empty one. {}", path.display(), e);
-                return Ok(());
+                return Ok(0);
             }
             Ok(f) => f,
         };
         let fs = std::fs::metadata(path)?.len();
 
-        let mtime_too_old = {
-            // compute mtime cutoff point so we do not repeat that computation
-            // in inner loop
-            let now = SystemTime::now();
-            let then = now.sub(max_track_age);
-            system_time_to_u64(then)
+        let mut count = 0;
+        let mut add = |t: Track| {
+            if !expired(t.lastmod, mtime_too_old) {
+                let lastmod = t.lastmod;
+                if set.contains(&t) {
+                    trace!("replacing entry file \"{}\" tracking age: {:?}", &t.src_path.display(), u64_to_system_time(lastmod));
+                    set.replace(t);
+                } else {
+                    trace!("entry file \"{}\" tracking age: {:?}", t.src_path.display(), u64_to_system_time(lastmod));
+                    set.insert(t);
+                }
+                count += 1;
+            } else {
+                trace!("file \"{}\" too old at {:?}", t.src_path.display(), u64_to_system_time(t.lastmod));
+            }
         };
 
-        let lines = std::io::BufReader::new(f_h).lines();
-        let mut count = 0;
-        for l in lines {
-            let l = l.with_context(|| format!("unable parse data file:{}:{}", &path.display(), count))?;
-            match Track::from_str(&l) {
-                Err(e) => error!("skipping a line due to {}", e),
-                Ok(t) => {
-                    if t.lastmod > mtime_too_old {
-                        let lastmod = t.lastmod;
-                        if set.contains(&t) {
-                            trace!("replacing entry file \"{}\" tracking age: {:?}", &t.src_path.display(), u64_to_system_time(lastmod));
-                            set.replace(t);
-                        } else {
-                            trace!("entry file \"{}\" tracking age: {:?}", t.src_path.display(), u64_to_system_time(lastmod));
-                            set.insert(t);
-                        }
-                        count += 1;
-                    } else {
-                        trace!("file \"{}\" too old at {:?}", t.src_path.display(), u64_to_system_time(t.lastmod));
-                    }
-                    ()
+        let mut magic = vec![0u8; BIN_MAGIC.len()];
+        let is_bin = f_h.read_exact(&mut magic).is_ok() && magic == BIN_MAGIC;
+        if is_bin {
+            // one read and no per line allocation - the reason for the format
+            let mut data = Vec::with_capacity(fs as usize);
+            f_h.read_to_end(&mut data).with_context(|| format!("unable to read binary tracking file: {}", path.display()))?;
+            let mut rest = &data[..];
+            while !rest.is_empty() {
+                let (rec, r) = postcard::take_from_bytes::<TrackRec>(rest)
+                    .with_context(|| format!("corrupt record in binary tracking file {} at byte {}", path.display(), BIN_MAGIC.len() + data.len() - rest.len()))?;
+                rest = r;
+                add(Track {
+                    src_path: PathBuf::from(rec.src_path.as_ref()),
+                    lastmod: rec.lastmod,
+                    size: rec.size,
+                    checksum: rec.checksum.map(|c| c.into_owned()),
+                });
+            }
+        } else {
+            f_h.seek(SeekFrom::Start(0))?;
+            let lines = std::io::BufReader::new(f_h).lines();
+            for (no, l) in lines.enumerate() {
+                let l = l.with_context(|| format!("unable parse data file:{}:{}", &path.display(), no))?;
+                match Track::from_str(&l) {
+                    Err(e) => error!("skipping a line due to {}", e),
+                    Ok(t) => add(t),
                 }
             }
         }
-        if fs > 0 && count == 0 {
+        if fs > 0 && count == 0 && !is_bin {
             return Err(anyhow!("Fishy tracker file: {}. It has size but no records could be parsed from it.", path.display()));
         }
         info!("read {} entries from \"{}\" in {:?}", count, &path.display(), now.elapsed().unwrap_or(Duration::from_secs(0)));
-        Ok(())
+        Ok(count)
+    }
+
+    /// rewrites a tracking file in the given format keeping every entry - the
+    /// WAL next to input, if any, is not read
+    pub fn convert(input: &PathBuf, output: &PathBuf, format: TrackFormat) -> Result<usize> {
+        let start_f = Instant::now();
+        let mut set = HashSet::default();
+        FileTracker::entries_from_cutoff(input, &mut set, 0)?;
+        if TrackFormat::for_path(output) != format {
+            warn!("\"{}\" is written as {:?} but will be rewritten as {:?} on commit, which goes by the .bin suffix", output.display(), format, TrackFormat::for_path(output));
+        }
+        let (count, _) = FileTracker::write_entries_as(output, &set, None, 0, format)?;
+        info!("converted {} entries from \"{}\" to {:?} \"{}\" in {:?}", count, input.display(), format, output.display(), start_f.elapsed());
+        Ok(count)
     }
 
 
     pub fn path_exists_in_tracker(&self, path: &PathBuf) -> bool {
-        let track = Track::from_just_path(&path);
-        return self.set.contains(&track);
+        if let Some(bloom) = &self.bloom {
+            if !bloom.check(path.as_os_str().to_string_lossy().as_bytes()) {
+                return false;
+            }
+        }
+        let track = Track::from_just_path(path);
+        if self.set.contains(&track) {
+            return true;
+        }
+        match &self.spill {
+            None => false,
+            Some(spill) => match spill.find(path) {
+                Err(e) => {
+                    error!("spill file lookup of \"{}\" failed so assuming not tracked: {}", path.display(), e);
+                    false
+                }
+                Ok(found) => found.is_some(),
+            },
+        }
     }
 
     #[allow(unused)]
-    pub fn check(&self, path: &PathBuf, filestat: FileStatus) -> Result<TrackDelta> {
-        let track = Track::from_sftp_entry(&path, filestat)?;
-        match self.set.get(&track) {
+    pub fn check(&self, path: &Path, filestat: FileStatus, checksum: Option<&str>) -> Result<TrackDelta> {
+        let track = Track::from_sftp_entry(path, filestat)?;
+        match self.get(&track)? {
             None => Ok(TrackDelta::None),
             Some(e) => {
                 if e.size != track.size {
                     Ok(TrackDelta::SizeChange)
                 } else if e.lastmod != track.lastmod {
                     Ok(TrackDelta::LastModChange)
+                } else if checksum.is_some() && e.checksum.is_some() && checksum != e.checksum.as_deref() {
+                    Ok(TrackDelta::ChecksumChange)
                 } else {
                     Ok(TrackDelta::Equal)
                 }
@@ -286,23 +944,38 @@ impl Tracker {
             mtime: SystemTime::UNIX_EPOCH,
             size: 0,
             file_type: crate::vfs::FileType::Unknown,
+            hard_link: None,
         };
-        let track = Track::from_sftp_entry(&path, fs)
+        let track = Track::from_sftp_entry(path, fs)
             .with_context(|| anyhow!("Not able to record just path to tracker: {:?}", (&path, fs)))?;
+        self.bloom_add(path);
         self.set.insert(track);
         Ok(())
     }
 
     pub fn insert_path_and_status(&mut self, path: &PathBuf, filestat: FileStatus) -> Result<()> {
-        let track = Track::from_sftp_entry(&path, filestat)
+        let track = Track::from_sftp_entry(path, filestat)
             .with_context(|| anyhow!("Not able to record just path to tracker: {:?}", (&path, filestat)))?;
+        self.bloom_add(path);
         self.set.insert(track);
         Ok(())
     }
 
-    pub fn xferred(&mut self, path: &PathBuf, filestat: FileStatus) -> Result<()> {
-        let track = Track::from_sftp_entry(&path, filestat)
+    /// spilled entries would come back from the spill file on commit, so this
+    /// needs the whole tracker in memory
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        if self.spill.is_some() {
+            return Err(anyhow!("cannot remove entries from \"{}\" while part of it is spilled - run with no memory limit", self.file.display()));
+        }
+        self.set.remove(&Track::from_just_path(path));
+        Ok(())
+    }
+
+    pub fn xferred(&mut self, path: &PathBuf, filestat: FileStatus, checksum: Option<String>) -> Result<()> {
+        let mut track = Track::from_sftp_entry(path, filestat)
             .with_context(|| anyhow!("Not able to add entry to tracker: {:?}", (&path, filestat)))?;
+        track.checksum = checksum;
+        self.bloom_add(path);
         track.write(self.wal.as_mut().unwrap())?;
         self.set.replace(track);
         self.wal.as_mut().unwrap().flush()?;
@@ -310,3 +983,126 @@ impl Tracker {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh dir per test so parallel tests do not share tracking files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pullpush-track-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn now() -> u64 {
+        system_time_to_u64(SystemTime::now())
+    }
+
+    /// Track's Eq only looks at the path so compare every field
+    fn fields(tracks: impl IntoIterator<Item=Track>) -> Vec<(PathBuf, u64, u64, Option<String>)> {
+        let mut v = tracks.into_iter().map(|t| (t.src_path, t.lastmod, t.size, t.checksum)).collect::<Vec<_>>();
+        v.sort();
+        v
+    }
+
+    fn status(lastmod: u64, size: u64) -> FileStatus {
+        FileStatus { file_type: crate::vfs::FileType::Regular, size, mtime: u64_to_system_time(lastmod), hard_link: None }
+    }
+
+    #[test]
+    fn text_and_bin_round_trip() {
+        let dir = test_dir("round-trip");
+        let mut set = HashSet::default();
+        set.insert(Track { src_path: PathBuf::from("/src/a.csv"), lastmod: now(), size: 10, checksum: None });
+        set.insert(Track { src_path: PathBuf::from("/src/sub dir/b.csv"), lastmod: 0, size: 0, checksum: None });
+        set.insert(Track { src_path: PathBuf::from("/src/ü.csv"), lastmod: now() - 60, size: u64::MAX, checksum: Some("abc123".to_string()) });
+        for (format, name) in &[(TrackFormat::Text, "t.track"), (TrackFormat::Binary, "t.bin")] {
+            let path = dir.join(name);
+            let (count, _) = FileTracker::write_entries_as(&path, &set, None, 0, *format).unwrap();
+            assert_eq!(count, set.len());
+            let mut read = HashSet::default();
+            assert_eq!(FileTracker::entries_from_cutoff(&path, &mut read, 0).unwrap(), set.len());
+            assert_eq!(fields(read), fields(set.clone()), "{:?}", format);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spill_file_finds_every_entry() {
+        let dir = test_dir("spill");
+        // uneven path lengths so the byte offset probes land mid line
+        let entries = (0..500).map(|i| Track {
+            src_path: PathBuf::from(format!("/src/{}/f{:04}", "d".repeat(i % 7), i * 2)),
+            lastmod: 1000 + i as u64,
+            size: i as u64,
+            checksum: None,
+        }).collect::<Vec<_>>();
+        let spill = SpillFile::create(dir.join("t.spill"), entries.clone()).unwrap();
+        for e in &entries {
+            let found = spill.find(&e.src_path).unwrap().unwrap();
+            assert_eq!((found.lastmod, found.size), (e.lastmod, e.size));
+        }
+        for missing in &["/", "/src/a", "/src/f0001", "/src/ddd/f0003", "/zzz"] {
+            assert!(spill.find(&PathBuf::from(missing)).unwrap().is_none(), "{}", missing);
+        }
+        drop(spill);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn csv_export_import_round_trip() {
+        let dir = test_dir("csv");
+        let age = Duration::from_secs(3600 * 24 * 30);
+        let mut from = Tracker::new(&dir.join("from.track"), age, TrackerBackend::File, 0, 0.0).unwrap();
+        for (i, name) in ["/src/a.csv", "/src/with,comma.csv", "/src/with \"quote\".csv"].iter().enumerate() {
+            from.insert_path_and_status(&PathBuf::from(name), status(now() - i as u64, i as u64 * 100)).unwrap();
+        }
+        let mut csv = vec![];
+        assert_eq!(from.export_csv(&mut csv).unwrap(), 3);
+
+        let mut to = Tracker::new(&dir.join("to.track"), age, TrackerBackend::File, 0, 0.0).unwrap();
+        assert_eq!(to.import_csv(&mut &csv[..], age).unwrap(), 3);
+        assert_eq!(fields(to.query_all().unwrap()), fields(from.query_all().unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_from_keeps_the_newer_lastmod() {
+        for backend in &[TrackerBackend::File, TrackerBackend::Sqlite] {
+            let dir = test_dir(&format!("merge-{:?}", backend));
+            let age = Duration::from_secs(3600 * 24 * 30);
+            let t = now();
+            let other_path = dir.join("other.track");
+            let mut other = Tracker::new(&other_path, age, *backend, 0, 0.0).unwrap();
+            other.insert_path_and_status(&PathBuf::from("/src/newer_there"), status(t, 2)).unwrap();
+            other.insert_path_and_status(&PathBuf::from("/src/newer_here"), status(t - 100, 2)).unwrap();
+            other.insert_path_and_status(&PathBuf::from("/src/only_there"), status(t, 2)).unwrap();
+            other.commit().unwrap();
+            drop(other);
+
+            let mut tracker = Tracker::new(&dir.join("this.track"), age, *backend, 0, 0.0).unwrap();
+            tracker.insert_path_and_status(&PathBuf::from("/src/newer_there"), status(t - 100, 1)).unwrap();
+            tracker.insert_path_and_status(&PathBuf::from("/src/newer_here"), status(t, 1)).unwrap();
+            assert_eq!(tracker.merge_from(&other_path, age).unwrap(), 2, "{:?}", backend);
+            let got = fields(tracker.query_all().unwrap()).into_iter().map(|(p, l, s, _)| (p, l, s)).collect::<Vec<_>>();
+            assert_eq!(got, vec![
+                (PathBuf::from("/src/newer_here"), t, 1),
+                (PathBuf::from("/src/newer_there"), t, 2),
+                (PathBuf::from("/src/only_there"), t, 2),
+            ], "{:?}", backend);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn sqlite_merge_from_leaves_a_missing_tracker_missing() {
+        let dir = test_dir("merge-missing");
+        let age = Duration::from_secs(3600);
+        let mut tracker = Tracker::new(&dir.join("this.track"), age, TrackerBackend::Sqlite, 0, 0.0).unwrap();
+        assert!(tracker.merge_from(&dir.join("typo.track"), age).is_err());
+        assert!(!dir.join("typo.track.sqlite").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
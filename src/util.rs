@@ -1,7 +1,11 @@
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
 
 use chrono::Utc;
-use env_logger; //::{Builder, Env, fmt::{Color, Formatter}};
 use log::LevelFilter;
 
 #[allow(unused)]
@@ -9,21 +13,180 @@ pub fn print_type_of<T>(_: &T) {
     println!("{}", std::any::type_name::<T>())
 }
 
-pub fn init_log(level: LevelFilter) {
+/// Sets up logging to stderr, or to syslog when an ident is given.  If syslog
+/// cannot be reached we fall back to stderr rather than run blind.
+pub fn init_log(level: LevelFilter, syslog_ident: Option<&str>) {
+    init_log_bridged(level, syslog_ident, false, None)
+}
+
+/// init_log that also hands each record to tracing as an event, so log lines
+/// show up in the --otlp-endpoint spans they were written in.  A log_file
+/// gets a copy of every line on top of stderr or syslog.
+pub fn init_log_bridged(level: LevelFilter, syslog_ident: Option<&str>, bridge_tracing: bool, log_file: Option<RotatingFile>) {
+    let logger = match syslog_ident.map(syslog_logger) {
+        Some(Ok(l)) => l,
+        Some(Err(e)) => {
+            eprintln!("unable to log to syslog so using stderr: {}", e);
+            stderr_logger(level)
+        }
+        None => stderr_logger(level),
+    };
+    let logger = match log_file {
+        None => logger,
+        Some(f) => Box::new(FileTeeLogger(logger, Mutex::new(f))),
+    };
+    set_logger(logger, level, bridge_tracing).expect("setting logger");
+}
+
+fn write_record(buf: &mut dyn Write, record: &log::Record) -> std::io::Result<()> {
+    writeln!(buf, "{} [{:4}] [{}:{}] {:>5}: {} ", Utc::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+             std::thread::current().name().unwrap_or("unknown"),
+             record.file().unwrap(),
+             record.line().unwrap(),
+             record.level(),
+             record.args())
+}
+
+fn stderr_logger(level: LevelFilter) -> Box<dyn log::Log> {
     let mut builder = env_logger::Builder::new();
 
-    builder.format(|buf, record| {
-        writeln!(buf, "{} [{:4}] [{}:{}] {:>5}: {} ", Utc::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                 std::thread::current().name().or(Some("unknown")).unwrap(),
-                 record.file().unwrap(),
-                 record.line().unwrap(),
-                 record.level(),
-                 record.args())
-    });
+    builder.format(|buf, record| write_record(buf, record));
     builder.filter_level(level);
-    builder.init();
+    Box::new(builder.build())
+}
 
+/// --log-file: appended to through a BufWriter that is flushed after each
+/// line, and once past max_size renamed to <path>.1 with the older ones
+/// shifted up to <path>.<keep>.  A max_size of 0 never rotates.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    out: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> anyhow::Result<RotatingFile> {
+        let (out, size) = Self::open_file(path)?;
+        Ok(RotatingFile { path: path.to_path_buf(), max_size, keep, out, size })
+    }
 
+    fn open_file(path: &Path) -> anyhow::Result<(BufWriter<File>, u64)> {
+        let f = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("opening log file \"{}\"", path.display()))?;
+        let size = f.metadata()?.len();
+        Ok((BufWriter::new(f), size))
+    }
+
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(format!(".{}", n));
+        PathBuf::from(p)
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.numbered(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.numbered(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.numbered(1))?;
+        }
+        let (out, size) = Self::open_file(&self.path)?;
+        self.out = out;
+        self.size = size;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.out.write_all(line)?;
+        self.out.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// the stderr or syslog logger plus --log-file.  The file's lock is held
+/// across a rotation so no line lands in between.
+struct FileTeeLogger(Box<dyn log::Log>, Mutex<RotatingFile>);
+
+impl log::Log for FileTeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = vec![];
+        if write_record(&mut line, record).is_ok() {
+            let mut f = self.1.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = f.write_line(&line) {
+                eprintln!("cannot write to log file \"{}\": {:#}", f.path.display(), e);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+        let _ = self.1.lock().unwrap_or_else(|e| e.into_inner()).out.flush();
+    }
+}
+
+fn set_logger(logger: Box<dyn log::Log>, level: LevelFilter, bridge_tracing: bool) -> anyhow::Result<(), anyhow::Error> {
+    if bridge_tracing {
+        log::set_boxed_logger(Box::new(TeeLogger(logger, tracing_log::LogTracer::new())))?;
+    } else {
+        log::set_boxed_logger(logger)?;
+    }
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// only one log::Log can be installed, so this feeds the tracing bridge too
+struct TeeLogger(Box<dyn log::Log>, tracing_log::LogTracer);
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.log(record);
+        self.1.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// syslog's BasicLogger maps error/warn/info to LOG_ERR/LOG_WARNING/LOG_INFO
+/// and both debug and trace to LOG_DEBUG
+fn syslog_logger(ident: &str) -> anyhow::Result<Box<dyn log::Log>, anyhow::Error> {
+    let formatter = syslog::Formatter3164 {
+        facility: syslog::Facility::LOG_DAEMON,
+        hostname: None,
+        process: ident.to_string(),
+        pid: std::process::id(),
+    };
+    #[cfg(unix)]
+    let logger = syslog::unix(formatter).map_err(|e| anyhow::anyhow!("cannot connect to syslog socket: {}", e))?;
+    #[cfg(not(unix))]
+    let logger = syslog::udp(formatter, "127.0.0.1:0", "127.0.0.1:514").map_err(|e| anyhow::anyhow!("cannot connect to syslog udp: {}", e))?;
+
+    Ok(Box::new(syslog::BasicLogger::new(logger)))
 }
 
 pub fn to_log_level(s: &str) -> anyhow::Result<LevelFilter, anyhow::Error> {
@@ -38,3 +201,7 @@ pub fn to_log_level(s: &str) -> anyhow::Result<LevelFilter, anyhow::Error> {
     }
 }
 
+/// serde serialize_with for Durations as floating point seconds
+pub fn duration_secs<S: serde::Serializer>(d: &std::time::Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
@@ -0,0 +1,94 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// digest used for --checksum-algorithm and --verify-checksum
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+    Xxhash,
+}
+
+/// a running digest of one of the algorithms
+#[derive(Clone)]
+pub enum Checksum {
+    Md5(md5::Context),
+    Sha256(Sha256),
+    Xxhash(Box<Xxh3>),
+}
+
+impl Checksum {
+    pub fn new(alg: ChecksumAlgorithm) -> Self {
+        match alg {
+            ChecksumAlgorithm::Md5 => Checksum::Md5(md5::Context::new()),
+            ChecksumAlgorithm::Sha256 => Checksum::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Xxhash => Checksum::Xxhash(Box::new(Xxh3::new())),
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            Checksum::Md5(c) => c.consume(buf),
+            Checksum::Sha256(c) => c.update(buf),
+            Checksum::Xxhash(c) => c.update(buf),
+        }
+    }
+
+    /// the digest in lower case hex
+    pub fn hex(self) -> String {
+        match self {
+            Checksum::Md5(c) => to_hex(&c.compute().0),
+            Checksum::Sha256(c) => to_hex(&c.finalize()),
+            Checksum::Xxhash(c) => format!("{:016x}", c.digest()),
+        }
+    }
+}
+
+/// Passes reads through while feeding every byte to a shared digest, so the
+/// src digest comes out of the copy pass itself.  The hasher is shared since
+/// the threaded copier moves the reader into its own thread.
+pub struct HashingReader {
+    inner: Box<dyn Read + Send>,
+    hasher: Arc<Mutex<Checksum>>,
+}
+
+impl HashingReader {
+    pub fn new(inner: Box<dyn Read + Send>, hasher: Arc<Mutex<Checksum>>) -> Self {
+        HashingReader { inner, hasher }
+    }
+}
+
+impl Read for HashingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+pub fn finish(hasher: &Arc<Mutex<Checksum>>) -> String {
+    hasher.lock().unwrap().clone().hex()
+}
+
+/// full read of a file for its digest - used for the dst read back
+pub fn digest_of(mut r: impl Read, buf_size: usize, alg: ChecksumAlgorithm) -> Result<String> {
+    let mut hasher = Checksum::new(alg);
+    let mut buf = vec![0u8; buf_size];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.hex())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -5,20 +5,126 @@
 use anyhow::{anyhow as ERR, Context};
 use log::{debug, error, info, trace, warn, Record};
 use std::path::{PathBuf, Path};
-use ssh2::{Sftp, Session, FileStat, RenameFlags};
+use ssh2::{Sftp, Session, FileStat, RenameFlags, OpenFlags, OpenType, HashType, CheckResult, KnownHostFileKind};
 use libssh2_sys::LIBSSH2_ERROR_FILE;
 use std::fs::{ReadDir, Metadata};
-use std::io::{Write, Read};
+use std::io::{Write, Read, Seek, SeekFrom};
 use url::Url;
 use std::time::{Duration, SystemTime};
 use std::net::TcpStream;
-use crate::vfs::FileType::{Regular, Directory};
+use crate::vfs::FileType::{Regular, Directory, Symlink};
 use std::convert::TryFrom;
 use std::ops::Add;
 use std::fmt::Display;
 use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use crate::azure::AzureBlobVfs;
+use crate::s3::S3Vfs;
+use crate::ftp::FtpVfs;
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
+pub type VfsResult<T> = std::result::Result<T, VfsError>;
+
+/// What went wrong with a vfs file operation, sorted enough that callers can
+/// tell a missing file or a refusal, which no retry will fix, from a timeout.
+#[derive(Debug)]
+pub enum VfsError {
+    NotFound(String),
+    PermissionDenied(String),
+    NetworkTimeout(String),
+    Io(std::io::Error),
+    Sftp(ssh2::Error),
+    Other(String),
+}
+
+impl VfsError {
+    /// false for errors the same operation will just hit again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            VfsError::NotFound(_) | VfsError::PermissionDenied(_) => false,
+            VfsError::NetworkTimeout(_) | VfsError::Io(_) | VfsError::Sftp(_) | VfsError::Other(_) => true,
+        }
+    }
+}
+
+impl Display for VfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VfsError::NotFound(m) => write!(f, "not found: {}", m),
+            VfsError::PermissionDenied(m) => write!(f, "permission denied: {}", m),
+            VfsError::NetworkTimeout(m) => write!(f, "network timeout: {}", m),
+            VfsError::Io(e) => write!(f, "{}", e),
+            VfsError::Sftp(e) => write!(f, "sftp: {}", e),
+            VfsError::Other(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl std::error::Error for VfsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VfsError::Io(e) => Some(e),
+            VfsError::Sftp(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for VfsError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::NotFound => VfsError::NotFound(e.to_string()),
+            ErrorKind::PermissionDenied => VfsError::PermissionDenied(e.to_string()),
+            ErrorKind::TimedOut => VfsError::NetworkTimeout(e.to_string()),
+            _ => VfsError::Io(e),
+        }
+    }
+}
+
+impl From<ssh2::Error> for VfsError {
+    fn from(e: ssh2::Error) -> Self {
+        match e.code() {
+            libssh2_sys::LIBSSH2_FX_NO_SUCH_FILE => VfsError::NotFound(e.to_string()),
+            libssh2_sys::LIBSSH2_FX_PERMISSION_DENIED => VfsError::PermissionDenied(e.to_string()),
+            libssh2_sys::LIBSSH2_ERROR_TIMEOUT => VfsError::NetworkTimeout(e.to_string()),
+            _ => VfsError::Sftp(e),
+        }
+    }
+}
+
+/// the backends and helpers still speak anyhow, so look down the chain for
+/// an io or ssh2 error to sort by, keeping the whole message with its context
+impl From<anyhow::Error> for VfsError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<std::io::Error>() {
+            Ok(io) => return VfsError::from(io),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<ssh2::Error>() {
+            Ok(ssh) => return VfsError::from(ssh),
+            Err(e) => e,
+        };
+        let msg = format!("{:#}", e);
+        for cause in e.chain() {
+            if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+                return match VfsError::from(std::io::Error::new(io.kind(), msg.clone())) {
+                    VfsError::Io(_) => VfsError::Other(msg),
+                    sorted => sorted,
+                };
+            }
+            if let Some(ssh) = cause.downcast_ref::<ssh2::Error>() {
+                return match ssh.code() {
+                    libssh2_sys::LIBSSH2_FX_NO_SUCH_FILE => VfsError::NotFound(msg),
+                    libssh2_sys::LIBSSH2_FX_PERMISSION_DENIED => VfsError::PermissionDenied(msg),
+                    libssh2_sys::LIBSSH2_ERROR_TIMEOUT => VfsError::NetworkTimeout(msg),
+                    _ => VfsError::Other(msg),
+                };
+            }
+        }
+        VfsError::Other(msg)
+    }
+}
 
 pub struct SftpFile {
     path: PathBuf,
@@ -35,20 +141,37 @@ pub struct LocalFile {
 pub enum FileType {
     Regular,
     Directory,
+    Symlink,
     Unknown,
 }
 
+/// how the dst tmp file is created
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum CreateMode {
+    Normal,
+    Exclusive,
+}
+
 #[derive(Clone,Copy,Debug)]
 pub struct FileStatus {
     pub file_type: FileType,
     pub size: u64,
     pub mtime: SystemTime,
+    /// st_dev and st_ino of a local file with more than one link, so hard
+    /// links can be told apart from copies.  None for a file with one link,
+    /// and always for sftp v3 and the object stores, which have nothing like it.
+    pub hard_link: Option<(u64, u64)>,
 }
 
 pub struct SftpVfs {
     write_perm: Option<u32>,
+    strict: bool,
     base_dir: PathBuf,
     sftp: Sftp,
+    /// shared with the keepalive thread, which ends once this is dropped
+    sess: Arc<Mutex<Session>>,
+    /// the session is --sftp-channel-per-thread's, other vfs have channels on it too
+    shared: bool,
 }
 
 pub struct LocalVfs {
@@ -57,19 +180,69 @@ pub struct LocalVfs {
 
 pub enum Vfs {
     Sftp(SftpVfs),
-    Local(LocalVfs)
+    Local(LocalVfs),
+    S3(S3Vfs),
+    Ftp(FtpVfs),
+    AzureBlob(AzureBlobVfs),
 }
 
 pub enum ReadDirHandle {
     Local(LocalFile),
     Sftp(SftpFile),
+    /// S3 pages through the whole listing when the "dir" is opened
+    S3(Vec<(PathBuf, Option<FileStatus>)>),
+    /// FTP also gets the whole listing in one LIST reply
+    Ftp(Vec<(PathBuf, Option<FileStatus>)>),
+    /// and so does azure
+    AzureBlob(Vec<(PathBuf, Option<FileStatus>)>),
 }
 
 impl ReadDirHandle {
     pub fn read_all_dir_entry(&mut self) -> Result<Vec<(PathBuf, Option<FileStatus>)>> {
-        match self {
+        self.read_all_dir_entry_tick(&|| ())
+    }
+
+    /// same as read_all_dir_entry but calls tick after every entry read so a
+    /// watcher can tell a slow listing from a hung one
+    pub fn read_all_dir_entry_tick(&mut self, tick: &dyn Fn()) -> Result<Vec<(PathBuf, Option<FileStatus>)>> {
+        let mut list = vec![];
+        for entry in self.iter() {
+            list.push(entry?);
+            tick();
+        }
+        Ok(list)
+    }
+
+    /// entries one at a time instead of the whole directory in one Vec - the
+    /// object stores and ftp already hold the whole listing so just hand it out
+    pub fn iter(&mut self) -> VfsReadDirIter<'_> {
+        let listed = match self {
+            ReadDirHandle::S3(list) | ReadDirHandle::Ftp(list) | ReadDirHandle::AzureBlob(list) => Some(std::mem::take(list).into_iter()),
+            ReadDirHandle::Sftp(_) | ReadDirHandle::Local(_) => None,
+        };
+        VfsReadDirIter { handle: self, listed, done: false }
+    }
+}
+
+/// see ReadDirHandle::iter - the first error ends it
+pub struct VfsReadDirIter<'a> {
+    handle: &'a mut ReadDirHandle,
+    listed: Option<std::vec::IntoIter<(PathBuf, Option<FileStatus>)>>,
+    done: bool,
+}
+
+impl<'a> Iterator for VfsReadDirIter<'a> {
+    type Item = Result<(PathBuf, Option<FileStatus>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some(listed) = &mut self.listed {
+            return listed.next().map(Ok);
+        }
+        let next = match self.handle {
             ReadDirHandle::Sftp(h) => {
-                let mut list = vec![];
                 let (this_dir, par_dir) = (Path::new("."), Path::new(".."));
                 loop {
                     match h.file.readdir() {
@@ -78,79 +251,134 @@ impl ReadDirHandle {
                                 continue;
                             }
 
-                            let status = FileStatus::try_from(&stat).context("next_dir_entry of SftpFile canon")?;
+                            // some servers do not send attributes with readdir - those get stat'ed later
+                            let status = if stat.mtime.is_some() {
+                                match FileStatus::try_from(&stat).context("next_dir_entry of SftpFile canon") {
+                                    Ok(s) => Some(s),
+                                    Err(e) => break Some(Err(e)),
+                                }
+                            } else {
+                                None
+                            };
                             trace!("next_dir_entry sftp return: {}", filename.display());
                             crate::STATS.dirs_check.fetch_add(1, Ordering::Relaxed);
-                            list.push( (filename, Some(status)) );
+                            break Some(Ok((filename, status)));
                         }
-                        Err(ref e) if e.code() == LIBSSH2_ERROR_FILE => return Ok(list),
-                        Err(e) => return Err(ERR!("error on next readdir: {}", e)),
+                        Err(ref e) if e.code() == LIBSSH2_ERROR_FILE => break None,
+                        Err(e) => break Some(Err(ERR!("error on next readdir: {}", e))),
                     };
                 }
-
             },
-            ReadDirHandle::Local(h) => {
-                let mut list = vec![];
-                loop {
-                    match h.itr.next() {
-                        None => return Ok(list),
-                        Some(r) => match r {
-                            Err(e) => return Err(ERR!("error on reading next entry in ReadDir: {}", e)),
-                            Ok(de) => {
-                                crate::STATS.dirs_check.fetch_add(1, Ordering::Relaxed);
-                                list.push((de.path(), None));
-                            },
-                        }
-                    }
-                }
+            ReadDirHandle::Local(h) => match h.itr.next() {
+                None => None,
+                Some(Err(e)) => Some(Err(ERR!("error on reading next entry in ReadDir: {}", e))),
+                Some(Ok(de)) => {
+                    crate::STATS.dirs_check.fetch_add(1, Ordering::Relaxed);
+                    Some(Ok((de.path(), None)))
+                },
             },
+            ReadDirHandle::S3(_) | ReadDirHandle::Ftp(_) | ReadDirHandle::AzureBlob(_) => None,
+        };
+        if !matches!(next, Some(Ok(_))) {
+            self.done = true;
         }
+        next
     }
-
 }
 
 impl Vfs {
-    pub fn new(url: &Url, perm: Option<u32>, pk: &Option<PathBuf>, timeout: Option<Duration>) -> Result<Vfs> {
+    /// shared, when given for an sftp url, is a session from sftp_session
+    /// that this vfs opens its own sftp channel on instead of connecting
+    pub fn new(url: &Url, opts: &ConnectOptions, shared: Option<&Arc<Mutex<Session>>>) -> Result<Vfs> {
         match url.scheme() {
             "sftp" => {
-                match (pk, timeout) {
-                    (Some(pk), Some(timeout)) => {
-                        let soc = url.socket_addrs(|| Some(22))?[0];
-                        let tcp = TcpStream::connect_timeout(&soc, timeout).with_context(|| format!("Tcp connection to url: {} failed", &url))?;
-
-
-                        let mut sess = Session::new().unwrap();
-                        sess.set_tcp_stream(tcp);
-                        sess.handshake()?;
-                        sess.userauth_pubkey_file(&url.username(), None,
-                                                  &pk, None).with_context(|| format!("Unable to setup user with private key: {} for url {}", pk.display(), &url))?;
-
-                        let sftp = sess.sftp().with_context(|| format!("Unable to create sftp session with private key: {} for url {}", pk.display(), &url))?;
-                        sftp.lstat(&*PathBuf::from(&url.path().to_string())).with_context(|| format!("Cannot stat check remote path of \"{}\"", url))?;
-                        info!("creating sftp vfs for {}", &url);
-                        return Ok(Vfs::Sftp(SftpVfs {
-                            base_dir: PathBuf::from(url.path()),
-                            sftp: sftp,
-                            write_perm: perm,
-                        }));
-                    }
-                    _ => return Err(ERR!("sftp URL requires timeout and private key settings for {}", url)),
-                }
+                let sess = match shared {
+                    Some(sess) => sess.clone(),
+                    None => Arc::new(Mutex::new(sftp_session(url, opts)?)),
+                };
+                // The channel always gets libssh2's 2M window and 32k max packet.
+                // libssh2_sftp_init opens it with the compiled in defaults and ssh2
+                // keeps the LIBSSH2_SFTP to itself, so neither can be tuned from here.
+                let sftp = sess.lock().unwrap().sftp().with_context(|| format!("Unable to create sftp session for url {}", &url))?;
+                sftp.lstat(&PathBuf::from(&url.path().to_string())).with_context(|| format!("Cannot stat check remote path of \"{}\"", url))?;
+                info!("creating sftp vfs for {}{}", &url, if shared.is_some() { " on the shared session" } else { "" });
+                Ok(Vfs::Sftp(SftpVfs {
+                    base_dir: PathBuf::from(url.path()),
+                    sftp,
+                    sess,
+                    shared: shared.is_some(),
+                    write_perm: opts.perm,
+                    strict: false,
+                }))
             }
+            "s3" => Ok(Vfs::S3(S3Vfs::new(url)?)),
+            "az" => Ok(Vfs::AzureBlob(AzureBlobVfs::new(url, opts.azure_sas_token)?)),
+            "ftp" | "ftps" => Ok(Vfs::Ftp(FtpVfs::new(url, opts.password, Some(opts.timeouts.tcp_connect))?)),
             "file" => {
                 info!("creating file vfs for {}", url);
-                return Ok(Vfs::Local(LocalVfs {
+                Ok(Vfs::Local(LocalVfs {
                     base_dir: PathBuf::from(url.path())
-                }));
+                }))
+            }
+            _ => Err(ERR!("Cannot create an file or sftp based VFS from url: {}", &url)),
+        }
+    }
+
+    pub fn is_sftp(&self) -> bool {
+        match self {
+            Vfs::Sftp(_) => true,
+            Vfs::Local(_) | Vfs::S3(_) | Vfs::Ftp(_) | Vfs::AzureBlob(_) => false,
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        match self {
+            Vfs::Local(_) => true,
+            Vfs::Sftp(_) | Vfs::S3(_) | Vfs::Ftp(_) | Vfs::AzureBlob(_) => false,
+        }
+    }
+
+    /// turns on lstat validation after sftp operations - no effect for local
+    pub fn strict_mode(mut self, strict: bool) -> Self {
+        if let Vfs::Sftp(f) = &mut self {
+            f.strict = strict;
+        }
+        self
+    }
+
+    /// Checks the sftp server answers a realpath of "." with a real path, for
+    /// servers that accept the subsystem but close the channel on the first
+    /// request.  Nothing to check for a non sftp vfs.
+    pub fn validate_subsystem(&self, url: &Url) -> Result<()> {
+        if let Vfs::Sftp(f) = self {
+            match f.sftp.realpath(Path::new(".")) {
+                Ok(p) if !p.as_os_str().is_empty() => debug!("sftp subsystem on {} resolved \".\" to \"{}\"", url, p.display()),
+                Ok(_) => return Err(ERR!("SFTP subsystem on {} appears broken: realpath of \".\" came back empty", url)),
+                Err(e) => return Err(ERR!("SFTP subsystem on {} appears broken: {}", url, e)),
             }
-            _ => return Err(ERR!("Cannot create an file or sftp based VFS from url: {}", &url)),
         }
+        Ok(())
+    }
+
+    /// Starts a thread sending an ssh keepalive every interval, see
+    /// start_keepalive.  A zero interval, a shared session, which gets its
+    /// own, or a non sftp vfs does nothing.
+    pub fn keepalive(self, interval: Duration) -> Self {
+        if let Vfs::Sftp(f) = &self {
+            if !f.shared {
+                start_keepalive(&f.sess, interval);
+            }
+        }
+        self
     }
 
     pub fn base_dir(&self) -> &PathBuf {
         match self {
             Vfs::Sftp(f) => &f.base_dir,
             Vfs::Local(f) => &f.base_dir,
+            Vfs::S3(f) => f.base_dir(),
+            Vfs::Ftp(f) => f.base_dir(),
+            Vfs::AzureBlob(f) => f.base_dir(),
         }
     }
 
@@ -162,31 +390,269 @@ impl Vfs {
                 Ok(file)
             },
             Vfs::Local(f) => {
-                let r = std::fs::read_dir(&path).context(line!())?;
+                let r = std::fs::read_dir(path).context(line!())?;
                 Ok(ReadDirHandle::Local(LocalFile { path: path.to_path_buf(), itr: r }))
             },
+            Vfs::S3(f) => Ok(ReadDirHandle::S3(f.list(path, None)?)),
+            Vfs::Ftp(f) => Ok(ReadDirHandle::Ftp(f.list(path)?)),
+            Vfs::AzureBlob(f) => Ok(ReadDirHandle::AzureBlob(f.list(path, None)?)),
+        }
+
+    }
+    /// Lists dir and descends into its subdirectories up to max_depth levels
+    /// (None for no limit, 1 for just dir).  Sub dir entries come back
+    /// prefixed with their path relative to dir, so joining them onto dir
+    /// works the same as for a flat listing.  Sftp reuses this one session.
+    /// unchanged is asked about each sub directory before it is listed and
+    /// can hand back the sub directories to go on to instead - as
+    /// --cache-dirs does for ones not changed since last time.  Every
+    /// directory actually listed below dir goes into listed with its status.
+    pub fn list_recursive(&mut self, dir: &Path, max_depth: Option<usize>, tick: &dyn Fn(), unchanged: &dyn Fn(&Path, &FileStatus) -> Option<Vec<PathBuf>>, listed: &mut Vec<(PathBuf, FileStatus)>) -> Result<Vec<(PathBuf, Option<FileStatus>)>> {
+        let mut all = vec![];
+        let mut pending: Vec<(PathBuf, usize, Option<FileStatus>)> = vec![(PathBuf::new(), 1usize, None)];
+        while let Some((rel, depth, dir_status)) = pending.pop() {
+            let descend = max_depth.is_none_or(|max| depth < max);
+            if let Some(status) = dir_status {
+                if let Some(subdirs) = unchanged(&dir.join(&rel), &status) {
+                    for sub in subdirs.into_iter().filter(|_| descend) {
+                        match self.stat(&sub) {
+                            Ok(fs) if fs.file_type == Directory => pending.push((sub.strip_prefix(dir).unwrap_or(&sub).to_path_buf(), depth + 1, Some(fs))),
+                            Ok(_) => debug!("cached dir \"{}\" is no longer a directory", sub.display()),
+                            Err(e) => debug!("cached dir \"{}\" cannot be stat'ed: {}", sub.display(), e),
+                        }
+                    }
+                    continue;
+                }
+                listed.push((dir.join(&rel), status));
+            }
+            let entries = self.open_dir(&dir.join(&rel))
+                .with_context(|| format!("open dir on directory: {}", dir.join(&rel).display()))?
+                .read_all_dir_entry_tick(tick)?;
+            tick();
+            for (p, status) in entries {
+                // local entries are full paths already, sftp ones bare names
+                let p = if p.is_absolute() { p } else { rel.join(p) };
+                if descend {
+                    if let Some(fs) = self.dir_status(dir, &p, &status) {
+                        pending.push((p.strip_prefix(dir).unwrap_or(&p).to_path_buf(), depth + 1, Some(fs)));
+                    }
+                }
+                all.push((p, status));
+            }
         }
+        Ok(all)
+    }
 
+    /// the status of p when it is a directory
+    fn dir_status(&self, dir: &Path, p: &Path, status: &Option<FileStatus>) -> Option<FileStatus> {
+        let fs = match status {
+            Some(fs) => *fs,
+            None => self.stat(&dir.join(p)).ok()?,
+        };
+        if fs.file_type == Directory { Some(fs) } else { None }
     }
-    pub fn open(&self, filename: &Path) -> Result<Box<dyn Read + Send>> {
+
+    pub fn open(&self, filename: &Path) -> VfsResult<Box<dyn Read + Send>> {
         match self {
             Vfs::Sftp(f) => Ok(Box::new(f.sftp.open(filename)?)),
-            Vfs::Local(f) => Ok(Box::new(std::fs::File::open(&filename)?)),
+            Vfs::Local(f) => Ok(Box::new(std::fs::File::open(filename)?)),
+            Vfs::S3(f) => Ok(f.open(filename, None)?),
+            Vfs::Ftp(f) => Ok(f.open(filename, 0)?),
+            Vfs::AzureBlob(f) => Ok(f.open(filename, 0)?),
         }
     }
-    pub fn create(&self, filename: &Path) -> Result<Box<dyn Write + Send>> {
+    /// opens a file and stats the open handle, so the status is of the file
+    /// actually being read even if the path was replaced after listing.
+    ///
+    /// libssh2 only speaks sftp v3 where the OPEN reply is just a handle, so
+    /// sftp always takes a second FSTAT round trip on that handle.
+    pub fn open_with_stat(&self, filename: &Path) -> VfsResult<(Box<dyn Read + Send>, FileStatus)> {
         match self {
-            Vfs::Sftp(f) => Ok(Box::new(f.sftp.create(filename)?)),
-            Vfs::Local(f) => Ok(Box::new(std::fs::File::create(&filename)?)),
+            Vfs::Sftp(f) => {
+                let mut file = f.sftp.open(filename)?;
+                let stat = FileStatus::try_from(&file.stat()?)?;
+                Ok((Box::new(file), stat))
+            }
+            Vfs::Local(_) => {
+                let file = std::fs::File::open(filename)?;
+                let stat = FileStatus::try_from(&file.metadata()?)?;
+                Ok((Box::new(file), stat))
+            }
+            // GetObject does return the length and time, but rusoto hands back the body only with them
+            Vfs::S3(f) => {
+                let stat = f.stat(filename)?;
+                Ok((f.open(filename, None)?, stat))
+            }
+            Vfs::Ftp(f) => {
+                let stat = f.stat(filename)?;
+                Ok((f.open(filename, 0)?, stat))
+            }
+            Vfs::AzureBlob(f) => {
+                let stat = f.stat(filename)?;
+                Ok((f.open(filename, 0)?, stat))
+            }
         }
     }
-    pub fn set_perm(&self, path: &Path) -> Result<()> {
+    pub fn create(&self, filename: &Path) -> VfsResult<Box<dyn Write + Send>> {
         match self {
-            Vfs::Sftp(f) => Ok(f.sftp.setstat(&path, FileStat { perm: f.write_perm, mtime: None, size: None, atime: None, gid: None, uid: None })?),
+            Vfs::Sftp(f) => {
+                let file = f.sftp.create(filename)?;
+                if f.strict {
+                    if let Err(e) = f.sftp.lstat(filename) {
+                        error!("strict: created file \"{}\" cannot be lstat'ed: {}", filename.display(), e);
+                    }
+                }
+                Ok(Box::new(file))
+            },
+            Vfs::Local(f) => Ok(Box::new(std::fs::File::create(filename)?)),
+            Vfs::S3(f) => Ok(f.create(filename)?),
+            Vfs::Ftp(f) => Ok(f.create(filename)?),
+            Vfs::AzureBlob(f) => Ok(f.create(filename)?),
+        }
+    }
+    /// creates a file only if it does not exist yet - None means it already did
+    pub fn create_exclusive(&self, filename: &Path) -> VfsResult<Option<Box<dyn Write + Send>>> {
+        match self {
+            Vfs::Sftp(f) => match f.sftp.open_mode(filename, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE | OpenFlags::TRUNCATE, 0o644, OpenType::File) {
+                Ok(file) => Ok(Some(Box::new(file))),
+                // sftp v3 servers only report a generic failure, so look for the file
+                Err(e) => match f.sftp.lstat(filename) {
+                    Ok(_) => Ok(None),
+                    Err(_) => Err(ERR!("exclusive create of \"{}\" failed: {}", filename.display(), e).into()),
+                },
+            },
+            Vfs::Local(f) => match std::fs::OpenOptions::new().write(true).create_new(true).open(filename) {
+                Ok(file) => Ok(Some(Box::new(file))),
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            // S3 has no conditional create here, so this is only check-then-create
+            Vfs::S3(f) => match f.stat(filename) {
+                Ok(_) => Ok(None),
+                Err(_) => Ok(Some(f.create(filename)?)),
+            },
+            // nor do FTP and azure
+            Vfs::AzureBlob(f) => match f.stat(filename) {
+                Ok(_) => Ok(None),
+                Err(_) => Ok(Some(f.create(filename)?)),
+            },
+            Vfs::Ftp(f) => match f.stat(filename) {
+                Ok(_) => Ok(None),
+                Err(_) => Ok(Some(f.create(filename)?)),
+            },
+        }
+    }
+    /// opens a file for reading positioned at offset - used for chunked transfers
+    pub fn open_at(&self, filename: &Path, offset: u64) -> VfsResult<Box<dyn Read + Send>> {
+        match self {
+            Vfs::Sftp(f) => {
+                let mut file = f.sftp.open(filename)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            },
+            Vfs::Local(f) => {
+                let mut file = std::fs::File::open(filename)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            },
+            Vfs::S3(f) => Ok(f.open(filename, Some(format!("bytes={}-", offset)))?),
+            Vfs::Ftp(f) => Ok(f.open(filename, offset)?),
+            Vfs::AzureBlob(f) => Ok(f.open(filename, offset)?),
+        }
+    }
+    /// opens a file for writing at offset WITHOUT truncating it, so that
+    /// several writers can fill in different parts of the same file
+    pub fn write_at(&self, filename: &Path, offset: u64) -> VfsResult<Box<dyn Write + Send>> {
+        match self {
+            Vfs::Sftp(f) => {
+                let mut file = f.sftp.open_mode(filename, OpenFlags::WRITE | OpenFlags::CREATE, 0o644, OpenType::File)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            },
+            Vfs::Local(f) => {
+                let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(filename)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            },
+            Vfs::S3(_) => Err(ERR!("s3 objects cannot be written at an offset so chunked writes are not possible: {}", filename.display()).into()),
+            Vfs::AzureBlob(_) => Err(ERR!("azure blobs cannot be written at an offset so chunked writes are not possible: {}", filename.display()).into()),
+            Vfs::Ftp(_) => Err(ERR!("ftp uploads cannot start at an offset so chunked writes are not possible: {}", filename.display()).into()),
+        }
+    }
+    pub fn set_perm(&self, path: &Path) -> VfsResult<()> {
+        match self {
+            Vfs::Sftp(f) => {
+                f.sftp.setstat(path, FileStat { perm: f.write_perm, mtime: None, size: None, atime: None, gid: None, uid: None })?;
+                if f.strict {
+                    match (f.sftp.lstat(path), f.write_perm) {
+                        (Err(e), _) => error!("strict: cannot lstat \"{}\" after setstat: {}", path.display(), e),
+                        (Ok(st), Some(perm)) if st.perm.map(|p| p & 0o7777) != Some(perm) =>
+                            error!("strict: setstat perm {:o} not applied to \"{}\", found {:?}", perm, path.display(), st.perm.map(|p| format!("{:o}", p & 0o7777))),
+                        _ => (),
+                    }
+                }
+                Ok(())
+            },
             Vfs::Local(f) => Ok(()),
+            Vfs::S3(_) => Ok(()), // no posix permissions on objects
+            Vfs::AzureBlob(_) => Ok(()), // no posix permissions on blobs
+            Vfs::Ftp(_) => Ok(()), // SITE CHMOD is not universal enough to rely on
+        }
+    }
+    /// sftp has to set atime along with mtime, so both get the mtime
+    pub fn set_mtime(&self, path: &Path, mtime: SystemTime) -> VfsResult<()> {
+        match self {
+            Vfs::Sftp(f) => {
+                let secs = mtime.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+                f.sftp.setstat(path, FileStat { perm: None, mtime: Some(secs), size: None, atime: Some(secs), gid: None, uid: None })?;
+                Ok(())
+            },
+            Vfs::Local(_) => Ok(filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime))?),
+            Vfs::S3(_) | Vfs::AzureBlob(_) | Vfs::Ftp(_) => Err(ERR!("cannot set the mtime of \"{}\" on this kind of dst", path.display()).into()),
+        }
+    }
+    /// creates the directory and any missing parents - s3 has no directories
+    pub fn mkdir_all(&self, path: &Path) -> VfsResult<()> {
+        match self {
+            Vfs::Sftp(f) => {
+                if f.sftp.stat(path).is_ok_and(|st| st.is_dir()) {
+                    return Ok(());
+                }
+                let mut dir = PathBuf::new();
+                for comp in path.components() {
+                    dir.push(comp);
+                    if f.sftp.stat(&dir).is_ok() {
+                        continue;
+                    }
+                    if let Err(e) = f.sftp.mkdir(&dir, 0o755) {
+                        // another xfer thread may have just made it
+                        if e.code() != LIBSSH2_ERROR_FILE && !f.sftp.stat(&dir).is_ok_and(|st| st.is_dir()) {
+                            return Err(ERR!("sftp mkdir of \"{}\" failed: {}", dir.display(), e).into());
+                        }
+                    }
+                }
+                Ok(())
+            },
+            Vfs::Local(f) => Ok(std::fs::create_dir_all(path)?),
+            Vfs::S3(_) | Vfs::AzureBlob(_) => Ok(()),
+            Vfs::Ftp(f) => {
+                let mut dir = PathBuf::new();
+                for comp in path.components() {
+                    dir.push(comp);
+                    if f.stat(&dir).is_ok() {
+                        continue;
+                    }
+                    if let Err(e) = f.mkdir(&dir) {
+                        if f.stat(&dir).map_or(true, |st| st.file_type != Directory) {
+                            return Err(e.into());
+                        }
+                    }
+                }
+                Ok(())
+            },
         }
     }
-    pub fn rename(&self, src: &Path, dst: &Path) -> Result<()> {
+    pub fn rename(&self, src: &Path, dst: &Path) -> VfsResult<()> {
         match self {
             Vfs::Sftp(f) => {
 
@@ -196,39 +662,514 @@ impl Vfs {
                         if e.code() == -31 {
                             warn!("ignore -31 code on rename from {} to {}", src.display(), dst.display());
                         } else {
-                            return Err(ERR!("sftp rename error: {}", e))
+                            return Err(ERR!("sftp rename error: {}", e).into())
+                        }
+                    },
+                    Ok(()) => {
+                        if f.strict {
+                            f.strict_check_rename(src, dst);
                         }
+                        return Ok(());
                     },
-                    Ok(()) => return Ok(()),
                 }
                 Ok(())
             },
             Vfs::Local(f) => Ok(std::fs::rename(src, dst)?),
+            Vfs::S3(f) => Ok(f.rename(src, dst)?),
+            Vfs::Ftp(f) => Ok(f.rename(src, dst)?),
+            Vfs::AzureBlob(f) => Ok(f.rename(src, dst)?),
         }
     }
-    pub fn stat(&self, path: &Path) -> Result<FileStatus> {
+    pub fn remove(&self, path: &Path) -> VfsResult<()> {
+        match self {
+            Vfs::Sftp(f) => Ok(f.sftp.unlink(path)?),
+            Vfs::Local(f) => Ok(std::fs::remove_file(path)?),
+            Vfs::S3(f) => Ok(f.remove(path)?),
+            Vfs::Ftp(f) => Ok(f.remove(path)?),
+            Vfs::AzureBlob(f) => Ok(f.remove(path)?),
+        }
+    }
+    /// cheap check that the connection is still alive - local is always alive
+    pub fn probe(&self, path: &Path) -> VfsResult<()> {
+        match self {
+            Vfs::Sftp(f) => {
+                f.sftp.lstat(path).with_context(|| format!("probe of \"{}\" failed", path.display()))?;
+                Ok(())
+            },
+            Vfs::Local(f) => Ok(()),
+            Vfs::S3(f) => Ok(f.stat(path).map(|_| ()).with_context(|| format!("probe of \"{}\" failed", path.display()))?),
+            Vfs::Ftp(f) => Ok(f.probe()?),
+            Vfs::AzureBlob(f) => Ok(f.stat(path).map(|_| ()).with_context(|| format!("probe of \"{}\" failed", path.display()))?),
+        }
+    }
+    /// bytes free to unprivileged writers on the filesystem holding dir, None
+    /// where there is no such thing.  sftp needs the statvfs@openssh.com
+    /// extension, which works on file handles only, so a probe file is made.
+    pub fn free_space(&self, dir: &Path) -> VfsResult<Option<u64>> {
+        match self {
+            Vfs::Sftp(f) => {
+                let probe = dir.join(".tmp.statvfs");
+                let st = f.sftp.create(&probe).and_then(|mut h| h.statvfs());
+                if let Err(e) = f.sftp.unlink(&probe) {
+                    warn!("cannot remove statvfs probe \"{}\": {}", probe.display(), e);
+                }
+                match st {
+                    Ok(st) => Ok(Some(st.f_bavail * st.f_frsize)),
+                    Err(e) => {
+                        debug!("sftp statvfs of \"{}\" failed so free space unknown: {}", dir.display(), e);
+                        Ok(None)
+                    }
+                }
+            }
+            #[cfg(unix)]
+            Vfs::Local(_) => {
+                let st = nix::sys::statvfs::statvfs(dir).with_context(|| format!("statvfs of \"{}\"", dir.display()))?;
+                Ok(Some(st.blocks_available() as u64 * st.fragment_size() as u64))
+            }
+            #[cfg(not(unix))]
+            Vfs::Local(_) => Ok(None),
+            Vfs::S3(_) | Vfs::Ftp(_) | Vfs::AzureBlob(_) => Ok(None),
+        }
+    }
+    /// does not follow symlinks, so they come back as Symlink - see stat_follow
+    pub fn stat(&self, path: &Path) -> VfsResult<FileStatus> {
         match self {
             Vfs::Sftp(f) => Ok(FileStatus::try_from(&f.sftp.lstat(path)?)?),
-            Vfs::Local(f) => Ok(FileStatus::try_from(&std::fs::metadata(&path)?)?),
+            Vfs::Local(f) => Ok(FileStatus::try_from(&std::fs::symlink_metadata(path)?)?),
+            Vfs::S3(f) => Ok(f.stat(path)?),
+            Vfs::Ftp(f) => Ok(f.stat(path)?),
+            Vfs::AzureBlob(f) => Ok(f.stat(path)?),
+        }
+    }
+
+    /// stats many paths, skipping with a warning the ones that fail.  depth is
+    /// the local stat thread count, or how many sftp stat requests are kept in
+    /// flight over the one connection.  Other vfs stat one at a time.
+    pub fn stat_many(&self, paths: &[PathBuf], depth: usize) -> VfsResult<Vec<(PathBuf, FileStatus)>> {
+        match self {
+            // pipelining flips the whole session to non-blocking, under other threads' feet if shared
+            Vfs::Sftp(f) if depth > 1 && paths.len() > 1 && !f.shared => Ok(f.stat_pipelined(paths, depth)?),
+            Vfs::Local(_) => Ok(crate::fast_stat::get_stats_fast(depth.max(1), &mut paths.to_vec(), crossbeam_channel::never())?),
+            _ => {
+                let mut list = Vec::with_capacity(paths.len());
+                for p in paths {
+                    crate::STATS.stat_check.fetch_add(1, Ordering::Relaxed);
+                    match self.stat(p) {
+                        Err(e) => warn!("cannot stat \"{}\" so skipping it: {}", p.display(), e),
+                        Ok(fs) => list.push((p.clone(), fs)),
+                    }
+                }
+                Ok(list)
+            }
+        }
+    }
+
+    /// like stat but follows symlinks to the status of their target
+    pub fn stat_follow(&self, path: &Path) -> VfsResult<FileStatus> {
+        match self {
+            Vfs::Sftp(f) => Ok(FileStatus::try_from(&f.sftp.stat(path)?)?),
+            Vfs::Local(f) => Ok(FileStatus::try_from(&std::fs::metadata(path)?)?),
+            Vfs::S3(f) => Ok(f.stat(path)?),
+            Vfs::Ftp(f) => Ok(f.stat(path)?),
+            Vfs::AzureBlob(f) => Ok(f.stat(path)?),
+        }
+    }
+
+    /// the path a symlink points to, for --include-symlinks copy
+    pub fn read_link(&self, path: &Path) -> VfsResult<PathBuf> {
+        match self {
+            Vfs::Sftp(f) => Ok(f.sftp.readlink(path)?),
+            Vfs::Local(_) => Ok(std::fs::read_link(path)?),
+            Vfs::S3(_) | Vfs::Ftp(_) | Vfs::AzureBlob(_) => Err(VfsError::Other(format!("cannot read symlink \"{}\" on this kind of source", path.display()))),
+        }
+    }
+
+}
+
+
+
+/// SO_RCVBUF and SO_SNDBUF for the tcp stream under an sftp session, from
+/// --sftp-tcp-rcvbuf and --sftp-tcp-sndbuf.  0 leaves the OS default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpBuffers {
+    pub rcvbuf: usize,
+    pub sndbuf: usize,
+}
+
+impl TcpBuffers {
+    #[cfg(unix)]
+    fn apply(&self, tcp: &TcpStream) -> Result<()> {
+        use nix::sys::socket::{setsockopt, sockopt};
+        if self.rcvbuf > 0 {
+            setsockopt(tcp, sockopt::RcvBuf, &self.rcvbuf).context("setting SO_RCVBUF")?;
+        }
+        if self.sndbuf > 0 {
+            setsockopt(tcp, sockopt::SndBuf, &self.sndbuf).context("setting SO_SNDBUF")?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _tcp: &TcpStream) -> Result<()> {
+        if self.rcvbuf > 0 || self.sndbuf > 0 {
+            warn!("--sftp-tcp-rcvbuf and --sftp-tcp-sndbuf are only applied on unix");
+        }
+        Ok(())
+    }
+}
+
+/// what Vfs::new needs to connect and authenticate - each scheme only looks
+/// at the fields it has a use for
+#[derive(Clone, Copy)]
+pub struct ConnectOptions<'a> {
+    /// permissions set on files written to an sftp dst
+    pub perm: Option<u32>,
+    pub pk: &'a Option<PathBuf>,
+    pub password: Option<&'a str>,
+    pub timeouts: Timeouts,
+    pub fingerprint_pin: Option<&'a str>,
+    /// None skips the host key check
+    pub known_hosts: Option<&'a Path>,
+    /// jump host url and the key to authenticate to it with
+    pub jump: Option<(&'a Url, &'a Option<PathBuf>)>,
+    pub azure_sas_token: Option<&'a str>,
+    pub tcp_buf: TcpBuffers,
+}
+
+/// how long each phase of making an sftp connection may take, and then each
+/// operation on it
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub tcp_connect: Duration,
+    /// covers the ssh handshake, host key checks and authentication
+    pub handshake: Duration,
+    pub io: Duration,
+}
+
+impl Timeouts {
+    /// io for every phase the connect and handshake limits leave unset
+    pub fn new(io: Duration, tcp_connect: Option<Duration>, handshake: Option<Duration>) -> Self {
+        Timeouts { tcp_connect: tcp_connect.unwrap_or(io), handshake: handshake.unwrap_or(io), io }
+    }
+}
+
+fn to_millis(d: Duration) -> u32 {
+    d.as_millis().min(u32::MAX as u128) as u32
+}
+
+/// names the phase of a connection that failed, and says so when it was
+/// the phase's timeout that ran out rather than some other error
+fn phase<T>(res: Result<T>, phase: &str, url: &Url, limit: Duration, flag: &str) -> Result<T> {
+    res.map_err(|e| {
+        let timed_out = e.chain().any(|c| {
+            c.downcast_ref::<std::io::Error>().is_some_and(|io| io.kind() == std::io::ErrorKind::TimedOut)
+                || c.downcast_ref::<ssh2::Error>().is_some_and(|s| s.code() == libssh2_sys::LIBSSH2_ERROR_TIMEOUT)
+        });
+        match timed_out {
+            true => e.context(format!("{} with {} timed out after {:?} - see {}", phase, url, limit, flag)),
+            false => e.context(format!("{} with {} failed", phase, url)),
+        }
+    })
+}
+
+/// connects, checks the host and authenticates an ssh session for an sftp url
+pub fn sftp_session(url: &Url, opts: &ConnectOptions) -> Result<Session> {
+    let ConnectOptions { pk, password, timeouts, fingerprint_pin, known_hosts, jump, tcp_buf, .. } = *opts;
+    if pk.is_none() && password.is_none() {
+        return Err(ERR!("sftp URL requires a private key or password for {}", url));
+    }
+    let mut sess = Session::new().unwrap();
+    match jump {
+        None => {
+            let soc = url.socket_addrs(|| Some(22))?[0];
+            let tcp = phase(TcpStream::connect_timeout(&soc, timeouts.tcp_connect).map_err(anyhow::Error::from), "tcp connect", url, timeouts.tcp_connect, "--tcp-connect-timeout")?;
+            tcp_buf.apply(&tcp).with_context(|| format!("setting tcp buffer sizes for url: {}", &url))?;
+            sess.set_tcp_stream(tcp);
         }
+        Some((jump_url, jump_pk)) => sess.set_tcp_stream(jump_tunnel(jump_url, jump_pk, url, timeouts, known_hosts, tcp_buf)?),
+    }
+    sess.set_timeout(to_millis(timeouts.handshake));
+    phase(sess.handshake().map_err(anyhow::Error::from), "ssh handshake", url, timeouts.handshake, "--ssh-handshake-timeout")?;
+    match known_hosts {
+        Some(kh) => check_known_host(&sess, url, kh)?,
+        None => warn!("host key of {} NOT checked against known hosts", url),
+    }
+    if let Some(pin) = fingerprint_pin {
+        check_fingerprint(&sess, url, pin)?;
     }
+    phase(userauth(&sess, url, pk, password), "ssh authentication", url, timeouts.handshake, "--ssh-handshake-timeout")?;
+    // from here on every blocking sftp call gets --timeout
+    sess.set_timeout(to_millis(timeouts.io));
+    Ok(sess)
+}
 
+/// Sends an ssh keepalive every interval from its own thread so long quiet
+/// stretches, like a slow listing, do not get the session dropped by the
+/// server.  The thread ends soon after the last Arc of the session goes.
+pub fn start_keepalive(sess: &Arc<Mutex<Session>>, interval: Duration) {
+    if interval.as_secs() == 0 {
+        return;
+    }
+    sess.lock().unwrap().set_keepalive(false, interval.as_secs() as u32);
+    let sess = Arc::downgrade(sess);
+    let started = std::thread::Builder::new().name("keepalive".to_string()).spawn(move || {
+        let mut last = std::time::Instant::now();
+        loop {
+            // short naps so the thread notices the session is gone soon after
+            std::thread::sleep(Duration::from_secs(1).min(interval));
+            let sess = match sess.upgrade() {
+                None => return,
+                Some(s) => s,
+            };
+            if last.elapsed() >= interval {
+                last = std::time::Instant::now();
+                if let Err(e) = sess.lock().unwrap().keepalive_send() {
+                    warn!("sftp keepalive failed: {}", e);
+                }
+            }
+        }
+    });
+    if let Err(e) = started {
+        warn!("cannot start sftp keepalive thread: {}", e);
+    }
 }
 
+/// key first when there is one, then the password - the password itself
+/// never goes into an error message
+fn userauth(sess: &Session, url: &Url, pk: &Option<PathBuf>, password: Option<&str>) -> Result<()> {
+    if let Some(pk) = pk {
+        match sess.userauth_pubkey_file(url.username(), None, pk, None) {
+            Ok(()) => return Ok(()),
+            Err(e) if password.is_some() => warn!("private key {} refused for url {} so trying password: {}", pk.display(), url, e),
+            Err(e) => return Err(e).with_context(|| format!("Unable to setup user with private key: {} for url {}", pk.display(), &url)),
+        }
+    }
+    match password {
+        Some(pw) => sess.userauth_password(url.username(), pw)
+            .with_context(|| format!("Unable to setup user with password ****** for url {}", &url)),
+        None => Err(ERR!("no private key or password for url {}", url)),
+    }
+}
 
+/// Connects and authenticates to the jump host, then opens a direct-tcpip
+/// channel from it to the host of url.  libssh2 needs a socket for the
+/// session to the final host, so a thread pumps bytes between the channel and
+/// one end of a loopback socket pair while the other end is returned.
+fn jump_tunnel(jump_url: &Url, jump_pk: &Option<PathBuf>, url: &Url, timeouts: Timeouts, known_hosts: Option<&Path>, tcp_buf: TcpBuffers) -> Result<TcpStream> {
+    let soc = jump_url.socket_addrs(|| Some(22))?[0];
+    let tcp = phase(TcpStream::connect_timeout(&soc, timeouts.tcp_connect).map_err(anyhow::Error::from), "tcp connect to jump host", jump_url, timeouts.tcp_connect, "--tcp-connect-timeout")?;
+    tcp_buf.apply(&tcp).with_context(|| format!("setting tcp buffer sizes for jump host {}", jump_url))?;
+    let mut jsess = Session::new().unwrap();
+    jsess.set_tcp_stream(tcp);
+    // the tunnel itself is pumped non-blocking so only the setup needs this
+    jsess.set_timeout(to_millis(timeouts.handshake));
+    phase(jsess.handshake().map_err(anyhow::Error::from), "ssh handshake with jump host", jump_url, timeouts.handshake, "--ssh-handshake-timeout")?;
+    match known_hosts {
+        Some(kh) => check_known_host(&jsess, jump_url, kh).with_context(|| format!("jump host {} failed its host check", jump_url))?,
+        None => warn!("host key of jump host {} NOT checked against known hosts", jump_url),
+    }
+    phase(userauth(&jsess, jump_url, jump_pk, None), "ssh authentication with jump host", jump_url, timeouts.handshake, "--ssh-handshake-timeout")?;
+
+    let host = url.host_str().ok_or_else(|| ERR!("no host in url {}", url))?;
+    let port = url.port().unwrap_or(22);
+    let channel = jsess.channel_direct_tcpip(host, port, None)
+        .with_context(|| format!("jump host {} could not open a channel to {}:{}", jump_url, host, port))?;
+    info!("tunneling to {}:{} through jump host {}", host, port, jump_url);
+
+    let (ours, theirs) = loopback_pair().context("creating the socket pair for the jump host tunnel")?;
+    let name = format!("jump:{}", host);
+    std::thread::Builder::new().name(name).spawn(move || {
+        if let Err(e) = pump_tunnel(&jsess, channel, theirs) {
+            debug!("jump host tunnel ended: {}", e);
+        }
+    })?;
+    Ok(ours)
+}
+
+/// two connected tcp streams on 127.0.0.1 - unix socket pairs are not
+/// portable and anything else connecting to the listener first is refused
+fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (theirs, peer) = listener.accept()?;
+        if peer == ours.local_addr()? {
+            // ssh packets are already framed - no point delaying small ones
+            ours.set_nodelay(true)?;
+            theirs.set_nodelay(true)?;
+            return Ok((ours, theirs));
+        }
+        warn!("unexpected connection from {} to the jump host tunnel refused", peer);
+    }
+}
+
+/// moves bytes both ways until either side closes - everything is non-blocking
+/// so one thread can serve both directions
+fn pump_tunnel(jsess: &Session, mut channel: ssh2::Channel, mut sock: TcpStream) -> std::io::Result<()> {
+    jsess.set_blocking(false);
+    sock.set_nonblocking(true)?;
+    let mut up = vec![0u8; 32 * 1024];
+    let (mut up_len, mut up_pos) = (0usize, 0usize);
+    let mut down = vec![0u8; 32 * 1024];
+    let (mut down_len, mut down_pos) = (0usize, 0usize);
+    loop {
+        let mut busy = false;
+        if up_pos == up_len {
+            match sock.read(&mut up) {
+                Ok(0) => { let _ = channel.send_eof(); return Ok(()); }
+                Ok(n) => { up_len = n; up_pos = 0; busy = true; }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if up_pos < up_len {
+            match channel.write(&up[up_pos..up_len]) {
+                Ok(n) => { up_pos += n; busy = true; }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if down_pos == down_len {
+            match channel.read(&mut down) {
+                Ok(0) if channel.eof() => return Ok(()),
+                Ok(0) => {}
+                Ok(n) => { down_len = n; down_pos = 0; busy = true; }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if down_pos < down_len {
+            match sock.write(&down[down_pos..down_len]) {
+                Ok(n) => { down_pos += n; busy = true; }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !busy {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// refuses the connection unless the server's key is listed for this host
+/// and port in the OpenSSH style known_hosts file
+fn check_known_host(sess: &Session, url: &Url, known_hosts: &Path) -> Result<()> {
+    let host = url.host_str().ok_or_else(|| ERR!("no host in url {}", url))?;
+    let port = url.port().unwrap_or(22);
+    let mut kh = sess.known_hosts()?;
+    kh.read_file(known_hosts, KnownHostFileKind::OpenSSH)
+        .with_context(|| format!("Unable to read known hosts file \"{}\"", known_hosts.display()))?;
+    let (key, _) = sess.host_key().ok_or_else(|| ERR!("no host key from {}", url))?;
+    match kh.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(ERR!("host {}:{} not found in known hosts \"{}\" - refusing connection", host, port, known_hosts.display())),
+        CheckResult::Mismatch => Err(ERR!("host key of {}:{} does NOT match known hosts \"{}\" - refusing connection", host, port, known_hosts.display())),
+        CheckResult::Failure => Err(ERR!("host key check of {}:{} against \"{}\" failed - refusing connection", host, port, known_hosts.display())),
+    }
+}
+
+/// rejects the host unless its SHA-256 host key hash matches the pinned hex
+/// value - the session is disconnected before anything is authenticated
+fn check_fingerprint(sess: &Session, url: &Url, pin: &str) -> Result<()> {
+    let hash = sess.host_key_hash(HashType::Sha256).ok_or_else(|| ERR!("no SHA-256 host key hash available for {}", url))?;
+    let fingerprint = hash.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    if fingerprint != pin {
+        let _ = sess.disconnect(None, "host key fingerprint mismatch", None);
+        return Err(ERR!("host key fingerprint of {} is {} but pinned to {} - refusing connection", url, fingerprint, pin));
+    }
+    debug!("host key fingerprint of {} matches pin", url);
+    Ok(())
+}
+
+impl SftpVfs {
+    /// libssh2 keeps one stat in progress per sftp channel, so in non-blocking
+    /// mode a channel each lets depth stat requests be in flight at once.
+    /// Each slot retries its call until it stops returning EAGAIN.
+    fn stat_pipelined(&self, paths: &[PathBuf], depth: usize) -> Result<Vec<(PathBuf, FileStatus)>> {
+        let start_f = std::time::Instant::now();
+        let sess = self.sess.lock().unwrap();
+        let mut extra = vec![];
+        for _ in 1..depth.min(paths.len()) {
+            extra.push(sess.sftp().context("Unable to open extra sftp channel for pipelined stats")?);
+        }
+        let channels = std::iter::once(&self.sftp).chain(extra.iter()).collect::<Vec<_>>();
+        let mut slots: Vec<Option<&PathBuf>> = vec![None; channels.len()];
+        let mut next = paths.iter();
+        let mut list = Vec::with_capacity(paths.len());
+
+        sess.set_blocking(false);
+        loop {
+            let mut busy = false;
+            let mut waiting = false;
+            for (slot, sftp) in slots.iter_mut().zip(channels.iter()) {
+                if slot.is_none() {
+                    *slot = next.next();
+                }
+                let p = match slot {
+                    None => continue,
+                    Some(p) => *p,
+                };
+                match sftp.lstat(p) {
+                    Err(e) if e.code() == libssh2_sys::LIBSSH2_ERROR_EAGAIN => {
+                        waiting = true;
+                        continue;
+                    }
+                    Err(e) => warn!("cannot stat \"{}\" so skipping it: {}", p.display(), e),
+                    Ok(st) => match FileStatus::try_from(&st) {
+                        Err(e) => warn!("cannot stat \"{}\" so skipping it: {}", p.display(), e),
+                        Ok(fs) => list.push((p.clone(), fs)),
+                    },
+                }
+                crate::STATS.stat_check.fetch_add(1, Ordering::Relaxed);
+                *slot = None;
+                busy = true;
+            }
+            if !waiting && !busy {
+                break;
+            }
+            if !busy {
+                std::thread::sleep(Duration::from_micros(100));
+            }
+        }
+        sess.set_blocking(true);
+        debug!("pipelined {} sftp stats {} deep in {:?}", paths.len(), channels.len(), start_f.elapsed());
+        Ok(list)
+    }
+
+    fn strict_check_rename(&self, src: &Path, dst: &Path) {
+        if self.sftp.lstat(src).is_ok() {
+            error!("strict: rename source \"{}\" still exists after rename", src.display());
+        }
+        if let Err(e) = self.sftp.lstat(dst) {
+            error!("strict: rename target \"{}\" missing after rename: {}", dst.display(), e);
+        }
+    }
+}
 
 impl TryFrom<&std::fs::Metadata> for FileStatus {
     type Error = std::io::Error;
     fn try_from(value: &Metadata) -> std::result::Result<Self, Self::Error> {
         let ft = value.modified()?;
+        let t = value.file_type();
         Ok(FileStatus {
-            file_type: if value.is_file() {
+            file_type: if t.is_file() {
                 Regular
-            } else {
+            } else if t.is_dir() {
                 Directory
+            } else if t.is_symlink() {
+                Symlink
+            } else {
+                FileType::Unknown
             },
             size: value.len(),
             mtime: ft,
+            #[cfg(unix)]
+            hard_link: {
+                use std::os::unix::fs::MetadataExt;
+                if value.nlink() > 1 { Some((value.dev(), value.ino())) } else { None }
+            },
+            #[cfg(not(unix))]
+            hard_link: None,
         })
     }
 }
@@ -240,11 +1181,14 @@ impl TryFrom<&ssh2::FileStat> for FileStatus {
         Ok(FileStatus {
             file_type: if value.is_dir() {
                 Directory
+            } else if value.file_type().is_symlink() {
+                Symlink
             } else {
                 Regular
             },
             mtime: SystemTime::UNIX_EPOCH.add(Duration::from_secs(value.mtime.unwrap())),
             size: value.size.unwrap_or(0),
+            hard_link: None,
         })
     }
 }